@@ -0,0 +1,101 @@
+use std::collections::HashSet;
+
+use super::instr::*;
+use super::module::*;
+
+/// Globals read by a global, either directly or by any function it may call
+pub fn global_deps(
+    modules: &[Module],
+    mod_idx: usize,
+    idx: usize,
+) -> Vec<(usize, usize)> {
+    let mut deps = vec![];
+    let mut visited = HashSet::new();
+    add_body_deps(
+        modules,
+        &modules[mod_idx].globals[idx].body,
+        &mut deps,
+        &mut visited,
+    );
+    deps
+}
+
+fn add_body_deps(
+    modules: &[Module],
+    body: &[Instr],
+    deps: &mut Vec<(usize, usize)>,
+    visited: &mut HashSet<(usize, usize)>,
+) {
+    for instr in body {
+        match &instr.body {
+            InstrBody::GetGlobal(mod_idx, idx) if !deps.contains(&(*mod_idx, *idx)) => {
+                deps.push((*mod_idx, *idx));
+            }
+            InstrBody::Call(mod_idx, func_idx)
+            | InstrBody::TailCall(mod_idx, func_idx)
+            | InstrBody::CallMethod(_, mod_idx, func_idx)
+            | InstrBody::ArrayFromFn(mod_idx, func_idx)
+            | InstrBody::CreateClosure(_, mod_idx, func_idx, _)
+                if visited.insert((*mod_idx, *func_idx)) =>
+            {
+                let body = &modules[*mod_idx].funcs[*func_idx].body;
+                add_body_deps(modules, body, deps, visited);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Globals that depend on each other, as the path from the first of them back to itself
+#[derive(Debug, Clone)]
+pub struct GlobalsCycle {
+    pub globals: Vec<(usize, usize)>,
+}
+
+/// Orders the globals so each one comes after the globals it depends on, keeping the
+/// given order otherwise. Fails if some globals depend on each other
+pub fn sort_globals(
+    modules: &[Module],
+    globals: impl IntoIterator<Item = (usize, usize)>,
+) -> Result<Vec<(usize, usize)>, GlobalsCycle> {
+    let mut sorter = GlobalSorter {
+        modules,
+        order: vec![],
+        done: HashSet::new(),
+        path: vec![],
+    };
+    for global in globals {
+        sorter.visit(global)?;
+    }
+    Ok(sorter.order)
+}
+
+struct GlobalSorter<'a> {
+    modules: &'a [Module],
+    order: Vec<(usize, usize)>,
+    done: HashSet<(usize, usize)>,
+    path: Vec<(usize, usize)>,
+}
+
+impl GlobalSorter<'_> {
+    fn visit(&mut self, global: (usize, usize)) -> Result<(), GlobalsCycle> {
+        if self.done.contains(&global) {
+            return Ok(());
+        }
+        if let Some(start) = self.path.iter().position(|g| *g == global) {
+            let mut globals = self.path[start..].to_vec();
+            globals.push(global);
+            return Err(GlobalsCycle { globals });
+        }
+
+        self.path.push(global);
+        for dep in global_deps(self.modules, global.0, global.1) {
+            self.visit(dep)?;
+        }
+        self.path.pop();
+
+        self.done.insert(global);
+        self.order.push(global);
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+use super::instr::*;
+use super::layout::*;
+use super::module::*;
+use super::ty::*;
+
+/// Maximum number of instructions evaluated for a single global, so a global that
+/// takes too long to compute is left to be initialized at runtime
+const MAX_STEPS: usize = 100_000;
+/// Maximum depth of nested calls while evaluating a global
+const MAX_CALL_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstValue {
+    pub ty: Type,
+    pub body: ConstValueBody,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstValueBody {
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    String(String),
+    Array(Vec<ConstValue>),
+    Record(Vec<(String, ConstValue)>),
+    Option(Option<Box<ConstValue>>),
+    /// Index of the case of the variant type and the value it holds
+    Variant(usize, Box<ConstValue>),
+}
+
+impl ConstValue {
+    fn new(ty: Type, body: ConstValueBody) -> Self {
+        Self { ty, body }
+    }
+    fn bool(value: bool) -> Self {
+        Self::new(Type::new(TypeBody::Bool, None), ConstValueBody::Bool(value))
+    }
+    fn usize(value: usize) -> Self {
+        Self::new(
+            Type::new(TypeBody::USize, None),
+            ConstValueBody::Int(value as i128),
+        )
+    }
+
+    /// Adds the instructions that create this value, all of them constant
+    pub fn add_instrs(&self, loc: Loc, instrs: &mut Vec<Instr>) {
+        let body = match &self.body {
+            ConstValueBody::Bool(v) => InstrBody::CreateBool(*v),
+            ConstValueBody::Int(v) => {
+                InstrBody::CreateNumber(self.ty.clone(), v.to_string())
+            }
+            ConstValueBody::Float(v) => {
+                let text = if matches!(&self.ty.body, TypeBody::F32) {
+                    (*v as f32).to_string()
+                } else {
+                    v.to_string()
+                };
+                InstrBody::CreateNumber(self.ty.clone(), text)
+            }
+            ConstValueBody::String(v) => InstrBody::CreateString(v.clone()),
+            // Arrays of bytes are kept as a single instruction, like the one that
+            // created them
+            ConstValueBody::Array(items) if is_byte_array(&self.ty) => {
+                let bytes = items
+                    .iter()
+                    .map(|item| match item.body {
+                        ConstValueBody::Int(v) => v as u8,
+                        _ => unreachable!("items of a byte array should be integers"),
+                    })
+                    .collect();
+                InstrBody::CreateBytes(bytes)
+            }
+            ConstValueBody::Array(items) => {
+                for item in items {
+                    item.add_instrs(loc, instrs);
+                }
+                InstrBody::CreateArray(self.ty.clone(), items.len())
+            }
+            ConstValueBody::Record(fields) => {
+                for (_, value) in fields {
+                    value.add_instrs(loc, instrs);
+                }
+                let names = fields.iter().map(|(name, _)| name.clone()).collect();
+                InstrBody::CreateRecord(self.ty.clone(), names)
+            }
+            ConstValueBody::Option(Some(value)) => {
+                value.add_instrs(loc, instrs);
+                InstrBody::CreateSome(self.ty.clone())
+            }
+            ConstValueBody::Option(None) => InstrBody::CreateNone(self.ty.clone()),
+            ConstValueBody::Variant(case, value) => {
+                value.add_instrs(loc, instrs);
+                InstrBody::CreateVariant(self.ty.clone(), *case)
+            }
+        };
+        instrs.push(Instr::new(body, loc));
+    }
+}
+
+/// Replaces the body of every global whose value can be computed at compile time by
+/// the instructions that create that value, so it can be emitted as static data. The
+/// entry point is left as is, since it's the program itself
+pub fn fold_globals(modules: &mut [Module], ptr_bytes: u8) {
+    for mod_idx in 0..modules.len() {
+        for idx in 0..modules[mod_idx].globals.len() {
+            let global = &modules[mod_idx].globals[idx];
+            if global.is_entry_point {
+                continue;
+            }
+
+            let loc = global.loc;
+            let Some(value) =
+                ConstEvaluator::new(modules, ptr_bytes).eval_global(mod_idx, idx)
+            else {
+                continue;
+            };
+
+            let mut body = vec![];
+            value.add_instrs(loc, &mut body);
+            modules[mod_idx].globals[idx].body = body;
+        }
+    }
+}
+
+struct Block {
+    /// Length of the stack when the block started, without the loop arguments
+    base: usize,
+    /// Position of the loop instruction and its arity, if the block is a loop
+    loop_start: Option<(usize, usize)>,
+}
+
+/// Interprets the bytecode of globals and the functions they call. Anything that
+/// can't be known at compile time, like extern calls, pointers or panics, makes the
+/// evaluation fail
+pub struct ConstEvaluator<'a> {
+    modules: &'a [Module],
+    ptr_bytes: u8,
+    steps: usize,
+    depth: usize,
+    /// Globals being evaluated, to stop when a global depends on itself
+    globals: Vec<(usize, usize)>,
+}
+
+impl<'a> ConstEvaluator<'a> {
+    pub fn new(modules: &'a [Module], ptr_bytes: u8) -> Self {
+        Self {
+            modules,
+            ptr_bytes,
+            steps: 0,
+            depth: 0,
+            globals: vec![],
+        }
+    }
+
+    pub fn eval_global(&mut self, mod_idx: usize, idx: usize) -> Option<ConstValue> {
+        if self.globals.contains(&(mod_idx, idx)) {
+            return None;
+        }
+        self.globals.push((mod_idx, idx));
+        let value = self.eval_body(&self.modules[mod_idx].globals[idx].body, vec![]);
+        self.globals.pop();
+        value
+    }
+
+    /// Evaluates instructions that don't depend on any value created before them
+    pub fn eval_instrs(&mut self, instrs: &'a [Instr]) -> Option<ConstValue> {
+        self.eval_body(instrs, vec![])
+    }
+
+    fn eval_call(
+        &mut self,
+        mod_idx: usize,
+        func_idx: usize,
+        args: Vec<ConstValue>,
+    ) -> Option<ConstValue> {
+        let func = &self.modules[mod_idx].funcs[func_idx];
+        if func.extn.is_some() || self.depth >= MAX_CALL_DEPTH {
+            return None;
+        }
+        self.depth += 1;
+        let value = self.eval_body(&func.body, args);
+        self.depth -= 1;
+        value
+    }
+
+    fn eval_body(
+        &mut self,
+        body: &'a [Instr],
+        params: Vec<ConstValue>,
+    ) -> Option<ConstValue> {
+        let targets = block_targets(body);
+        let mut stack = params;
+        let mut blocks: Vec<Block> = vec![];
+        let mut pc = 0;
+
+        while pc < body.len() {
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return None;
+            }
+
+            match &body[pc].body {
+                InstrBody::Dup(n) => {
+                    let value = stack.get(stack.len().checked_sub(n + 1)?)?.clone();
+                    stack.push(value);
+                }
+                InstrBody::GetGlobal(mod_idx, idx) => {
+                    stack.push(self.eval_global(*mod_idx, *idx)?);
+                }
+                InstrBody::GetField(name) => {
+                    let ConstValueBody::Record(fields) = stack.pop()?.body else {
+                        return None;
+                    };
+                    let (_, value) = fields.into_iter().find(|(n, _)| n == name)?;
+                    stack.push(value);
+                }
+                InstrBody::CreateBool(v) => stack.push(ConstValue::bool(*v)),
+                InstrBody::CreateNumber(ty, n) => {
+                    let body = if ty.is_float() {
+                        ConstValueBody::Float(self.fit_float(ty, n.parse().ok()?))
+                    } else {
+                        ConstValueBody::Int(n.parse().ok()?)
+                    };
+                    stack.push(ConstValue::new(ty.clone(), body));
+                }
+                InstrBody::CreateString(v) => stack.push(ConstValue::new(
+                    Type::new(TypeBody::String(StringType::new(Some(v.len()))), None),
+                    ConstValueBody::String(v.clone()),
+                )),
+                InstrBody::CreateBytes(v) => {
+                    let byte_ty = Type::new(TypeBody::U8, None);
+                    let items = v
+                        .iter()
+                        .map(|byte| {
+                            ConstValue::new(
+                                byte_ty.clone(),
+                                ConstValueBody::Int(*byte as i128),
+                            )
+                        })
+                        .collect();
+                    let ty = Type::new(
+                        TypeBody::Array(ArrayType::new(byte_ty.into(), Some(v.len()))),
+                        None,
+                    );
+                    stack.push(ConstValue::new(ty, ConstValueBody::Array(items)));
+                }
+                InstrBody::CreateArray(ty, n) => {
+                    let items = pop_many(&mut stack, *n)?;
+                    stack.push(ConstValue::new(ty.clone(), ConstValueBody::Array(items)));
+                }
+                InstrBody::CreateRecord(ty, names) => {
+                    let values = pop_many(&mut stack, names.len())?;
+                    let fields = names.iter().cloned().zip(values).collect();
+                    stack.push(ConstValue::new(
+                        ty.clone(),
+                        ConstValueBody::Record(fields),
+                    ));
+                }
+                InstrBody::CreateSome(ty) => {
+                    let value = Box::new(stack.pop()?);
+                    stack.push(ConstValue::new(
+                        ty.clone(),
+                        ConstValueBody::Option(Some(value)),
+                    ));
+                }
+                InstrBody::CreateNone(ty) => {
+                    stack.push(ConstValue::new(ty.clone(), ConstValueBody::Option(None)));
+                }
+                InstrBody::CreateVariant(ty, case) => {
+                    let value = Box::new(stack.pop()?);
+                    stack.push(ConstValue::new(
+                        ty.clone(),
+                        ConstValueBody::Variant(*case, value),
+                    ));
+                }
+                InstrBody::Add
+                | InstrBody::Sub
+                | InstrBody::Mul
+                | InstrBody::Div
+                | InstrBody::Mod => {
+                    let rhs = stack.pop()?;
+                    let lhs = stack.pop()?;
+                    stack.push(self.eval_arith(&body[pc].body, lhs, rhs)?);
+                }
+                InstrBody::Eq
+                | InstrBody::Neq
+                | InstrBody::Gt
+                | InstrBody::Gte
+                | InstrBody::Lt
+                | InstrBody::Lte => {
+                    let rhs = stack.pop()?;
+                    let lhs = stack.pop()?;
+                    stack.push(ConstValue::bool(eval_cmp(&body[pc].body, &lhs, &rhs)?));
+                }
+                InstrBody::Cast(ty) => {
+                    let value = stack.pop()?;
+                    stack.push(self.eval_cast(value, ty)?);
+                }
+                InstrBody::Call(mod_idx, func_idx) => {
+                    let params_len = self.modules[*mod_idx].funcs[*func_idx].params.len();
+                    let args = pop_many(&mut stack, params_len)?;
+                    stack.push(self.eval_call(*mod_idx, *func_idx, args)?);
+                }
+                InstrBody::TailCall(mod_idx, func_idx) => {
+                    let params_len = self.modules[*mod_idx].funcs[*func_idx].params.len();
+                    let args = pop_many(&mut stack, params_len)?;
+                    return self.eval_call(*mod_idx, *func_idx, args);
+                }
+                InstrBody::If(_) => {
+                    let ConstValueBody::Bool(cond) = stack.pop()?.body else {
+                        return None;
+                    };
+                    blocks.push(Block {
+                        base: stack.len(),
+                        loop_start: None,
+                    });
+                    if !cond {
+                        // Without an else branch, there's no value to continue with
+                        let else_pc = targets[pc]?;
+                        if !matches!(&body[else_pc].body, InstrBody::Else) {
+                            return None;
+                        }
+                        pc = else_pc;
+                    }
+                }
+                InstrBody::Loop(_, n) => {
+                    blocks.push(Block {
+                        base: stack.len().checked_sub(*n)?,
+                        loop_start: Some((pc, *n)),
+                    });
+                }
+                // The end of the then branch, so the else branch is skipped
+                InstrBody::Else | InstrBody::End => {
+                    let block = blocks.pop()?;
+                    let result = stack.pop()?;
+                    stack.truncate(block.base);
+                    stack.push(result);
+                    if matches!(&body[pc].body, InstrBody::Else) {
+                        pc = targets[pc]?;
+                    }
+                }
+                InstrBody::Continue => {
+                    let loop_idx = blocks.iter().rposition(|b| b.loop_start.is_some())?;
+                    blocks.truncate(loop_idx + 1);
+                    let block = &blocks[loop_idx];
+                    let (start, arity) = block.loop_start?;
+                    let args = pop_many(&mut stack, arity)?;
+                    stack.truncate(block.base);
+                    stack.extend(args);
+                    pc = start;
+                }
+                InstrBody::Return => return stack.pop(),
+                InstrBody::ArrayLen => {
+                    let ConstValueBody::Array(items) = stack.pop()?.body else {
+                        return None;
+                    };
+                    stack.push(ConstValue::usize(items.len()));
+                }
+                InstrBody::StrLen => {
+                    let ConstValueBody::String(v) = stack.pop()?.body else {
+                        return None;
+                    };
+                    stack.push(ConstValue::usize(v.len()));
+                }
+                InstrBody::OptionIsSome => {
+                    let ConstValueBody::Option(v) = stack.pop()?.body else {
+                        return None;
+                    };
+                    stack.push(ConstValue::bool(v.is_some()));
+                }
+                InstrBody::OptionUnwrap => {
+                    let ConstValueBody::Option(Some(v)) = stack.pop()?.body else {
+                        return None;
+                    };
+                    stack.push(*v);
+                }
+                InstrBody::VariantIs(_, expected) => {
+                    let ConstValueBody::Variant(case, _) = stack.pop()?.body else {
+                        return None;
+                    };
+                    stack.push(ConstValue::bool(case == *expected));
+                }
+                InstrBody::VariantUnwrap(_, expected) => {
+                    let ConstValueBody::Variant(case, v) = stack.pop()?.body else {
+                        return None;
+                    };
+                    if case != *expected {
+                        return None;
+                    }
+                    stack.push(*v);
+                }
+                InstrBody::SizeOf(ty) => {
+                    let size = size_of(ty, self.modules, self.ptr_bytes);
+                    stack.push(ConstValue::usize(size));
+                }
+                InstrBody::AlignOf(ty) => {
+                    let align = align_of(ty, self.modules, self.ptr_bytes);
+                    stack.push(ConstValue::usize(align));
+                }
+                InstrBody::StaticAssert(..) => {
+                    let ConstValueBody::Bool(true) = stack.pop()?.body else {
+                        return None;
+                    };
+                    stack.push(ConstValue::bool(true));
+                }
+                InstrBody::Annotate(_) | InstrBody::Unused => {}
+                InstrBody::CallMethod(name, mod_idx, func_idx) => {
+                    let params_len = self.modules[*mod_idx].funcs[*func_idx].params.len();
+                    let args = pop_many(&mut stack, params_len)?;
+                    // Function values held by fields can't be evaluated
+                    if args[0].ty.property(name, self.modules).is_some() {
+                        return None;
+                    }
+                    stack.push(self.eval_call(*mod_idx, *func_idx, args)?);
+                }
+                InstrBody::Panic
+                | InstrBody::ArrayPtr(_)
+                | InstrBody::ArraySlice
+                | InstrBody::ArrayFromFn(..)
+                | InstrBody::CreateClosure(..)
+                | InstrBody::CallValue(_)
+                | InstrBody::StrPtr(_)
+                | InstrBody::StrFromPtr
+                | InstrBody::StrAlloc
+                | InstrBody::PtrIsNull
+                | InstrBody::PtrAdd
+                | InstrBody::PtrLoad
+                | InstrBody::EnvVar
+                | InstrBody::Dbg(..)
+                | InstrBody::CompileError => return None,
+            }
+
+            pc += 1;
+        }
+
+        stack.pop()
+    }
+
+    fn eval_arith(
+        &self,
+        instr: &InstrBody,
+        lhs: ConstValue,
+        rhs: ConstValue,
+    ) -> Option<ConstValue> {
+        let body = match (&lhs.body, &rhs.body) {
+            (ConstValueBody::Int(a), ConstValueBody::Int(b)) => {
+                let (a, b) = (*a, *b);
+                let value = match instr {
+                    InstrBody::Add => a.wrapping_add(b),
+                    InstrBody::Sub => a.wrapping_sub(b),
+                    InstrBody::Mul => a.wrapping_mul(b),
+                    // Division by zero panics at runtime, as does the overflow of a
+                    // signed division, even if only the remainder is used
+                    InstrBody::Div | InstrBody::Mod if b == 0 => return None,
+                    InstrBody::Div | InstrBody::Mod
+                        if self.wrap_int(&lhs.ty, a / b)? != a / b =>
+                    {
+                        return None
+                    }
+                    InstrBody::Div => a / b,
+                    InstrBody::Mod => a % b,
+                    _ => unreachable!(),
+                };
+                ConstValueBody::Int(self.wrap_int(&lhs.ty, value)?)
+            }
+            (ConstValueBody::Float(a), ConstValueBody::Float(b)) => {
+                let (a, b) = (*a, *b);
+                let value = match instr {
+                    InstrBody::Add => a + b,
+                    InstrBody::Sub => a - b,
+                    InstrBody::Mul => a * b,
+                    InstrBody::Div => a / b,
+                    InstrBody::Mod => (a / b).trunc().mul_add(-b, a),
+                    _ => unreachable!(),
+                };
+                ConstValueBody::Float(self.fit_float(&lhs.ty, value))
+            }
+            _ => return None,
+        };
+        Some(ConstValue::new(lhs.ty, body))
+    }
+
+    fn eval_cast(&self, value: ConstValue, ty: &Type) -> Option<ConstValue> {
+        let body = match (value.body, ty.is_float()) {
+            (ConstValueBody::Int(v), false) => ConstValueBody::Int(self.wrap_int(ty, v)?),
+            (ConstValueBody::Int(v), true) if matches!(&ty.body, TypeBody::F32) => {
+                ConstValueBody::Float(v as f32 as f64)
+            }
+            (ConstValueBody::Int(v), true) => ConstValueBody::Float(v as f64),
+            // Conversions to integers saturate, like they do at runtime
+            (ConstValueBody::Float(v), false) => {
+                let (min, max) = self.int_range(ty)?;
+                ConstValueBody::Int(if v.is_nan() {
+                    0
+                } else {
+                    (v.clamp(min as f64, max as f64) as i128).clamp(min, max)
+                })
+            }
+            (ConstValueBody::Float(v), true) => {
+                ConstValueBody::Float(self.fit_float(ty, v))
+            }
+            _ => return None,
+        };
+        Some(ConstValue::new(ty.clone(), body))
+    }
+
+    fn int_bits(&self, ty: &Type) -> Option<(u32, bool)> {
+        match &ty.body {
+            TypeBody::I8 => Some((8, true)),
+            TypeBody::I16 => Some((16, true)),
+            TypeBody::I32 => Some((32, true)),
+            TypeBody::I64 => Some((64, true)),
+            TypeBody::U8 => Some((8, false)),
+            TypeBody::U16 => Some((16, false)),
+            TypeBody::U32 => Some((32, false)),
+            TypeBody::U64 => Some((64, false)),
+            TypeBody::USize => Some((self.ptr_bytes as u32 * 8, false)),
+            _ => None,
+        }
+    }
+
+    fn int_range(&self, ty: &Type) -> Option<(i128, i128)> {
+        let (bits, signed) = self.int_bits(ty)?;
+        if signed {
+            Some((-(1 << (bits - 1)), (1 << (bits - 1)) - 1))
+        } else {
+            Some((0, (1 << bits) - 1))
+        }
+    }
+
+    /// Wraps the value around the range of the type, like arithmetic does at runtime
+    fn wrap_int(&self, ty: &Type, value: i128) -> Option<i128> {
+        let (bits, signed) = self.int_bits(ty)?;
+        let shift = 128 - bits;
+        if signed {
+            Some((value << shift) >> shift)
+        } else {
+            Some(((value << shift) as u128 >> shift) as i128)
+        }
+    }
+
+    fn fit_float(&self, ty: &Type, value: f64) -> f64 {
+        if matches!(&ty.body, TypeBody::F32) {
+            value as f32 as f64
+        } else {
+            value
+        }
+    }
+}
+
+fn eval_cmp(instr: &InstrBody, lhs: &ConstValue, rhs: &ConstValue) -> Option<bool> {
+    macro_rules! cmp {
+        ($a:expr, $b:expr) => {
+            match instr {
+                InstrBody::Eq => $a == $b,
+                InstrBody::Neq => $a != $b,
+                InstrBody::Gt => $a > $b,
+                InstrBody::Gte => $a >= $b,
+                InstrBody::Lt => $a < $b,
+                InstrBody::Lte => $a <= $b,
+                _ => unreachable!(),
+            }
+        };
+    }
+
+    match (&lhs.body, &rhs.body) {
+        (ConstValueBody::Int(a), ConstValueBody::Int(b)) => Some(cmp!(a, b)),
+        (ConstValueBody::Float(a), ConstValueBody::Float(b)) => Some(cmp!(a, b)),
+        _ => None,
+    }
+}
+
+fn pop_many(stack: &mut Vec<ConstValue>, n: usize) -> Option<Vec<ConstValue>> {
+    let start = stack.len().checked_sub(n)?;
+    Some(stack.split_off(start))
+}
+
+/// Position of the instruction that closes each block: the else of an if, or the end
+/// of an else or a loop
+fn block_targets(body: &[Instr]) -> Vec<Option<usize>> {
+    let mut targets = vec![None; body.len()];
+    let mut open = vec![];
+    for (i, instr) in body.iter().enumerate() {
+        match &instr.body {
+            InstrBody::If(_) | InstrBody::Loop(..) => open.push(i),
+            InstrBody::Else => {
+                if let Some(start) = open.pop() {
+                    targets[start] = Some(i);
+                }
+                open.push(i);
+            }
+            InstrBody::End => {
+                if let Some(start) = open.pop() {
+                    targets[start] = Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+fn is_byte_array(ty: &Type) -> bool {
+    let TypeBody::Array(array_ty) = &ty.body else {
+        return false;
+    };
+    matches!(array_ty.item.body, TypeBody::U8)
+}
@@ -15,14 +15,26 @@ pub enum InstrBody {
     CreateBool(bool),
     CreateNumber(Type, String),
     CreateString(String),
+    /// Creates an array of `u8` with the given bytes
+    CreateBytes(Vec<u8>),
     CreateArray(Type, usize),
     CreateRecord(Type, Vec<String>),
+    CreateSome(Type),
+    CreateNone(Type),
+    /// Creates the case of the variant type with the given index, holding the value at
+    /// the top of the stack
+    CreateVariant(Type, usize),
+    /// Creates a function value that calls the function of the module and index. The
+    /// parameters marked as bound take the values at the top of the stack, in order, and
+    /// the others are left for the caller
+    CreateClosure(Type, usize, usize, Vec<bool>),
 
     Add,
     Sub,
     Mul,
     Div,
     Mod,
+    Cast(Type),
 
     Eq,
     Neq,
@@ -32,17 +44,50 @@ pub enum InstrBody {
     Lte,
 
     Call(usize, usize),
+    TailCall(usize, usize),
+    /// Call made with the method syntax `value.name(args)`. When the type of the value
+    /// has a field with the same name, the function value held by the field is called
+    /// with the arguments after the value. Otherwise, it calls the function
+    CallMethod(String, usize, usize),
+    /// Calls the function value below the given number of arguments
+    CallValue(usize),
 
     If(Type),
     Else,
     Loop(Type, usize),
     End,
     Continue,
+    Return,
+    Panic,
 
     ArrayLen,
     ArrayPtr(u64),
+    ArraySlice,
+    ArrayFromFn(usize, usize),
     StrLen,
     StrPtr(u64),
+    StrFromPtr,
+    StrAlloc,
+    PtrIsNull,
+    PtrAdd,
+    PtrLoad,
+    EnvVar,
+    OptionIsSome,
+    OptionUnwrap,
+    VariantIs(Type, usize),
+    VariantUnwrap(Type, usize),
+    Dbg(Type, String),
+    SizeOf(Type),
+    AlignOf(Type),
+    /// Checked at compile time against the condition made by the previous instructions,
+    /// which are then removed
+    StaticAssert(String, usize),
+    /// Constrains the value at the top of the stack to the type annotated in the source.
+    /// The value is kept on the stack
+    Annotate(Type),
+    /// Marks the value at the top of the stack as unused, which is reported when its type
+    /// is not unit. The value is kept on the stack until the end of its scope
+    Unused,
 
     CompileError,
 }
@@ -65,6 +110,7 @@ impl Display for Instr {
             InstrBody::CreateString(v) => {
                 write!(f, "create_string {}", utils::encode_string_lit(v))?
             }
+            InstrBody::CreateBytes(v) => write!(f, "create_bytes {}", v.len())?,
             InstrBody::CreateArray(ty, len) => write!(f, "create_array {ty} {len}")?,
             InstrBody::CreateRecord(ty, fields) => {
                 write!(f, "create_record {ty}")?;
@@ -72,11 +118,23 @@ impl Display for Instr {
                     write!(f, " .{field}")?;
                 }
             }
+            InstrBody::CreateSome(ty) => write!(f, "create_some {ty}")?,
+            InstrBody::CreateNone(ty) => write!(f, "create_none {ty}")?,
+            InstrBody::CreateVariant(ty, case) => {
+                write!(f, "create_variant {ty} {case}")?
+            }
+            InstrBody::CreateClosure(ty, mod_idx, func_idx, bound) => {
+                write!(f, "create_closure {ty} {mod_idx}-{func_idx}")?;
+                for is_bound in bound {
+                    write!(f, " {}", if *is_bound { "bound" } else { "open" })?;
+                }
+            }
             InstrBody::Add => write!(f, "add")?,
             InstrBody::Sub => write!(f, "sub")?,
             InstrBody::Mul => write!(f, "mul")?,
             InstrBody::Div => write!(f, "div")?,
             InstrBody::Mod => write!(f, "mod")?,
+            InstrBody::Cast(ty) => write!(f, "cast {ty}")?,
             InstrBody::Eq => write!(f, "eq")?,
             InstrBody::Neq => write!(f, "neq")?,
             InstrBody::Gt => write!(f, "gt")?,
@@ -84,15 +142,52 @@ impl Display for Instr {
             InstrBody::Lt => write!(f, "lt")?,
             InstrBody::Lte => write!(f, "lte")?,
             InstrBody::Call(mod_idx, func_idx) => write!(f, "call {mod_idx}-{func_idx}")?,
+            InstrBody::TailCall(mod_idx, func_idx) => {
+                write!(f, "tail_call {mod_idx}-{func_idx}")?
+            }
+            InstrBody::CallMethod(name, mod_idx, func_idx) => {
+                write!(f, "call_method .{name} {mod_idx}-{func_idx}")?
+            }
+            InstrBody::CallValue(n) => write!(f, "call_value {n}")?,
             InstrBody::If(ty) => write!(f, "if {ty}")?,
             InstrBody::Else => write!(f, "else")?,
             InstrBody::Loop(ty, n) => write!(f, "loop {ty} {n}")?,
             InstrBody::End => write!(f, "end")?,
             InstrBody::Continue => write!(f, "continue")?,
+            InstrBody::Return => write!(f, "return")?,
+            InstrBody::Panic => write!(f, "panic")?,
             InstrBody::ArrayLen => write!(f, "array_len")?,
             InstrBody::ArrayPtr(idx) => write!(f, "array_ptr {idx}")?,
+            InstrBody::ArraySlice => write!(f, "array_slice")?,
+            InstrBody::ArrayFromFn(mod_idx, func_idx) => {
+                write!(f, "array_from_fn {mod_idx}-{func_idx}")?
+            }
             InstrBody::StrLen => write!(f, "str_len")?,
             InstrBody::StrPtr(idx) => write!(f, "str_ptr {idx}")?,
+            InstrBody::StrFromPtr => write!(f, "str_from_ptr")?,
+            InstrBody::StrAlloc => write!(f, "str_alloc")?,
+            InstrBody::PtrIsNull => write!(f, "ptr_is_null")?,
+            InstrBody::PtrAdd => write!(f, "ptr_add")?,
+            InstrBody::PtrLoad => write!(f, "ptr_load")?,
+            InstrBody::EnvVar => write!(f, "env_var")?,
+            InstrBody::OptionIsSome => write!(f, "option_is_some")?,
+            InstrBody::OptionUnwrap => write!(f, "option_unwrap")?,
+            InstrBody::VariantIs(ty, case) => write!(f, "variant_is {ty} {case}")?,
+            InstrBody::VariantUnwrap(ty, case) => {
+                write!(f, "variant_unwrap {ty} {case}")?
+            }
+            InstrBody::Dbg(ty, label) => {
+                write!(f, "dbg {ty} {}", utils::encode_string_lit(label))?
+            }
+            InstrBody::SizeOf(ty) => write!(f, "size_of {ty}")?,
+            InstrBody::AlignOf(ty) => write!(f, "align_of {ty}")?,
+            InstrBody::StaticAssert(message, len) => write!(
+                f,
+                "static_assert {len} {}",
+                utils::encode_string_lit(message)
+            )?,
+            InstrBody::Annotate(ty) => write!(f, "annotate {ty}")?,
+            InstrBody::Unused => write!(f, "unused")?,
             InstrBody::CompileError => write!(f, "compile_error")?,
         }
         write!(f, " {}", &self.loc)?;
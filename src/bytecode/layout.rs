@@ -0,0 +1,92 @@
+use super::module::*;
+use super::ty::*;
+
+/// Size in bytes of a value of the type when it's held in a variable or in a field.
+/// Composite values are always held as pointers to their data
+pub fn value_size(ty: &Type, ptr_bytes: u8) -> usize {
+    match &ty.body {
+        TypeBody::Bool | TypeBody::I8 | TypeBody::U8 => 1,
+        TypeBody::I16 | TypeBody::U16 => 2,
+        TypeBody::I32 | TypeBody::U32 | TypeBody::F32 => 4,
+        TypeBody::I64 | TypeBody::U64 | TypeBody::F64 => 8,
+        TypeBody::USize
+        | TypeBody::String(_)
+        | TypeBody::Array(_)
+        | TypeBody::Ptr(_)
+        | TypeBody::Option(_)
+        | TypeBody::TypeRef(..)
+        | TypeBody::Variant(..)
+        | TypeBody::Func(_) => ptr_bytes as usize,
+        TypeBody::AnyNumber
+        | TypeBody::AnySignedNumber
+        | TypeBody::AnyFloat
+        | TypeBody::Inferred(_) => panic!("Type must be resolved before layout"),
+        TypeBody::AnyOpaque => panic!("anyopaque cannot be used directly"),
+        TypeBody::Param(_) => panic!("Parameters must be replaced before layout"),
+    }
+}
+
+/// Size in bytes of the data of the type, which is what a pointer to a composite value
+/// points to
+pub fn size_of(ty: &Type, modules: &[Module], ptr_bytes: u8) -> usize {
+    match &ty.body {
+        TypeBody::String(s) => s.len.map_or(ptr_bytes as usize, |len| len + 1),
+        // Arrays are always referenced by their header, regardless of their length
+        TypeBody::Array(_) => ptr_bytes as usize,
+        TypeBody::TypeRef(i, j) => match &modules[*i].typedefs[*j].body {
+            TypeDefBody::Record(rec) => record_layout(rec, ptr_bytes).size,
+            TypeDefBody::Variant(_) => unreachable!("variant types are not type refs"),
+        },
+        _ => value_size(ty, ptr_bytes),
+    }
+}
+
+/// Alignment in bytes needed by the data of the type
+pub fn align_of(ty: &Type, modules: &[Module], ptr_bytes: u8) -> usize {
+    match &ty.body {
+        TypeBody::TypeRef(i, j) => match &modules[*i].typedefs[*j].body {
+            TypeDefBody::Record(rec) => record_layout(rec, ptr_bytes).align,
+            TypeDefBody::Variant(_) => unreachable!("variant types are not type refs"),
+        },
+        _ => value_size(ty, ptr_bytes),
+    }
+}
+
+/// Layout of the data of a composite value, which holds its values one after the other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TupleLayout {
+    pub offsets: Vec<usize>,
+    pub size: usize,
+    pub align: usize,
+}
+
+/// Layout of values with the given sizes. Every value size is a power of two, so each
+/// value is aligned to its own size, and the size of the whole is padded to the largest
+/// of them
+pub fn tuple_layout(sizes: impl IntoIterator<Item = usize>) -> TupleLayout {
+    let mut offsets = vec![];
+    let mut size: usize = 0;
+    let mut align: usize = 1;
+    for value_size in sizes {
+        size = size.next_multiple_of(value_size);
+        offsets.push(size);
+        size += value_size;
+        align = align.max(value_size);
+    }
+    TupleLayout {
+        offsets,
+        size: size.next_multiple_of(align),
+        align,
+    }
+}
+
+/// Layout of the fields of a record, in the order of their names. Fields that are
+/// records hold pointers to their data, so records that refer to themselves still have
+/// a finite size
+pub fn record_layout(rec: &RecordType, ptr_bytes: u8) -> TupleLayout {
+    tuple_layout(
+        rec.fields
+            .values()
+            .map(|field| value_size(&field.ty, ptr_bytes)),
+    )
+}
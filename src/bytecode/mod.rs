@@ -1,7 +1,13 @@
+mod deps;
+mod eval;
 mod instr;
+mod layout;
 mod module;
 mod ty;
 
+pub use self::deps::*;
+pub use self::eval::*;
 pub use self::instr::*;
+pub use self::layout::*;
 pub use self::module::*;
 pub use self::ty::*;
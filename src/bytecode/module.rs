@@ -1,4 +1,3 @@
-use std::collections::HashSet;
 use std::path::PathBuf;
 use std::{cmp, fmt};
 
@@ -19,7 +18,8 @@ pub struct Module {
     pub globals: Vec<Global>,
     #[new(default)]
     pub funcs: Vec<Func>,
-    pub sources: HashSet<Source>,
+    /// Sources known when the module was parsed, indexed like `Loc::source_idx`
+    pub sources: Vec<Source>,
 }
 impl Display for Module {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -36,6 +36,16 @@ impl Display for Module {
                     }
                     write!(f, ")")?;
                 }
+                TypeDefBody::Variant(v) => {
+                    write!(f, " (variant")?;
+                    if !v.params.is_empty() {
+                        write!(f, " (params {})", v.params.join(" "))?;
+                    }
+                    for case in &v.cases {
+                        write!(f, "\n    {}: {}", case.name, case.ty)?;
+                    }
+                    write!(f, ")")?;
+                }
             }
 
             writeln!(f)?;
@@ -56,7 +66,11 @@ impl Display for Module {
             if func.params.len() > 0 {
                 write!(f, " (params")?;
                 for param in &func.params {
-                    write!(f, " ({} {})", param.ty, param.loc)?;
+                    write!(f, " ({} {}", param.ty, param.loc)?;
+                    if let Some(default) = param.default {
+                        write!(f, " (default {default})")?;
+                    }
+                    write!(f, ")")?;
                 }
                 write!(f, ")")?;
             }
@@ -78,6 +92,20 @@ pub struct TypeDef {
     pub body: TypeDefBody,
     pub loc: Loc,
 }
+impl TypeDef {
+    /// Type referring to the definition. Parameters of variant types are left to be
+    /// inferred
+    pub fn type_ref(&self, mod_idx: usize, idx: usize) -> TypeBody {
+        match &self.body {
+            TypeDefBody::Record(_) => TypeBody::TypeRef(mod_idx, idx),
+            TypeDefBody::Variant(v) => TypeBody::Variant(
+                mod_idx,
+                idx,
+                v.params.iter().map(|_| Type::unknown(None)).collect(),
+            ),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Global {
@@ -95,12 +123,14 @@ pub struct Func {
     pub ret: Type,
     pub body: Vec<Instr>,
     pub extn: Option<Extern>,
+    pub is_entry_point: bool,
     pub loc: Loc,
 }
 
 #[derive(Debug, Clone)]
 pub enum TypeDefBody {
     Record(RecordType),
+    Variant(VariantType),
 }
 
 #[derive(Debug, Clone)]
@@ -122,9 +152,27 @@ pub struct RecordFieldName {
     pub loc: Loc,
 }
 
+/// A value that is one of the cases, each one holding a single value. The types of the
+/// values can refer to the parameters of the type, which are given where it's used
+#[derive(Debug, Clone, new)]
+pub struct VariantType {
+    pub params: Vec<String>,
+    pub cases: Vec<VariantCase>,
+}
+
+#[derive(Debug, Clone, new)]
+pub struct VariantCase {
+    pub name: String,
+    pub ty: Type,
+    pub loc: Loc,
+}
+
 #[derive(Debug, Clone)]
 pub struct Param {
+    pub name: String,
     pub ty: Type,
+    /// Index of the global, in the same module, that holds the default value
+    pub default: Option<usize>,
     pub loc: Loc,
 }
 
@@ -4,6 +4,7 @@ use std::fmt::Display;
 use std::hash::Hash;
 
 use derive_new::new;
+use itertools::izip;
 
 use super::{Loc, Module, TypeDefBody};
 use crate::utils;
@@ -33,7 +34,15 @@ pub enum TypeBody {
     String(StringType),
     Array(ArrayType),
     Ptr(Box<Type>),
+    Option(Box<Type>),
     TypeRef(usize, usize),
+    /// Variant type of the module and index, with the types given to its parameters
+    Variant(usize, usize, Vec<Type>),
+    /// Parameter of the variant type where it's used, by its index
+    Param(usize),
+    /// Function value, which can be called with arguments of the types of its
+    /// parameters
+    Func(Box<FuncType>),
 }
 impl Display for TypeBody {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -73,7 +82,22 @@ impl Display for TypeBody {
                 }
             }
             TypeBody::Ptr(ty) => write!(f, "ptr {ty}")?,
+            TypeBody::Option(ty) => write!(f, "option {ty}")?,
             TypeBody::TypeRef(mod_idx, ty_idx) => write!(f, "type {mod_idx}-{ty_idx}")?,
+            TypeBody::Variant(mod_idx, ty_idx, args) => {
+                write!(f, "variant {mod_idx}-{ty_idx}")?;
+                for arg in args {
+                    write!(f, " {arg}")?;
+                }
+            }
+            TypeBody::Param(idx) => write!(f, "param {idx}")?,
+            TypeBody::Func(func) => {
+                write!(f, "func")?;
+                for param in &func.params {
+                    write!(f, " {param}")?;
+                }
+                write!(f, " -> {}", func.ret)?;
+            }
         }
         Ok(())
     }
@@ -177,12 +201,73 @@ impl Type {
             TypeBody::TypeRef(mod_idx, ty_idx) => {
                 match &modules.get(*mod_idx)?.typedefs.get(*ty_idx)?.body {
                     TypeDefBody::Record(rec) => Some(&rec.fields.get(name)?.ty),
+                    TypeDefBody::Variant(_) => None,
                 }
             }
             _ => None,
         }
     }
 
+    /// Names and types of the cases of a variant type, with the types given to its
+    /// parameters
+    pub fn variant_cases<'a>(
+        &self,
+        modules: &'a [Module],
+    ) -> Option<Vec<(&'a str, Type)>> {
+        let TypeBody::Variant(mod_idx, ty_idx, args) = &self.body else {
+            return None;
+        };
+        match &modules.get(*mod_idx)?.typedefs.get(*ty_idx)?.body {
+            TypeDefBody::Variant(v) => Some(
+                v.cases
+                    .iter()
+                    .map(|case| (case.name.as_str(), case.ty.with_args(args)))
+                    .collect(),
+            ),
+            TypeDefBody::Record(_) => None,
+        }
+    }
+
+    /// Replaces the parameters of a variant type in this type, like in the type of one
+    /// of its cases, with the types they are given
+    pub fn with_args(&self, args: &[Type]) -> Type {
+        let body = match &self.body {
+            TypeBody::Param(idx) => return args[*idx].clone(),
+            TypeBody::Array(a) => {
+                TypeBody::Array(ArrayType::new(a.item.with_args(args).into(), a.len))
+            }
+            TypeBody::Ptr(ty) => TypeBody::Ptr(ty.with_args(args).into()),
+            TypeBody::Option(ty) => TypeBody::Option(ty.with_args(args).into()),
+            TypeBody::Variant(mod_idx, ty_idx, ty_args) => TypeBody::Variant(
+                *mod_idx,
+                *ty_idx,
+                ty_args.iter().map(|ty| ty.with_args(args)).collect(),
+            ),
+            TypeBody::Func(func) => TypeBody::Func(
+                FuncType::new(
+                    func.params.iter().map(|ty| ty.with_args(args)).collect(),
+                    func.ret.with_args(args),
+                )
+                .into(),
+            ),
+            body => body.clone(),
+        };
+        Type::new(body, self.loc)
+    }
+
+    pub fn has_params(&self) -> bool {
+        match &self.body {
+            TypeBody::Param(_) => true,
+            TypeBody::Array(a) => a.item.has_params(),
+            TypeBody::Ptr(ty) | TypeBody::Option(ty) => ty.has_params(),
+            TypeBody::Variant(_, _, args) => args.iter().any(|ty| ty.has_params()),
+            TypeBody::Func(func) => {
+                func.params.iter().any(|ty| ty.has_params()) || func.ret.has_params()
+            }
+            _ => false,
+        }
+    }
+
     pub fn intersection(&self, other: &Type, modules: &[Module]) -> Option<Type> {
         let body = match (self, other) {
             number!(U8) => TypeBody::U8,
@@ -218,6 +303,32 @@ impl Type {
             (body!(TypeBody::Ptr(a)), body!(TypeBody::Ptr(b))) => {
                 TypeBody::Ptr(a.intersection(&b, modules)?.into())
             }
+            (body!(TypeBody::Option(a)), body!(TypeBody::Option(b))) => {
+                TypeBody::Option(a.intersection(b, modules)?.into())
+            }
+            (
+                body!(TypeBody::Variant(a_mod, a_idx, a_args)),
+                body!(TypeBody::Variant(b_mod, b_idx, b_args)),
+            ) if a_mod == b_mod && a_idx == b_idx => TypeBody::Variant(
+                *a_mod,
+                *a_idx,
+                izip!(a_args, b_args)
+                    .map(|(a, b)| a.intersection(b, modules))
+                    .collect::<Option<_>>()?,
+            ),
+            (body!(TypeBody::Func(a)), body!(TypeBody::Func(b)))
+                if a.params.len() == b.params.len() =>
+            {
+                TypeBody::Func(
+                    FuncType::new(
+                        izip!(&a.params, &b.params)
+                            .map(|(a, b)| a.intersection(b, modules))
+                            .collect::<Option<_>>()?,
+                        a.ret.intersection(&b.ret, modules)?,
+                    )
+                    .into(),
+                )
+            }
             (body!(TypeBody::Inferred(a)), body!(TypeBody::Inferred(b))) => {
                 let mut props = utils::SortedMap::new();
                 let prop_names: HashSet<_> =
@@ -281,6 +392,34 @@ impl Type {
             (body!(TypeBody::Ptr(a)), body!(TypeBody::Ptr(b))) => {
                 TypeBody::Ptr(a.common_type(&b, modules)?.into())
             }
+            (body!(TypeBody::Option(a)), body!(TypeBody::Option(b))) => {
+                TypeBody::Option(a.common_type(b, modules)?.into())
+            }
+            (
+                body!(TypeBody::Variant(a_mod, a_idx, a_args)),
+                body!(TypeBody::Variant(b_mod, b_idx, b_args)),
+            ) if a_mod == b_mod && a_idx == b_idx => TypeBody::Variant(
+                *a_mod,
+                *a_idx,
+                izip!(a_args, b_args)
+                    .map(|(a, b)| a.common_type(b, modules))
+                    .collect::<Option<_>>()?,
+            ),
+            // Functions are called with the same arguments whichever one is given, so
+            // their parameters can't be widened
+            (body!(TypeBody::Func(a)), body!(TypeBody::Func(b)))
+                if a.params.len() == b.params.len() =>
+            {
+                TypeBody::Func(
+                    FuncType::new(
+                        izip!(&a.params, &b.params)
+                            .map(|(a, b)| a.intersection(b, modules))
+                            .collect::<Option<_>>()?,
+                        a.ret.common_type(&b.ret, modules)?,
+                    )
+                    .into(),
+                )
+            }
             (body!(TypeBody::Inferred(a)), body!(TypeBody::Inferred(b))) => {
                 let mut props = utils::SortedMap::new();
                 let prop_names: HashSet<_> =
@@ -375,7 +514,22 @@ impl Display for Type {
                 }
             }
             TypeBody::Ptr(ty) => write!(f, "ptr {ty}")?,
+            TypeBody::Option(ty) => write!(f, "option {ty}")?,
             TypeBody::TypeRef(mod_idx, ty_idx) => write!(f, "type {mod_idx}-{ty_idx}")?,
+            TypeBody::Variant(mod_idx, ty_idx, args) => {
+                write!(f, "variant {mod_idx}-{ty_idx}")?;
+                for arg in args {
+                    write!(f, " {arg}")?;
+                }
+            }
+            TypeBody::Param(idx) => write!(f, "param {idx}")?,
+            TypeBody::Func(func) => {
+                write!(f, "func")?;
+                for param in &func.params {
+                    write!(f, " {param}")?;
+                }
+                write!(f, " -> {}", func.ret)?;
+            }
         }
         if let Some(loc) = &self.loc {
             write!(f, " {loc}")?;
@@ -408,3 +562,9 @@ pub struct ArrayType {
     pub item: Box<Type>,
     pub len: Option<usize>,
 }
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, new)]
+pub struct FuncType {
+    pub params: Vec<Type>,
+    pub ret: Type,
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::mem;
+
+use cranelift_shim::{self as cl};
+use itertools::izip;
+
+use super::rc;
+use super::types::get_type;
+use crate::bytecode as b;
+
+/// Function of a module with some of its parameters bound, by the module and index of
+/// the function and whether each of its parameters is bound
+pub type ClosureKey = (usize, usize, Vec<bool>);
+
+/// Runtime functions of the function values. Every function value is a heap value with
+/// a pointer to a function that calls the target function, a pointer to a function that
+/// releases the bound values, and the bound values themselves, in the order of their
+/// parameters. Like the release functions, they are only declared here and built later
+/// by the codegen
+#[derive(Debug, Default)]
+pub struct ClosureFuncs {
+    funcs: HashMap<ClosureKey, (cl::FuncId, cl::FuncId)>,
+    pending: Vec<(ClosureKey, cl::FuncId, cl::FuncId)>,
+}
+impl ClosureFuncs {
+    /// Functions that call the target of the function value and that release its bound
+    /// values, in this order
+    pub fn closure_funcs<M: cl::Module>(
+        &mut self,
+        key: &ClosureKey,
+        modules: &[b::Module],
+        obj_module: &mut M,
+    ) -> (cl::FuncId, cl::FuncId) {
+        if let Some(funcs) = self.funcs.get(key) {
+            return *funcs;
+        }
+
+        let (mod_idx, func_idx, bound) = key;
+        let func = &modules[*mod_idx].funcs[*func_idx];
+        let func_ty = b::FuncType::new(
+            izip!(&func.params, bound)
+                .filter(|(_, is_bound)| !**is_bound)
+                .map(|(param, _)| param.ty.clone())
+                .collect(),
+            func.ret.clone(),
+        );
+
+        let n = self.funcs.len();
+        let call_id = obj_module
+            .declare_function(
+                &format!("$closure_call_{n}"),
+                cl::Linkage::Local,
+                &call_signature(&func_ty, modules, obj_module),
+            )
+            .unwrap();
+        let drop_id = obj_module
+            .declare_function(
+                &format!("$closure_drop_{n}"),
+                cl::Linkage::Local,
+                &rc::rc_signature(obj_module),
+            )
+            .unwrap();
+        self.funcs.insert(key.clone(), (call_id, drop_id));
+        self.pending.push((key.clone(), call_id, drop_id));
+        (call_id, drop_id)
+    }
+
+    pub fn take_pending(&mut self) -> Vec<(ClosureKey, cl::FuncId, cl::FuncId)> {
+        mem::take(&mut self.pending)
+    }
+}
+
+/// Signature of the function that calls the target of a function value, which receives
+/// the function value itself before the arguments
+pub fn call_signature(
+    func_ty: &b::FuncType,
+    modules: &[b::Module],
+    obj_module: &impl cl::Module,
+) -> cl::Signature {
+    let mut sig = obj_module.make_signature();
+    sig.params
+        .push(cl::AbiParam::new(obj_module.isa().pointer_type()));
+    for param in &func_ty.params {
+        sig.params
+            .push(cl::AbiParam::new(get_type(param, modules, obj_module)));
+    }
+    sig.returns.push(cl::AbiParam::new(get_type(
+        &func_ty.ret,
+        modules,
+        obj_module,
+    )));
+    sig.call_conv = cl::isa::CallConv::Tail;
+    sig
+}
+
+/// Offsets of the bound values, which are stored after the pointers to the functions
+pub fn bound_offsets(
+    key: &ClosureKey,
+    modules: &[b::Module],
+    obj_module: &impl cl::Module,
+) -> Vec<i32> {
+    let (mod_idx, func_idx, bound) = key;
+    let func = &modules[*mod_idx].funcs[*func_idx];
+    let ptr_bytes = obj_module.isa().pointer_bytes() as usize;
+    let sizes = izip!(&func.params, bound)
+        .filter(|(_, is_bound)| **is_bound)
+        .map(|(param, _)| get_type(&param.ty, modules, obj_module).bytes() as usize);
+    b::tuple_layout([ptr_bytes, ptr_bytes].into_iter().chain(sizes)).offsets[2..]
+        .iter()
+        .map(|offset| *offset as i32)
+        .collect()
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::mem;
+
+use cranelift_shim::{self as cl};
+use itertools::{izip, Itertools};
+
+use super::rc;
+use super::types::get_type;
+use crate::bytecode as b;
+
+/// Runtime functions that write a value to stderr, used by `@dbg`. Each type has its own
+/// function, so values that contain themselves can be written recursively. Like the
+/// release functions, they are only declared here and built later by the codegen
+#[derive(Debug, Default)]
+pub struct DbgFuncs {
+    writes: HashMap<b::Type, cl::FuncId>,
+    pending: Vec<(b::Type, cl::FuncId)>,
+}
+impl DbgFuncs {
+    pub fn write_func<M: cl::Module>(
+        &mut self,
+        ty: &b::Type,
+        modules: &[b::Module],
+        obj_module: &mut M,
+    ) -> cl::FuncId {
+        let ty = rc::layout_type(ty);
+        if let Some(func_id) = self.writes.get(&ty) {
+            return *func_id;
+        }
+
+        let func_id = obj_module
+            .declare_function(
+                &format!("$dbg_write_{}", self.writes.len()),
+                cl::Linkage::Local,
+                &write_signature(&ty, modules, obj_module),
+            )
+            .unwrap();
+        self.writes.insert(ty.clone(), func_id);
+        self.pending.push((ty, func_id));
+        func_id
+    }
+
+    pub fn take_pending(&mut self) -> Vec<(b::Type, cl::FuncId)> {
+        mem::take(&mut self.pending)
+    }
+}
+
+pub fn write_signature(
+    ty: &b::Type,
+    modules: &[b::Module],
+    obj_module: &impl cl::Module,
+) -> cl::Signature {
+    let mut sig = obj_module.make_signature();
+    sig.params
+        .push(cl::AbiParam::new(get_type(ty, modules, obj_module)));
+    sig
+}
+
+/// Name of the type as written in the source code
+pub fn type_name(ty: &b::Type, modules: &[b::Module]) -> String {
+    match &ty.body {
+        b::TypeBody::Bool => "bool".to_string(),
+        b::TypeBody::I8 => "i8".to_string(),
+        b::TypeBody::I16 => "i16".to_string(),
+        b::TypeBody::I32 => "i32".to_string(),
+        b::TypeBody::I64 => "i64".to_string(),
+        b::TypeBody::U8 => "u8".to_string(),
+        b::TypeBody::U16 => "u16".to_string(),
+        b::TypeBody::U32 => "u32".to_string(),
+        b::TypeBody::U64 => "u64".to_string(),
+        b::TypeBody::USize => "usize".to_string(),
+        b::TypeBody::F32 => "f32".to_string(),
+        b::TypeBody::F64 => "f64".to_string(),
+        b::TypeBody::String(_) => "str".to_string(),
+        b::TypeBody::Array(array_ty) => match array_ty.len {
+            Some(len) => format!("[{}; {len}]", type_name(&array_ty.item, modules)),
+            None => format!("[{}]", type_name(&array_ty.item, modules)),
+        },
+        b::TypeBody::Ptr(item) => format!("Ptr({})", type_name(item, modules)),
+        b::TypeBody::Option(item) => format!("Option({})", type_name(item, modules)),
+        b::TypeBody::TypeRef(mod_idx, ty_idx) => {
+            modules[*mod_idx].typedefs[*ty_idx].name.clone()
+        }
+        b::TypeBody::Variant(mod_idx, ty_idx, args) => {
+            let name = &modules[*mod_idx].typedefs[*ty_idx].name;
+            if args.is_empty() {
+                name.clone()
+            } else {
+                let args = args.iter().map(|arg| type_name(arg, modules));
+                format!("{name}({})", args.collect::<Vec<_>>().join(", "))
+            }
+        }
+        b::TypeBody::Func(func_ty) => {
+            let params = func_ty.params.iter().map(|param| type_name(param, modules));
+            format!(
+                "({}): {}",
+                params.collect::<Vec<_>>().join(", "),
+                type_name(&func_ty.ret, modules)
+            )
+        }
+        b::TypeBody::AnyNumber
+        | b::TypeBody::AnySignedNumber
+        | b::TypeBody::AnyFloat
+        | b::TypeBody::Inferred(_) => panic!("Type must be resolved before codegen"),
+        b::TypeBody::AnyOpaque => panic!("anyopaque cannot be used directly"),
+        b::TypeBody::Param(_) => panic!("Parameters must be replaced before codegen"),
+    }
+}
+
+/// Fields of the record in the order they were declared, along with their offsets,
+/// which follow the order of their names
+pub fn record_fields<'a>(
+    rec: &'a b::RecordType,
+    obj_module: &impl cl::Module,
+) -> Vec<(&'a b::RecordField, i32)> {
+    let layout = b::record_layout(rec, obj_module.isa().pointer_bytes());
+    izip!(rec.fields.values(), layout.offsets)
+        .map(|(field, offset)| (field, offset as i32))
+        .sorted_by_key(|(field, _)| field.loc.start_byte)
+        .collect()
+}
@@ -1,17 +1,36 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::mem;
+use std::slice;
 
 use cl::InstBuilder;
 use cranelift_shim as cl;
 use derive_new::new;
-use itertools::{izip, Itertools};
+use itertools::{enumerate, izip, Itertools};
 
+use super::closure::{self, ClosureFuncs};
+use super::dbg::{self, DbgFuncs};
 use super::globals::{GlobalBinding, Globals};
+use super::rc::{self, RcFuncs};
 use super::types::{self, get_size, get_type, RuntimeValue};
 use super::FuncBinding;
 use crate::{bytecode as b, utils};
 
+/// Exit status of a program that panics, which is distinct from the usual failure status
+/// of 1
+const PANIC_EXIT_CODE: i64 = 101;
+
+/// Object module and codegen state given back once a function is built, so they can be
+/// used to build the next one
+pub type FuncCodegenParts<'a, M> = (
+    M,
+    Globals<'a>,
+    HashMap<(usize, usize), FuncBinding>,
+    RcFuncs,
+    DbgFuncs,
+    ClosureFuncs,
+);
+
 #[derive(new)]
 pub struct FuncCodegen<'a, 'b, M: cl::Module> {
     pub modules: &'a [b::Module],
@@ -19,6 +38,10 @@ pub struct FuncCodegen<'a, 'b, M: cl::Module> {
     pub obj_module: M,
     pub globals: Globals<'a>,
     pub funcs: HashMap<(usize, usize), FuncBinding>,
+    pub rc: RcFuncs,
+    pub dbg: DbgFuncs,
+    #[new(default)]
+    pub closures: ClosureFuncs,
     #[new(value = "utils::ValueStack::new(ScopePayload::default())")]
     pub stack: utils::ValueStack<types::RuntimeValue<'a>, ScopePayload<'a>>,
     #[new(default)]
@@ -33,22 +56,32 @@ macro_rules! expect_builder {
     }};
 }
 impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
-    pub fn create_initial_block(&mut self, params: &'a [b::Param]) {
+    /// Creates the block where the function starts. Functions that aren't extern own
+    /// their parameters, like loops, so the callers retain the arguments and they are
+    /// released when the function returns. That way, a tail call can release every
+    /// value of the function before the callee replaces it
+    pub fn create_initial_block(&mut self, params: &'a [b::Param], owns_params: bool) {
         let Some(func) = &mut self.builder else {
             panic!("cannot add stack slot without a function");
         };
 
         let block = func.create_block();
         func.append_block_params_for_function_params(block);
-        for (param, value) in izip!(params, func.block_params(block)) {
-            self.stack.push(types::RuntimeValue::new(
-                Cow::Borrowed(&param.ty),
-                (*value).into(),
-            ));
-        }
+        let params = izip!(params, func.block_params(block))
+            .map(|(param, value)| {
+                types::RuntimeValue::new(Cow::Borrowed(&param.ty), (*value).into())
+            })
+            .collect_vec();
 
         func.switch_to_block(block);
         self.stack.get_scope_mut().payload.block = Some(block);
+
+        for param in params {
+            if owns_params {
+                self.own(&param);
+            }
+            self.stack.push(param);
+        }
     }
     pub fn add_instr(&mut self, instr: &'a b::Instr) {
         if self.stack.get_scope().is_never()
@@ -97,6 +130,7 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                 });
             }
             b::InstrBody::Div => {
+                self.check_divisor(&instr.loc);
                 self.push_bin_op(|func, lhs, rhs, ty| {
                     if ty.is_uint() {
                         func.ins().udiv(lhs, rhs)
@@ -110,6 +144,7 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                 });
             }
             b::InstrBody::Mod => {
+                self.check_divisor(&instr.loc);
                 self.push_bin_op(|func, lhs, rhs, ty| {
                     if ty.is_uint() {
                         func.ins().urem(lhs, rhs)
@@ -125,6 +160,66 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                     }
                 });
             }
+            b::InstrBody::Cast(ty) => {
+                let builder = expect_builder!(self);
+
+                let source = self.stack.pop();
+                let source_value = source.add_to_func(&self.obj_module, builder);
+                let source_ty = builder.func.dfg.value_type(source_value);
+                let target_ty = get_type(ty, self.modules, &self.obj_module);
+
+                let value = match (source.ty.is_float(), ty.is_float()) {
+                    (false, false) if target_ty.bits() > source_ty.bits() => {
+                        if source.ty.is_sint() {
+                            builder.ins().sextend(target_ty, source_value)
+                        } else {
+                            builder.ins().uextend(target_ty, source_value)
+                        }
+                    }
+                    (false, false) if target_ty.bits() < source_ty.bits() => {
+                        builder.ins().ireduce(target_ty, source_value)
+                    }
+                    (false, false) => source_value,
+                    (false, true) if source.ty.is_sint() => {
+                        builder.ins().fcvt_from_sint(target_ty, source_value)
+                    }
+                    (false, true) => {
+                        builder.ins().fcvt_from_uint(target_ty, source_value)
+                    }
+                    // Saturating conversions only produce 32 or 64 bits, so smaller
+                    // integers are converted to i32 and clamped to their range
+                    (true, false) if target_ty.bits() < 32 => {
+                        let bits = target_ty.bits();
+                        let (min, max) = if ty.is_sint() {
+                            (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+                        } else {
+                            (0, (1 << bits) - 1)
+                        };
+                        let value =
+                            builder.ins().fcvt_to_sint_sat(cl::types::I32, source_value);
+                        let min = builder.ins().iconst(cl::types::I32, min);
+                        let max = builder.ins().iconst(cl::types::I32, max);
+                        let value = builder.ins().smax(value, min);
+                        let value = builder.ins().smin(value, max);
+                        builder.ins().ireduce(target_ty, value)
+                    }
+                    (true, false) if ty.is_sint() => {
+                        builder.ins().fcvt_to_sint_sat(target_ty, source_value)
+                    }
+                    (true, false) => {
+                        builder.ins().fcvt_to_uint_sat(target_ty, source_value)
+                    }
+                    (true, true) if target_ty.bits() > source_ty.bits() => {
+                        builder.ins().fpromote(target_ty, source_value)
+                    }
+                    (true, true) if target_ty.bits() < source_ty.bits() => {
+                        builder.ins().fdemote(target_ty, source_value)
+                    }
+                    (true, true) => source_value,
+                };
+                self.stack
+                    .push(RuntimeValue::new(Cow::Owned(ty.clone()), value.into()));
+            }
             b::InstrBody::Eq
             | b::InstrBody::Neq
             | b::InstrBody::Gt
@@ -193,31 +288,38 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                     branches: vec![else_block],
                     next_block: Some(next_block),
                     ty: Some(Cow::Borrowed(ty)),
+                    owned: vec![],
                 });
             }
             b::InstrBody::Else => {
-                let builder = expect_builder!(self);
-
+                let is_never = self.stack.get_scope().is_never();
                 let (scope, values) = self.stack.branch_scope();
                 let else_block = scope.payload.branches.pop().unwrap();
+                let next_block = scope.payload.next_block.unwrap();
                 scope.payload.start_block = Some(else_block);
                 scope.payload.block = Some(else_block);
+                let owned = mem::take(&mut scope.payload.owned);
 
-                if !scope.is_never() {
-                    let value = values
-                        .last()
-                        .unwrap()
-                        .add_to_func(&self.obj_module, builder);
-                    builder
-                        .ins()
-                        .jump(scope.payload.next_block.unwrap(), &[value]);
+                if !is_never {
+                    let result = values.last().unwrap();
+                    self.release_scope(slice::from_ref(result), owned);
+
+                    let builder = expect_builder!(self);
+                    let value = result.add_to_func(&self.obj_module, builder);
+                    builder.ins().jump(next_block, &[value]);
                 }
 
-                builder.switch_to_block(else_block);
+                expect_builder!(self).switch_to_block(else_block);
             }
             b::InstrBody::Loop(ty, n) => {
-                let builder = expect_builder!(self);
                 let args = self.stack.pop_many(*n);
+                // The loop owns its parameters, since they are released on each
+                // iteration
+                for arg in &args {
+                    self.retain(arg);
+                }
+
+                let builder = expect_builder!(self);
                 let mut args_values = vec![];
                 let mut loop_params = vec![];
 
@@ -249,26 +351,36 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                     next_block: Some(next_block),
                     ty: Some(Cow::Borrowed(ty)),
                     branches: vec![],
+                    owned: vec![],
                 });
                 scope.is_loop = true;
                 scope.loop_arity = *n;
 
+                for param in &loop_params {
+                    self.own(param);
+                }
                 self.stack.extend(loop_params);
             }
             b::InstrBody::End => {
-                let builder = expect_builder!(self);
-
-                let (scope, values) = self.stack.end_scope();
+                let (mut scope, values) = self.stack.end_scope();
                 let next_block = scope.payload.next_block.unwrap();
 
                 if !scope.is_never() {
-                    let value = values
-                        .last()
-                        .unwrap()
-                        .add_to_func(&self.obj_module, builder);
+                    let result = values.last().unwrap();
+                    let owned = mem::take(&mut scope.payload.owned);
+                    self.release_scope(slice::from_ref(result), owned);
+
+                    let builder = expect_builder!(self);
+                    let value = result.add_to_func(&self.obj_module, builder);
                     builder.ins().jump(next_block, &[value]);
                 }
 
+                // Every branch is never, so the next block is unreachable
+                if self.stack.get_scope().is_never() {
+                    return;
+                }
+
+                let builder = expect_builder!(self);
                 builder.switch_to_block(next_block);
 
                 let block_params = builder.block_params(next_block);
@@ -276,14 +388,15 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                 assert!(block_params.is_empty() == scope.payload.ty.is_none());
 
                 if let [value] = block_params {
-                    self.stack.push(types::RuntimeValue::new(
+                    let value = types::RuntimeValue::new(
                         scope.payload.ty.unwrap(),
                         (*value).into(),
-                    ));
+                    );
+                    self.own(&value);
+                    self.stack.push(value);
                 }
             }
             b::InstrBody::Continue => {
-                let builder = expect_builder!(self);
                 let (block, arity) = {
                     let scope = self
                         .stack
@@ -292,9 +405,19 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                     (scope.payload.start_block.unwrap(), scope.loop_arity)
                 };
 
-                let values = self
+                let args = self.stack.pop_many(arity);
+                // Every scope inside the loop ends here, but only in this path, so the
+                // values are still owned by them in the other paths
+                let owned = self
                     .stack
-                    .pop_many(arity)
+                    .loop_scopes()
+                    .iter()
+                    .flat_map(|scope| scope.payload.owned.iter().cloned())
+                    .collect_vec();
+                self.release_scope(&args, owned);
+
+                let builder = expect_builder!(self);
+                let values = args
                     .iter()
                     .map(|arg| arg.add_to_func(&mut self.obj_module, builder))
                     .collect_vec();
@@ -302,67 +425,121 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                 builder.ins().jump(block, &values);
                 self.stack.get_scope_mut().mark_as_never();
             }
+            b::InstrBody::Return => {
+                let result = self.stack.pop();
+                // Every scope of the function ends here, but only in this path, so the
+                // values are still owned by them in the other paths
+                let owned = self
+                    .stack
+                    .scopes()
+                    .iter()
+                    .flat_map(|scope| scope.payload.owned.iter().cloned())
+                    .collect_vec();
+                self.release_scope(slice::from_ref(&result), owned);
+
+                let builder = expect_builder!(self);
+                let value = result.add_to_func(&self.obj_module, builder);
+                builder.ins().return_(&[value]);
+                self.stack.get_scope_mut().mark_as_never();
+            }
+            b::InstrBody::Panic => {
+                let message = self.stack.pop();
+                let ptr_ty = self.obj_module.isa().pointer_type();
+                let ptr_bytes = self.obj_module.isa().pointer_bytes() as i64;
+
+                self.write_static_str(&format!("{}: ", self.loc_text(&instr.loc)));
+                let builder = expect_builder!(self);
+                let message = message.add_to_func(&self.obj_module, builder);
+                let len = builder.ins().load(ptr_ty, cl::MemFlags::new(), message, 0);
+                let content = builder.ins().iadd_imm(message, ptr_bytes);
+                self.write_stderr(content, len);
+                self.write_static_str("\n");
+                self.exit_panicking();
+
+                self.stack.get_scope_mut().mark_as_never();
+            }
             b::InstrBody::Call(mod_idx, func_idx) => {
-                let func_id = self.funcs.get(&(*mod_idx, *func_idx)).unwrap().func_id;
+                self.add_call(*mod_idx, *func_idx);
+            }
+            b::InstrBody::TailCall(mod_idx, func_idx) => {
+                let binding = self.funcs.get(&(*mod_idx, *func_idx)).unwrap();
+                let func_id = binding.func_id;
+                assert!(!binding.is_extern, "extern functions can't be tail called");
                 let func = &self.modules[*mod_idx].funcs[*func_idx];
-                let builder = expect_builder!(self);
 
-                let args = self
+                // The callee takes the ownership of the arguments, so every value of the
+                // function can be released before its frame is replaced. Every scope of
+                // the function ends here, but only in this path, so the values are still
+                // owned by them in the other paths
+                let args = self.stack.pop_many(func.params.len());
+                for arg in &args {
+                    self.retain(arg);
+                }
+                let owned = self
                     .stack
-                    .pop_many(func.params.len())
-                    .into_iter()
-                    .map(|arg| arg.add_to_func(&mut self.obj_module, builder))
+                    .scopes()
+                    .iter()
+                    .flat_map(|scope| scope.payload.owned.iter().cloned())
                     .collect_vec();
+                self.release_scope(&[], owned);
 
-                if let Some(value) = self.call(func_id, &args) {
-                    self.stack.push(types::RuntimeValue::new(
-                        Cow::Borrowed(&func.ret),
-                        value.into(),
-                    ));
+                let builder = expect_builder!(self);
+                let args = args
+                    .into_iter()
+                    .map(|arg| arg.add_to_func(&self.obj_module, builder))
+                    .collect_vec();
+                let func_ref = self.func_ref(func_id);
+                expect_builder!(self).ins().return_call(func_ref, &args);
+                self.stack.get_scope_mut().mark_as_never();
+            }
+            b::InstrBody::CallMethod(name, mod_idx, func_idx) => {
+                let params_len = self.modules[*mod_idx].funcs[*func_idx].params.len();
+                let receiver = self.stack.get(params_len - 1).unwrap();
+                if receiver.ty.property(name, self.modules).is_some() {
+                    let mut args = self.stack.pop_many(params_len);
+                    let receiver = args.remove(0);
+                    let callee = self.get_field(receiver, name);
+                    self.call_value(callee, args);
+                } else {
+                    self.add_call(*mod_idx, *func_idx);
                 }
             }
-            b::InstrBody::GetField(name) => {
-                let builder = expect_builder!(self);
-
-                let source = self.stack.pop();
-                let b::Type {
-                    body: b::TypeBody::TypeRef(mod_idx, ty_idx),
-                    ..
-                } = source.ty.as_ref()
-                else {
-                    panic!("type should be a record type");
-                };
-                let b::TypeDefBody::Record(rec) =
-                    &self.modules[*mod_idx].typedefs[*ty_idx].body
-                else {
-                    panic!("type should be a record type");
-                };
-
-                let field = rec
-                    .fields
-                    .get(name)
-                    .expect("field should be present in record");
+            b::InstrBody::CallValue(n) => {
+                let args = self.stack.pop_many(*n);
+                let callee = self.stack.pop();
+                self.call_value(callee, args);
+            }
+            b::InstrBody::CreateClosure(ty, mod_idx, func_idx, bound) => {
+                let ptr_ty = self.obj_module.isa().pointer_type();
+                let bound_len = bound.iter().filter(|is_bound| **is_bound).count();
+                let bound_values = self.stack.pop_many(bound_len);
 
-                let mut offset = 0;
-                for (field_name, field) in &rec.fields {
-                    if field_name == name {
-                        break;
-                    }
+                let key = (*mod_idx, *func_idx, bound.clone());
+                let (call_id, drop_id) =
+                    self.closures
+                        .closure_funcs(&key, self.modules, &mut self.obj_module);
+                let call_ref = self.func_ref(call_id);
+                let drop_ref = self.func_ref(drop_id);
+                let builder = expect_builder!(self);
+                let call_ptr = builder.ins().func_addr(ptr_ty, call_ref);
+                let drop_ptr = builder.ins().func_addr(ptr_ty, drop_ref);
 
-                    offset += get_type(&field.ty, self.modules, &self.obj_module).bytes();
-                }
+                let usize_ty = b::Type::new(b::TypeBody::USize, None);
+                let mut values = vec![
+                    RuntimeValue::new(Cow::Borrowed(&usize_ty), call_ptr.into()),
+                    RuntimeValue::new(Cow::Borrowed(&usize_ty), drop_ptr.into()),
+                ];
+                values.extend(bound_values);
+                let ptr = self.create_heap_value(&values);
 
-                let source_value = source.add_to_func(&mut self.obj_module, builder);
-                let value = builder.ins().load(
-                    source.native_type(self.modules, &self.obj_module).clone(),
-                    cl::MemFlags::new(),
-                    source_value,
-                    offset as i32,
-                );
-                self.stack.push(types::RuntimeValue::new(
-                    Cow::Borrowed(&field.ty),
-                    value.into(),
-                ));
+                let value = RuntimeValue::new(Cow::Borrowed(ty), ptr.into());
+                self.own(&value);
+                self.stack.push(value);
+            }
+            b::InstrBody::GetField(name) => {
+                let source = self.stack.pop();
+                let value = self.get_field(source, name);
+                self.stack.push(value);
             }
             b::InstrBody::ArrayLen | b::InstrBody::StrLen => {
                 let builder = expect_builder!(self);
@@ -384,17 +561,20 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                 let source_value =
                     source.add_to_func(&mut self.obj_module, expect_builder!(self));
 
-                let (item_size, len) = match &source.ty.body {
+                // Strings are null-terminated, so a pointer to the end is valid as well
+                let (item_size, len, allows_end) = match &source.ty.body {
                     b::TypeBody::Array(array_ty) => (
-                        get_size(&array_ty.item, &self.modules, &self.obj_module),
+                        get_type(&array_ty.item, self.modules, &self.obj_module).bytes()
+                            as usize,
                         array_ty.len,
+                        false,
                     ),
-                    b::TypeBody::String(str_ty) => (1, str_ty.len),
+                    b::TypeBody::String(str_ty) => (1, str_ty.len, true),
                     _ => panic!("type should be string or array"),
                 };
 
                 if let Some(len) = len {
-                    assert!(*idx < len as u64);
+                    assert!(*idx < len as u64 || allows_end && *idx == len as u64);
                 } else {
                     // Check length at runtime
                     let builder = expect_builder!(self);
@@ -410,23 +590,40 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                         source_value,
                         0,
                     );
-                    let cond =
-                        builder
-                            .ins()
-                            .icmp(cl::IntCC::UnsignedLessThan, idx_value, len);
-                    self.add_assert(cond, cl::TrapCode::NullReference);
+                    let cc = if allows_end {
+                        cl::IntCC::UnsignedLessThanOrEqual
+                    } else {
+                        cl::IntCC::UnsignedLessThan
+                    };
+                    let cond = builder.ins().icmp(cc, idx_value, len);
+                    self.add_assert(cond, "index out of bounds", &instr.loc);
                 }
 
                 let builder = expect_builder!(self);
 
-                let offset =
-                    self.obj_module.isa().pointer_bytes() as u64 + idx * item_size as u64;
+                let (items, offset) = match &source.ty.body {
+                    b::TypeBody::Array(_) => {
+                        let array_header = types::ArrayHeader::new(&self.obj_module);
+                        let items = builder.ins().load(
+                            self.obj_module.isa().pointer_type(),
+                            cl::MemFlags::new(),
+                            source_value,
+                            array_header.items,
+                        );
+                        (items, idx * item_size as u64)
+                    }
+                    _ => (
+                        source_value,
+                        self.obj_module.isa().pointer_bytes() as u64
+                            + idx * item_size as u64,
+                    ),
+                };
                 let offset_value = builder
                     .ins()
                     .iconst(self.obj_module.isa().pointer_type(), unsafe {
                         mem::transmute::<_, i64>(offset)
                     });
-                let value = builder.ins().iadd(source_value, offset_value);
+                let value = builder.ins().iadd(items, offset_value);
 
                 let item_ty = match &source.ty.body {
                     b::TypeBody::Array(array_ty) => array_ty.item.clone(),
@@ -438,211 +635,1801 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
                     value.into(),
                 ));
             }
-            b::InstrBody::CompileError => {
-                panic!("never should try to compile CompileError")
+            b::InstrBody::ArraySlice => {
+                let [source, start, end] = &self.stack.pop_many(3)[..] else {
+                    unreachable!()
+                };
+                let b::TypeBody::Array(array_ty) = &source.ty.body else {
+                    panic!("type should be an array");
+                };
+                let item_size = get_type(&array_ty.item, self.modules, &self.obj_module)
+                    .bytes() as i64;
+                let ptr_ty = self.obj_module.isa().pointer_type();
+                let array_header = types::ArrayHeader::new(&self.obj_module);
+
+                let builder = expect_builder!(self);
+                let source_value = source.add_to_func(&self.obj_module, builder);
+                let start = start.add_to_func(&self.obj_module, builder);
+                let end = end.add_to_func(&self.obj_module, builder);
+
+                // Check range at runtime
+                let len = builder.ins().load(
+                    ptr_ty,
+                    cl::MemFlags::new(),
+                    source_value,
+                    array_header.len,
+                );
+                let start_in_range =
+                    builder
+                        .ins()
+                        .icmp(cl::IntCC::UnsignedLessThanOrEqual, start, end);
+                let end_in_range =
+                    builder
+                        .ins()
+                        .icmp(cl::IntCC::UnsignedLessThanOrEqual, end, len);
+                self.add_assert(start_in_range, "slice out of bounds", &instr.loc);
+                self.add_assert(end_in_range, "slice out of bounds", &instr.loc);
+
+                // Slices of slices point to the array that owns the items, so there's no
+                // chain of slices to keep alive
+                let builder = expect_builder!(self);
+                let source_owner = builder.ins().load(
+                    ptr_ty,
+                    cl::MemFlags::new(),
+                    source_value,
+                    array_header.owner,
+                );
+                let owner =
+                    builder
+                        .ins()
+                        .select(source_owner, source_owner, source_value);
+                let source_items = builder.ins().load(
+                    ptr_ty,
+                    cl::MemFlags::new(),
+                    source_value,
+                    array_header.items,
+                );
+                let offset = builder.ins().imul_imm(start, item_size);
+                let items = builder.ins().iadd(source_items, offset);
+                let len = builder.ins().isub(end, start);
+
+                self.retain(&RuntimeValue::new(source.ty.clone(), owner.into()));
+
+                let builder = expect_builder!(self);
+                let header_size = builder.ins().iconst(ptr_ty, array_header.size as i64);
+                let value = self.alloc(header_size);
+
+                let builder = expect_builder!(self);
+                for (field, offset) in [
+                    (len, array_header.len),
+                    (items, array_header.items),
+                    (owner, array_header.owner),
+                ] {
+                    builder
+                        .ins()
+                        .store(cl::MemFlags::new(), field, value, offset);
+                }
+
+                let value = RuntimeValue::new(
+                    Cow::Owned(b::Type::new(
+                        b::TypeBody::Array(b::ArrayType::new(
+                            array_ty.item.clone(),
+                            None,
+                        )),
+                        None,
+                    )),
+                    value.into(),
+                );
+                self.own(&value);
+                self.stack.push(value);
             }
-            b::InstrBody::Dup(..)
-            | b::InstrBody::CreateNumber(..)
-            | b::InstrBody::CreateBool(..)
-            | b::InstrBody::CreateString(..)
-            | b::InstrBody::CreateArray(..)
-            | b::InstrBody::CreateRecord(..)
-            | b::InstrBody::GetGlobal(..) => unreachable!(),
-        }
-    }
-    pub fn return_value(
-        mut self,
-    ) -> (M, Globals<'a>, HashMap<(usize, usize), FuncBinding>) {
-        let builder = expect_builder!(self);
+            b::InstrBody::ArrayFromFn(mod_idx, func_idx) => {
+                let func_id = self.funcs.get(&(*mod_idx, *func_idx)).unwrap().func_id;
+                let func = &self.modules[*mod_idx].funcs[*func_idx];
+                let item_size =
+                    get_type(&func.ret, self.modules, &self.obj_module).bytes() as i64;
+                let ptr_ty = self.obj_module.isa().pointer_type();
 
-        let value = self.stack.pop().add_to_func(&mut self.obj_module, builder);
-        builder.ins().return_(&[value]);
+                let builder = expect_builder!(self);
+                let len = self.stack.pop().add_to_func(&self.obj_module, builder);
+                let (array, items) = self.alloc_array(len, item_size);
 
-        (self.obj_module, self.globals, self.funcs)
-    }
-    pub fn return_never(
-        mut self,
-    ) -> (M, Globals<'a>, HashMap<(usize, usize), FuncBinding>) {
-        let func = expect_builder!(self);
+                let builder = expect_builder!(self);
+                let loop_block = builder.create_block();
+                let i = builder.append_block_param(loop_block, ptr_ty);
+                let body_block = builder.create_block();
+                let next_block = builder.create_block();
 
-        func.ins().trap(cl::TrapCode::UnreachableCodeReached);
+                let zero = builder.ins().iconst(ptr_ty, 0);
+                builder.ins().jump(loop_block, &[zero]);
 
-        (self.obj_module, self.globals, self.funcs)
-    }
-    pub fn value_from_instr(
-        &mut self,
-        instr: &'a b::Instr,
-    ) -> Option<types::RuntimeValue<'a>> {
-        utils::replace_with(self, |mut this| {
-            let v = 'match_b: {
-                match &instr.body {
-                    b::InstrBody::Dup(n) => Some(this.stack.get(*n).unwrap().clone()),
-                    b::InstrBody::CreateNumber(ty, n) => {
-                        macro_rules! parse_num {
-                            ($ty:ty, $variant:ident) => {{
-                                let value: $ty = n.parse().unwrap();
-                                let src = types::ValueSource::$variant(unsafe {
-                                    mem::transmute(value)
-                                });
-                                Some(types::RuntimeValue::new(Cow::Borrowed(ty), src))
-                            }};
-                        }
+                builder.switch_to_block(loop_block);
+                let cond = builder.ins().icmp(cl::IntCC::UnsignedLessThan, i, len);
+                builder.ins().brif(cond, body_block, &[], next_block, &[]);
 
-                        match &ty.body {
-                            b::TypeBody::I8 => parse_num!(i8, I8),
-                            b::TypeBody::I16 => parse_num!(i16, I16),
-                            b::TypeBody::I32 => parse_num!(i32, I32),
-                            b::TypeBody::I64 => parse_num!(i64, I64),
-                            b::TypeBody::U8 => parse_num!(u8, I8),
-                            b::TypeBody::U16 => parse_num!(u16, I16),
-                            b::TypeBody::U32 => parse_num!(u32, I32),
-                            b::TypeBody::U64 => parse_num!(u64, I64),
-                            b::TypeBody::USize => {
-                                match this.obj_module.isa().pointer_bytes() {
-                                    1 => parse_num!(u8, I8),
-                                    2 => parse_num!(u16, I16),
-                                    4 => parse_num!(u32, I32),
-                                    8 => parse_num!(u64, I64),
-                                    _ => unreachable!(),
-                                }
-                            }
-                            b::TypeBody::F32 => parse_num!(f32, F32),
-                            b::TypeBody::F64 => parse_num!(f64, F64),
-                            b::TypeBody::Bool
-                            | b::TypeBody::String(_)
-                            | b::TypeBody::TypeRef(_, _)
-                            | b::TypeBody::Array(_)
-                            | b::TypeBody::Ptr(_)
-                            | b::TypeBody::Inferred(_)
-                            | b::TypeBody::AnyOpaque
-                            | b::TypeBody::AnyNumber
-                            | b::TypeBody::AnySignedNumber
-                            | b::TypeBody::AnyFloat => panic!("Cannot parse {n} as {ty}"),
-                        }
-                    }
-                    b::InstrBody::CreateBool(b) => Some(types::RuntimeValue::new(
-                        Cow::Owned(b::Type::new(b::TypeBody::Bool, None)),
-                        (*b as u8).into(),
+                // The array takes the ownership of the items returned by the function
+                builder.switch_to_block(body_block);
+                let item = self.call(func_id, &[i]).unwrap();
+
+                let builder = expect_builder!(self);
+                let offset = builder.ins().imul_imm(i, item_size);
+                let item_addr = builder.ins().iadd(items, offset);
+                builder.ins().store(cl::MemFlags::new(), item, item_addr, 0);
+                let next_i = builder.ins().iadd_imm(i, 1);
+                builder.ins().jump(loop_block, &[next_i]);
+
+                builder.switch_to_block(next_block);
+
+                let value = RuntimeValue::new(
+                    Cow::Owned(b::Type::new(
+                        b::TypeBody::Array(b::ArrayType::new(
+                            func.ret.clone().into(),
+                            None,
+                        )),
+                        None,
                     )),
-                    b::InstrBody::CreateString(s) => {
-                        let (data, module) =
-                            this.globals.data_for_string(s, this.obj_module);
-                        this.obj_module = module;
-                        Some(types::RuntimeValue::new(
-                            Cow::Owned(b::Type::new(
-                                b::TypeBody::String(b::StringType::new(Some(s.len()))),
-                                None,
-                            )),
-                            data.into(),
-                        ))
-                    }
-                    b::InstrBody::CreateArray(ty, n) => {
-                        let values = this.stack.pop_many(*n);
-                        let (data, module) =
-                            this.globals.data_for_array(values.clone(), this.obj_module);
-                        this.obj_module = module;
-                        let src = if let Some(data) = data {
-                            data.into()
-                        } else if this.builder.is_some() {
-                            this.create_stack_slot(&values).into()
-                        } else {
-                            break 'match_b None;
-                        };
+                    array.into(),
+                );
+                self.own(&value);
+                self.stack.push(value);
+            }
+            b::InstrBody::StrFromPtr => {
+                let builder = expect_builder!(self);
+
+                let source = self.stack.pop().add_to_func(&self.obj_module, builder);
+                let value = self.str_from_c_str(source);
+                let value = RuntimeValue::new(
+                    Cow::Owned(b::Type::new(
+                        b::TypeBody::String(b::StringType::new(None)),
+                        None,
+                    )),
+                    value.into(),
+                );
+                self.own(&value);
+                self.stack.push(value);
+            }
+            b::InstrBody::StrAlloc => {
+                let builder = expect_builder!(self);
+
+                let len = self.stack.pop().add_to_func(&self.obj_module, builder);
+                let value = self.alloc_str(len);
+                let value = RuntimeValue::new(
+                    Cow::Owned(b::Type::new(
+                        b::TypeBody::String(b::StringType::new(None)),
+                        None,
+                    )),
+                    value.into(),
+                );
+                self.own(&value);
+                self.stack.push(value);
+            }
+            b::InstrBody::PtrIsNull => {
+                let builder = expect_builder!(self);
+
+                let source = self.stack.pop().add_to_func(&self.obj_module, builder);
+                let value = builder.ins().icmp_imm(cl::IntCC::Equal, source, 0);
+                self.stack.push(RuntimeValue::new(
+                    Cow::Owned(b::Type::new(b::TypeBody::Bool, None)),
+                    value.into(),
+                ));
+            }
+            b::InstrBody::PtrAdd => {
+                let builder = expect_builder!(self);
+
+                let offset = self.stack.pop().add_to_func(&self.obj_module, builder);
+                let ptr = self.stack.pop();
+                let b::TypeBody::Ptr(item_ty) = &ptr.ty.body else {
+                    panic!("type should be a pointer");
+                };
+                let item_size = get_size(item_ty, self.modules, &self.obj_module);
+
+                let ptr_value = ptr.add_to_func(&self.obj_module, builder);
+                let offset = builder.ins().imul_imm(offset, item_size as i64);
+                let value = builder.ins().iadd(ptr_value, offset);
+                self.stack
+                    .push(RuntimeValue::new(ptr.ty.clone(), value.into()));
+            }
+            b::InstrBody::PtrLoad => {
+                let builder = expect_builder!(self);
+
+                let ptr = self.stack.pop();
+                let b::TypeBody::Ptr(item_ty) = &ptr.ty.body else {
+                    panic!("type should be a pointer");
+                };
+
+                let ptr_value = ptr.add_to_func(&self.obj_module, builder);
+                let value = builder.ins().load(
+                    get_type(item_ty, self.modules, &self.obj_module),
+                    cl::MemFlags::new(),
+                    ptr_value,
+                    0,
+                );
+                self.stack.push(RuntimeValue::new(
+                    Cow::Owned(item_ty.as_ref().clone()),
+                    value.into(),
+                ));
+            }
+            b::InstrBody::EnvVar => {
+                let value = self.env_var();
+                self.stack.push(RuntimeValue::new(
+                    Cow::Owned(b::Type::new(
+                        b::TypeBody::Ptr(b::Type::new(b::TypeBody::U8, None).into()),
+                        None,
+                    )),
+                    value.into(),
+                ));
+            }
+            b::InstrBody::OptionIsSome => {
+                let builder = expect_builder!(self);
+
+                let source = self.stack.pop().add_to_func(&self.obj_module, builder);
+                let value = builder.ins().icmp_imm(cl::IntCC::NotEqual, source, 0);
+                self.stack.push(RuntimeValue::new(
+                    Cow::Owned(b::Type::new(b::TypeBody::Bool, None)),
+                    value.into(),
+                ));
+            }
+            b::InstrBody::OptionUnwrap => {
+                let builder = expect_builder!(self);
+
+                let source = self.stack.pop();
+                let b::TypeBody::Option(item_ty) = &source.ty.body else {
+                    panic!("type should be an option");
+                };
+
+                let source_value = source.add_to_func(&self.obj_module, builder);
+                self.add_assert(source_value, "option has no value", &instr.loc);
+
+                let builder = expect_builder!(self);
+                let value = builder.ins().load(
+                    get_type(item_ty, self.modules, &self.obj_module),
+                    cl::MemFlags::new(),
+                    source_value,
+                    0,
+                );
+                self.stack.push(RuntimeValue::new(
+                    Cow::Owned(item_ty.as_ref().clone()),
+                    value.into(),
+                ));
+            }
+            b::InstrBody::VariantIs(_, case) => {
+                let builder = expect_builder!(self);
+
+                let source = self.stack.pop().add_to_func(&self.obj_module, builder);
+                let source_case =
+                    builder
+                        .ins()
+                        .load(cl::types::I8, cl::MemFlags::new(), source, 0);
+                let value =
+                    builder
+                        .ins()
+                        .icmp_imm(cl::IntCC::Equal, source_case, *case as i64);
+                self.stack.push(RuntimeValue::new(
+                    Cow::Owned(b::Type::new(b::TypeBody::Bool, None)),
+                    value.into(),
+                ));
+            }
+            b::InstrBody::VariantUnwrap(_, case) => {
+                let source = self.stack.pop();
+                let cases = source
+                    .ty
+                    .variant_cases(self.modules)
+                    .expect("type should be a variant type");
+                let (case_name, value_ty) = cases[*case].clone();
+
+                let builder = expect_builder!(self);
+                let source_value = source.add_to_func(&self.obj_module, builder);
+                let source_case = builder.ins().load(
+                    cl::types::I8,
+                    cl::MemFlags::new(),
+                    source_value,
+                    0,
+                );
+                let is_expected =
+                    builder
+                        .ins()
+                        .icmp_imm(cl::IntCC::Equal, source_case, *case as i64);
+                self.add_assert(
+                    is_expected,
+                    &format!("value is not {case_name}"),
+                    &instr.loc,
+                );
+
+                let builder = expect_builder!(self);
+                let value = builder.ins().load(
+                    get_type(&value_ty, self.modules, &self.obj_module),
+                    cl::MemFlags::new(),
+                    source_value,
+                    types::variant_value_offset(
+                        &value_ty,
+                        self.modules,
+                        &self.obj_module,
+                    ),
+                );
+                self.stack
+                    .push(RuntimeValue::new(Cow::Owned(value_ty), value.into()));
+            }
+            b::InstrBody::Dbg(ty, label) => {
+                let value = self.stack.pop();
+
+                self.write_static_str(&format!(
+                    "{}: {label} = ",
+                    self.loc_text(&instr.loc)
+                ));
+                let value_arg =
+                    value.add_to_func(&self.obj_module, expect_builder!(self));
+                self.write_value(ty, value_arg);
+                self.write_static_str(&format!(
+                    " : {}\n",
+                    dbg::type_name(ty, self.modules)
+                ));
+
+                self.stack.push(value);
+            }
+            b::InstrBody::CompileError => {
+                panic!("never should try to compile CompileError")
+            }
+            b::InstrBody::StaticAssert(..) => {
+                panic!("static assertions should be checked before codegen")
+            }
+            b::InstrBody::Annotate(_) | b::InstrBody::Unused => {}
+            b::InstrBody::Dup(..)
+            | b::InstrBody::CreateNumber(..)
+            | b::InstrBody::CreateBool(..)
+            | b::InstrBody::CreateString(..)
+            | b::InstrBody::CreateBytes(..)
+            | b::InstrBody::CreateArray(..)
+            | b::InstrBody::CreateRecord(..)
+            | b::InstrBody::CreateSome(..)
+            | b::InstrBody::CreateNone(..)
+            | b::InstrBody::CreateVariant(..)
+            | b::InstrBody::SizeOf(..)
+            | b::InstrBody::AlignOf(..)
+            | b::InstrBody::GetGlobal(..) => unreachable!(),
+        }
+    }
+    pub fn return_value(mut self) -> FuncCodegenParts<'a, M> {
+        // The function already returned in every path, like when its body ends with a
+        // tail call
+        if self.stack.get_scope().is_never() {
+            return self.finish();
+        }
+
+        let result = self.stack.pop();
+        let owned = mem::take(&mut self.stack.get_scope_mut().payload.owned);
+        self.release_scope(slice::from_ref(&result), owned);
+
+        let builder = expect_builder!(self);
+        let value = result.add_to_func(&self.obj_module, builder);
+        builder.ins().return_(&[value]);
+
+        self.finish()
+    }
+    pub fn return_never(mut self) -> FuncCodegenParts<'a, M> {
+        let func = expect_builder!(self);
+
+        func.ins().trap(cl::TrapCode::UnreachableCodeReached);
+
+        self.finish()
+    }
+    pub fn finish(self) -> FuncCodegenParts<'a, M> {
+        (
+            self.obj_module,
+            self.globals,
+            self.funcs,
+            self.rc,
+            self.dbg,
+            self.closures,
+        )
+    }
+    pub fn value_from_instr(
+        &mut self,
+        instr: &'a b::Instr,
+    ) -> Option<types::RuntimeValue<'a>> {
+        utils::replace_with(self, |mut this| {
+            let v = 'match_b: {
+                match &instr.body {
+                    b::InstrBody::Dup(n) => Some(this.stack.get(*n).unwrap().clone()),
+                    b::InstrBody::CreateNumber(ty, n) => {
+                        macro_rules! parse_num {
+                            ($ty:ty, $variant:ident) => {{
+                                let value: $ty = n.parse().unwrap();
+                                let src = types::ValueSource::$variant(unsafe {
+                                    mem::transmute(value)
+                                });
+                                Some(types::RuntimeValue::new(Cow::Borrowed(ty), src))
+                            }};
+                        }
+
+                        match &ty.body {
+                            b::TypeBody::I8 => parse_num!(i8, I8),
+                            b::TypeBody::I16 => parse_num!(i16, I16),
+                            b::TypeBody::I32 => parse_num!(i32, I32),
+                            b::TypeBody::I64 => parse_num!(i64, I64),
+                            b::TypeBody::U8 => parse_num!(u8, I8),
+                            b::TypeBody::U16 => parse_num!(u16, I16),
+                            b::TypeBody::U32 => parse_num!(u32, I32),
+                            b::TypeBody::U64 => parse_num!(u64, I64),
+                            b::TypeBody::USize => {
+                                match this.obj_module.isa().pointer_bytes() {
+                                    1 => parse_num!(u8, I8),
+                                    2 => parse_num!(u16, I16),
+                                    4 => parse_num!(u32, I32),
+                                    8 => parse_num!(u64, I64),
+                                    _ => unreachable!(),
+                                }
+                            }
+                            b::TypeBody::F32 => parse_num!(f32, F32),
+                            b::TypeBody::F64 => parse_num!(f64, F64),
+                            b::TypeBody::Bool
+                            | b::TypeBody::String(_)
+                            | b::TypeBody::TypeRef(_, _)
+                            | b::TypeBody::Array(_)
+                            | b::TypeBody::Ptr(_)
+                            | b::TypeBody::Option(_)
+                            | b::TypeBody::Variant(..)
+                            | b::TypeBody::Func(_)
+                            | b::TypeBody::Param(_)
+                            | b::TypeBody::Inferred(_)
+                            | b::TypeBody::AnyOpaque
+                            | b::TypeBody::AnyNumber
+                            | b::TypeBody::AnySignedNumber
+                            | b::TypeBody::AnyFloat => panic!("Cannot parse {n} as {ty}"),
+                        }
+                    }
+                    b::InstrBody::CreateBool(b) => Some(types::RuntimeValue::new(
+                        Cow::Owned(b::Type::new(b::TypeBody::Bool, None)),
+                        (*b as u8).into(),
+                    )),
+                    b::InstrBody::CreateString(s) => {
+                        let (data, module) =
+                            this.globals.data_for_string(s, this.obj_module);
+                        this.obj_module = module;
+                        Some(types::RuntimeValue::new(
+                            Cow::Owned(b::Type::new(
+                                b::TypeBody::String(b::StringType::new(Some(s.len()))),
+                                None,
+                            )),
+                            data.into(),
+                        ))
+                    }
+                    b::InstrBody::CreateBytes(v) => {
+                        let (data, module) =
+                            this.globals.data_for_bytes(v, this.obj_module);
+                        this.obj_module = module;
+                        let byte_ty = b::Type::new(b::TypeBody::U8, None);
+                        Some(types::RuntimeValue::new(
+                            Cow::Owned(b::Type::new(
+                                b::TypeBody::Array(b::ArrayType::new(
+                                    byte_ty.into(),
+                                    Some(v.len()),
+                                )),
+                                None,
+                            )),
+                            data.into(),
+                        ))
+                    }
+                    b::InstrBody::CreateArray(ty, n) => {
+                        let items = this.stack.pop_many(*n);
+                        let (data, module) =
+                            this.globals.data_for_array(items.clone(), this.obj_module);
+                        this.obj_module = module;
+                        let src = if let Some(data) = data {
+                            data.into()
+                        } else if this.builder.is_some() {
+                            this.create_heap_array(ty, &items).into()
+                        } else {
+                            break 'match_b None;
+                        };
+                        let value = types::RuntimeValue::new(Cow::Borrowed(ty), src);
+                        this.own(&value);
+                        Some(value)
+                    }
+                    b::InstrBody::CreateRecord(ty, fields) => {
+                        let values = types::tuple_from_record(
+                            izip!(fields, this.stack.pop_many(fields.len())),
+                            ty,
+                            this.modules,
+                        );
+                        let (data, module) =
+                            this.globals.data_for_tuple(values.clone(), this.obj_module);
+                        this.obj_module = module;
+                        let src = if let Some(data) = data {
+                            data.into()
+                        } else if this.builder.is_some() {
+                            this.create_heap_value(&values).into()
+                        } else {
+                            break 'match_b None;
+                        };
+                        let value = types::RuntimeValue::new(Cow::Borrowed(ty), src);
+                        this.own(&value);
+                        Some(value)
+                    }
+                    b::InstrBody::CreateSome(ty) => {
+                        let values = this.stack.pop_many(1);
+                        let (data, module) =
+                            this.globals.data_for_tuple(values.clone(), this.obj_module);
+                        this.obj_module = module;
+                        let src = if let Some(data) = data {
+                            data.into()
+                        } else if this.builder.is_some() {
+                            this.create_heap_value(&values).into()
+                        } else {
+                            break 'match_b None;
+                        };
+                        let value = types::RuntimeValue::new(Cow::Borrowed(ty), src);
+                        this.own(&value);
+                        Some(value)
+                    }
+                    b::InstrBody::CreateVariant(ty, case) => {
+                        let values = vec![
+                            types::RuntimeValue::new(
+                                Cow::Owned(b::Type::new(b::TypeBody::U8, None)),
+                                (*case as u8).into(),
+                            ),
+                            this.stack.pop(),
+                        ];
+                        let (data, module) =
+                            this.globals.data_for_tuple(values.clone(), this.obj_module);
+                        this.obj_module = module;
+                        let src = if let Some(data) = data {
+                            data.into()
+                        } else if this.builder.is_some() {
+                            this.create_heap_value(&values).into()
+                        } else {
+                            break 'match_b None;
+                        };
+                        let value = types::RuntimeValue::new(Cow::Borrowed(ty), src);
+                        this.own(&value);
+                        Some(value)
+                    }
+                    b::InstrBody::SizeOf(ty) | b::InstrBody::AlignOf(ty) => {
+                        let ptr_bytes = this.obj_module.isa().pointer_bytes();
+                        let value = match &instr.body {
+                            b::InstrBody::SizeOf(_) => {
+                                b::size_of(ty, this.modules, ptr_bytes)
+                            }
+                            _ => b::align_of(ty, this.modules, ptr_bytes),
+                        };
+                        let src = match ptr_bytes {
+                            4 => types::ValueSource::I32(value as u32),
+                            8 => types::ValueSource::I64(value as u64),
+                            _ => unreachable!(),
+                        };
+                        Some(types::RuntimeValue::new(
+                            Cow::Owned(b::Type::new(b::TypeBody::USize, None)),
+                            src,
+                        ))
+                    }
+                    // Options without a value are null pointers, which have nothing to
+                    // be counted or freed
+                    b::InstrBody::CreateNone(ty) => {
+                        let src = match this.obj_module.isa().pointer_bytes() {
+                            4 => types::ValueSource::I32(0),
+                            8 => types::ValueSource::I64(0),
+                            _ => unreachable!(),
+                        };
                         Some(types::RuntimeValue::new(Cow::Borrowed(ty), src))
                     }
-                    b::InstrBody::CreateRecord(ty, fields) => {
-                        let values = types::tuple_from_record(
-                            izip!(fields, this.stack.pop_many(fields.len())),
-                            ty,
-                            this.modules,
-                        );
-                        let (data, module) =
-                            this.globals.data_for_tuple(values.clone(), this.obj_module);
-                        this.obj_module = module;
-                        let src = if let Some(data) = data {
-                            data.into()
-                        } else if this.builder.is_some() {
-                            this.create_stack_slot(&values).into()
+                    b::InstrBody::GetGlobal(mod_idx, global_idx) => {
+                        let global = this
+                            .globals
+                            .get_global(*mod_idx, *global_idx)
+                            .expect("global idx out of range");
+                        if global.is_const {
+                            break 'match_b Some(global.value.clone());
+                        }
+
+                        // Globals that are not constant are initialized by the entry
+                        // point, so their values should be loaded from their data
+                        let value = global.value.clone();
+                        let types::ValueSource::Data(data_id) = value.src else {
+                            panic!("global that is not constant should have data");
+                        };
+                        let Some(builder) = &mut this.builder else {
+                            break 'match_b None;
+                        };
+                        let gv =
+                            this.obj_module.declare_data_in_func(data_id, builder.func);
+                        let ptr_ty = this.obj_module.isa().pointer_type();
+                        let ptr = builder.ins().global_value(ptr_ty, gv);
+                        let loaded = builder.ins().load(
+                            get_type(&value.ty, this.modules, &this.obj_module),
+                            cl::MemFlags::new(),
+                            ptr,
+                            0,
+                        );
+                        Some(types::RuntimeValue::new(value.ty, loaded.into()))
+                    }
+                    _ => None,
+                }
+            };
+
+            (this, v)
+        })
+    }
+    pub fn store_global(&mut self, value: RuntimeValue, global: &GlobalBinding) {
+        let types::ValueSource::Data(data_id) = &global.value.src else {
+            panic!("should never try to store a global that is a const");
+        };
+
+        // The global holds a reference to the value for the rest of the program
+        self.retain(&value);
+
+        let builder = expect_builder!(self);
+
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let value = value.add_to_func(&mut self.obj_module, builder);
+        let gv = self
+            .obj_module
+            .declare_data_in_func(*data_id, &mut builder.func);
+        let ptr = builder.ins().global_value(ptr_ty, gv);
+        builder.ins().store(cl::MemFlags::new(), value, ptr, 0);
+    }
+    pub fn call(&mut self, func_id: cl::FuncId, args: &[cl::Value]) -> Option<cl::Value> {
+        let func_ref = self.func_ref(func_id);
+        let builder = expect_builder!(self);
+
+        let instr = builder.ins().call(func_ref, &args);
+        let results = builder.inst_results(instr);
+        assert!(results.len() <= 1);
+
+        if results.is_empty() {
+            None
+        } else {
+            Some(results[0])
+        }
+    }
+    /// Calls the function with the arguments at the top of the stack, pushing its result
+    fn add_call(&mut self, mod_idx: usize, func_idx: usize) {
+        let binding = self.funcs.get(&(mod_idx, func_idx)).unwrap();
+        let (func_id, is_extern) = (binding.func_id, binding.is_extern);
+        let func = &self.modules[mod_idx].funcs[func_idx];
+
+        let args = self.stack.pop_many(func.params.len());
+        // The callee owns its parameters, except extern functions, which only borrow
+        // them
+        if !is_extern {
+            for arg in &args {
+                self.retain(arg);
+            }
+        }
+
+        let builder = expect_builder!(self);
+        let args = args
+            .into_iter()
+            .map(|arg| arg.add_to_func(&self.obj_module, builder))
+            .collect_vec();
+
+        if let Some(value) = self.call(func_id, &args) {
+            let value = types::RuntimeValue::new(Cow::Borrowed(&func.ret), value.into());
+            self.own(&value);
+            self.stack.push(value);
+        }
+    }
+    /// Calls a function value through the function stored at its start, pushing its
+    /// result. Like the functions they call, function values own their parameters
+    fn call_value(&mut self, callee: RuntimeValue<'a>, args: Vec<RuntimeValue<'a>>) {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let b::TypeBody::Func(func_ty) = &callee.ty.body else {
+            panic!("type should be a function type");
+        };
+        let ret_ty = func_ty.ret.clone();
+        let sig = closure::call_signature(func_ty, self.modules, &self.obj_module);
+
+        for arg in &args {
+            self.retain(arg);
+        }
+
+        let builder = expect_builder!(self);
+        let callee = callee.add_to_func(&self.obj_module, builder);
+        let mut values = vec![callee];
+        for arg in &args {
+            values.push(arg.add_to_func(&self.obj_module, builder));
+        }
+        let call_ptr = builder.ins().load(ptr_ty, cl::MemFlags::new(), callee, 0);
+        let sig_ref = builder.import_signature(sig);
+        let instr = builder.ins().call_indirect(sig_ref, call_ptr, &values);
+        let result = builder.inst_results(instr)[0];
+
+        let value = types::RuntimeValue::new(Cow::Owned(ret_ty), result.into());
+        self.own(&value);
+        self.stack.push(value);
+    }
+    fn get_field(&mut self, source: RuntimeValue<'a>, name: &str) -> RuntimeValue<'a> {
+        let b::Type {
+            body: b::TypeBody::TypeRef(mod_idx, ty_idx),
+            ..
+        } = source.ty.as_ref()
+        else {
+            panic!("type should be a record type");
+        };
+        let b::TypeDefBody::Record(rec) = &self.modules[*mod_idx].typedefs[*ty_idx].body
+        else {
+            panic!("type should be a record type");
+        };
+
+        let (field_idx, field) = rec
+            .fields
+            .values()
+            .find_position(|field| field.name.name == name)
+            .expect("field should be present in record");
+        let ptr_bytes = self.obj_module.isa().pointer_bytes();
+        let offset = b::record_layout(rec, ptr_bytes).offsets[field_idx];
+
+        let builder = expect_builder!(self);
+        let source_value = source.add_to_func(&self.obj_module, builder);
+        let value = builder.ins().load(
+            get_type(&field.ty, self.modules, &self.obj_module),
+            cl::MemFlags::new(),
+            source_value,
+            offset as i32,
+        );
+        types::RuntimeValue::new(Cow::Borrowed(&field.ty), value.into())
+    }
+    fn func_ref(&mut self, func_id: cl::FuncId) -> cl::FuncRef {
+        let builder = expect_builder!(self);
+
+        match self.declared_funcs.get(&func_id) {
+            Some(func_ref) => *func_ref,
+            None => {
+                let func_ref =
+                    self.obj_module.declare_func_in_func(func_id, builder.func);
+                self.declared_funcs.insert(func_id, func_ref);
+                func_ref
+            }
+        }
+    }
+
+    /// Calls a function from libc, declaring it in the module if needed. The signature is
+    /// derived from the types of the arguments and the expected return type
+    pub fn call_libc(
+        &mut self,
+        name: &str,
+        args: &[cl::Value],
+        ret: Option<cl::Type>,
+    ) -> Option<cl::Value> {
+        let builder = expect_builder!(self);
+
+        let mut sig = self.obj_module.make_signature();
+        for arg in args {
+            sig.params
+                .push(cl::AbiParam::new(builder.func.dfg.value_type(*arg)));
+        }
+        if let Some(ret) = ret {
+            sig.returns.push(cl::AbiParam::new(ret));
+        }
+
+        let func_id = self
+            .obj_module
+            .declare_function(name, cl::Linkage::Import, &sig)
+            .unwrap();
+        self.call(func_id, args)
+    }
+    /// Writes the bytes to stderr
+    fn write_stderr(&mut self, ptr: cl::Value, len: cl::Value) {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let fd = expect_builder!(self).ins().iconst(cl::types::I32, 2);
+        self.call_libc("write", &[fd, ptr, len], Some(ptr_ty));
+    }
+    /// Pointer to the null-terminated content of a static string
+    fn static_str_ptr(&mut self, value: &str) -> cl::Value {
+        let data_id = utils::replace_with(self, |mut this| {
+            let (data_id, module) = this.globals.data_for_string(value, this.obj_module);
+            this.obj_module = module;
+            (this, data_id)
+        });
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let ptr_bytes = self.obj_module.isa().pointer_bytes() as i64;
+
+        let builder = expect_builder!(self);
+        let gv = self.obj_module.declare_data_in_func(data_id, builder.func);
+        let ptr = builder.ins().global_value(ptr_ty, gv);
+        // Skip the reference count and the length of the string
+        builder.ins().iadd_imm(ptr, 2 * ptr_bytes)
+    }
+    fn write_static_str(&mut self, value: &str) {
+        let ptr = self.static_str_ptr(value);
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let len = expect_builder!(self)
+            .ins()
+            .iconst(ptr_ty, value.len() as i64);
+        self.write_stderr(ptr, len);
+    }
+    /// Writes the decimal digits of an unsigned 64-bit number
+    fn write_digits(&mut self, n: cl::Value) {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        // Enough for the largest 64-bit number
+        let buf_size = 20;
+
+        let builder = expect_builder!(self);
+        let slot = builder.create_sized_stack_slot(cl::StackSlotData::new(
+            cl::StackSlotKind::ExplicitSlot,
+            buf_size,
+        ));
+        let buf = builder.ins().stack_addr(ptr_ty, slot, 0);
+
+        // Digits are written from the end of the buffer, starting by the last one
+        let loop_block = builder.create_block();
+        let rest = builder.append_block_param(loop_block, cl::types::I64);
+        let pos = builder.append_block_param(loop_block, ptr_ty);
+        let next_block = builder.create_block();
+        let start = builder.append_block_param(next_block, ptr_ty);
+
+        let end = builder.ins().iconst(ptr_ty, buf_size as i64);
+        builder.ins().jump(loop_block, &[n, end]);
+
+        builder.switch_to_block(loop_block);
+        let pos = builder.ins().iadd_imm(pos, -1);
+        let digit = builder.ins().urem_imm(rest, 10);
+        let digit = builder.ins().iadd_imm(digit, b'0' as i64);
+        let digit = builder.ins().ireduce(cl::types::I8, digit);
+        let addr = builder.ins().iadd(buf, pos);
+        builder.ins().store(cl::MemFlags::new(), digit, addr, 0);
+        let rest = builder.ins().udiv_imm(rest, 10);
+        builder
+            .ins()
+            .brif(rest, loop_block, &[rest, pos], next_block, &[pos]);
+
+        builder.switch_to_block(next_block);
+        let ptr = builder.ins().iadd(buf, start);
+        let len = builder.ins().irsub_imm(start, buf_size as i64);
+        self.write_stderr(ptr, len);
+    }
+    /// Writes a value to stderr with the function built for its type
+    fn write_value(&mut self, ty: &b::Type, value: cl::Value) {
+        let func_id = self.dbg.write_func(ty, self.modules, &mut self.obj_module);
+        self.call(func_id, &[value]);
+    }
+    /// Location of the instruction as `file:line:col`
+    fn loc_text(&self, loc: &b::Loc) -> String {
+        // Every module knows the sources parsed before it, which use the same indexes
+        let source = self
+            .modules
+            .iter()
+            .find_map(|module| module.sources.get(loc.source_idx))
+            .expect("source should exist");
+        format!(
+            "{}:{}:{}",
+            source.path.display(),
+            loc.start_line,
+            loc.start_col
+        )
+    }
+    /// Copies a null-terminated C string into a newly allocated string value
+    pub fn str_from_c_str(&mut self, c_str: cl::Value) -> cl::Value {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let ptr_bytes = self.obj_module.isa().pointer_bytes() as i64;
+
+        let len = self.call_libc("strlen", &[c_str], Some(ptr_ty)).unwrap();
+
+        let builder = expect_builder!(self);
+        let size = builder.ins().iadd_imm(len, ptr_bytes + 1);
+        let buf = self.alloc(size);
+
+        let builder = expect_builder!(self);
+        builder.ins().store(cl::MemFlags::new(), len, buf, 0);
+        let content = builder.ins().iadd_imm(buf, ptr_bytes);
+        // Copy the null terminator as well, like static strings do
+        let content_len = builder.ins().iadd_imm(len, 1);
+        self.call_libc("memcpy", &[content, c_str, content_len], Some(ptr_ty));
+
+        buf
+    }
+    /// Allocates a zero-filled string with the given length. The extra byte of zero at
+    /// the end makes it a valid C string as well
+    pub fn alloc_str(&mut self, len: cl::Value) -> cl::Value {
+        let ptr_bytes = self.obj_module.isa().pointer_bytes() as i64;
+
+        let builder = expect_builder!(self);
+        let size = builder.ins().iadd_imm(len, ptr_bytes + 1);
+        let buf = self.alloc(size);
+
+        let builder = expect_builder!(self);
+        builder.ins().store(cl::MemFlags::new(), len, buf, 0);
+
+        buf
+    }
+    /// Creates an array of strings from a C array of null-terminated strings, like `argv`
+    pub fn array_from_c_strs(&mut self, len: cl::Value, c_strs: cl::Value) -> cl::Value {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let ptr_bytes = self.obj_module.isa().pointer_bytes() as i64;
+
+        let builder = expect_builder!(self);
+        let len = if builder.func.dfg.value_type(len) == ptr_ty {
+            len
+        } else {
+            builder.ins().uextend(ptr_ty, len)
+        };
+        let (array, items) = self.alloc_array(len, ptr_bytes);
+
+        let builder = expect_builder!(self);
+
+        let loop_block = builder.create_block();
+        let i = builder.append_block_param(loop_block, ptr_ty);
+        let body_block = builder.create_block();
+        let next_block = builder.create_block();
+
+        let zero = builder.ins().iconst(ptr_ty, 0);
+        builder.ins().jump(loop_block, &[zero]);
+
+        builder.switch_to_block(loop_block);
+        let cond = builder.ins().icmp(cl::IntCC::UnsignedLessThan, i, len);
+        builder.ins().brif(cond, body_block, &[], next_block, &[]);
+
+        builder.switch_to_block(body_block);
+        let offset = builder.ins().imul_imm(i, ptr_bytes);
+        let c_str_addr = builder.ins().iadd(c_strs, offset);
+        let c_str = builder
+            .ins()
+            .load(ptr_ty, cl::MemFlags::new(), c_str_addr, 0);
+        let item = self.str_from_c_str(c_str);
+
+        let builder = expect_builder!(self);
+        let item_addr = builder.ins().iadd(items, offset);
+        builder.ins().store(cl::MemFlags::new(), item, item_addr, 0);
+        let next_i = builder.ins().iadd_imm(i, 1);
+        builder.ins().jump(loop_block, &[next_i]);
+
+        builder.switch_to_block(next_block);
+
+        array
+    }
+    /// Builds the body of the function that increments the reference count of a value
+    pub fn build_retain(&mut self) {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let header_size = self.obj_module.isa().pointer_bytes() as i32;
+
+        let builder = expect_builder!(self);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        let value = builder.block_params(block)[0];
+
+        let count_block = builder.create_block();
+        let incr_block = builder.create_block();
+        let end_block = builder.create_block();
+
+        // Options without a value are null, so they have no count
+        builder.ins().brif(value, count_block, &[], end_block, &[]);
+
+        builder.switch_to_block(count_block);
+        let count = builder
+            .ins()
+            .load(ptr_ty, cl::MemFlags::new(), value, -header_size);
+        builder.ins().brif(count, incr_block, &[], end_block, &[]);
+
+        builder.switch_to_block(incr_block);
+        let count = builder.ins().iadd_imm(count, 1);
+        builder
+            .ins()
+            .store(cl::MemFlags::new(), count, value, -header_size);
+        builder.ins().jump(end_block, &[]);
+
+        builder.switch_to_block(end_block);
+        builder.ins().return_(&[]);
+    }
+    /// Builds the body of the function that decrements the reference count of a value of
+    /// the given type. When no references are left, the value is freed and the values
+    /// stored in it are released as well
+    pub fn build_release(&mut self, ty: &b::Type) {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let ptr_bytes = self.obj_module.isa().pointer_bytes() as i64;
+        let header_size = ptr_bytes as i32;
+
+        let builder = expect_builder!(self);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        let value = builder.block_params(block)[0];
+
+        let count_block = builder.create_block();
+        let decr_block = builder.create_block();
+        let free_block = builder.create_block();
+        let end_block = builder.create_block();
+
+        // Options without a value are null, so they have no count
+        builder.ins().brif(value, count_block, &[], end_block, &[]);
+
+        builder.switch_to_block(count_block);
+        let count = builder
+            .ins()
+            .load(ptr_ty, cl::MemFlags::new(), value, -header_size);
+        builder.ins().brif(count, decr_block, &[], end_block, &[]);
+
+        builder.switch_to_block(decr_block);
+        let count = builder.ins().iadd_imm(count, -1);
+        builder
+            .ins()
+            .store(cl::MemFlags::new(), count, value, -header_size);
+        builder.ins().brif(count, end_block, &[], free_block, &[]);
+
+        builder.switch_to_block(free_block);
+        match &ty.body {
+            b::TypeBody::TypeRef(mod_idx, ty_idx) => {
+                let b::TypeDefBody::Record(rec) =
+                    &self.modules[*mod_idx].typedefs[*ty_idx].body
+                else {
+                    panic!("type should be a record type");
+                };
+
+                let ptr_bytes = self.obj_module.isa().pointer_bytes();
+                let layout = b::record_layout(rec, ptr_bytes);
+                for (field, offset) in izip!(rec.fields.values(), layout.offsets) {
+                    if rc::is_managed(&field.ty) {
+                        let field_ty =
+                            get_type(&field.ty, self.modules, &self.obj_module);
+                        let builder = expect_builder!(self);
+                        let field_value = builder.ins().load(
+                            field_ty,
+                            cl::MemFlags::new(),
+                            value,
+                            offset as i32,
+                        );
+                        self.release(&RuntimeValue::new(
+                            Cow::Borrowed(&field.ty),
+                            field_value.into(),
+                        ));
+                    }
+                }
+            }
+            b::TypeBody::Array(array_ty) => {
+                let array_header = types::ArrayHeader::new(&self.obj_module);
+
+                let builder = expect_builder!(self);
+                let owner_block = builder.create_block();
+                let items_block = builder.create_block();
+                let next_block = builder.create_block();
+
+                let owner = builder.ins().load(
+                    ptr_ty,
+                    cl::MemFlags::new(),
+                    value,
+                    array_header.owner,
+                );
+                builder
+                    .ins()
+                    .brif(owner, owner_block, &[], items_block, &[]);
+
+                // Slices don't own their items, only a reference to the array that does
+                builder.switch_to_block(owner_block);
+                self.release(&RuntimeValue::new(Cow::Borrowed(ty), owner.into()));
+                expect_builder!(self).ins().jump(next_block, &[]);
+
+                expect_builder!(self).switch_to_block(items_block);
+                if rc::is_managed(&array_ty.item) {
+                    let item_size =
+                        get_type(&array_ty.item, self.modules, &self.obj_module).bytes();
+
+                    let builder = expect_builder!(self);
+                    let len = builder.ins().load(
+                        ptr_ty,
+                        cl::MemFlags::new(),
+                        value,
+                        array_header.len,
+                    );
+                    let items = builder.ins().load(
+                        ptr_ty,
+                        cl::MemFlags::new(),
+                        value,
+                        array_header.items,
+                    );
+
+                    let loop_block = builder.create_block();
+                    let i = builder.append_block_param(loop_block, ptr_ty);
+                    let body_block = builder.create_block();
+
+                    let zero = builder.ins().iconst(ptr_ty, 0);
+                    builder.ins().jump(loop_block, &[zero]);
+
+                    builder.switch_to_block(loop_block);
+                    let cond = builder.ins().icmp(cl::IntCC::UnsignedLessThan, i, len);
+                    builder.ins().brif(cond, body_block, &[], next_block, &[]);
+
+                    builder.switch_to_block(body_block);
+                    let offset = builder.ins().imul_imm(i, item_size as i64);
+                    let item_addr = builder.ins().iadd(items, offset);
+                    let item =
+                        builder
+                            .ins()
+                            .load(ptr_ty, cl::MemFlags::new(), item_addr, 0);
+                    self.release(&RuntimeValue::new(
+                        Cow::Borrowed(&array_ty.item),
+                        item.into(),
+                    ));
+
+                    let builder = expect_builder!(self);
+                    let next_i = builder.ins().iadd_imm(i, 1);
+                    builder.ins().jump(loop_block, &[next_i]);
+                } else {
+                    expect_builder!(self).ins().jump(next_block, &[]);
+                }
+
+                expect_builder!(self).switch_to_block(next_block);
+            }
+            b::TypeBody::Option(item_ty) if rc::is_managed(item_ty) => {
+                let builder = expect_builder!(self);
+                let item = builder.ins().load(
+                    get_type(item_ty, self.modules, &self.obj_module),
+                    cl::MemFlags::new(),
+                    value,
+                    0,
+                );
+                self.release(&RuntimeValue::new(Cow::Borrowed(item_ty), item.into()));
+            }
+            b::TypeBody::Variant(..) => {
+                let cases = ty
+                    .variant_cases(self.modules)
+                    .expect("type should be a variant type");
+
+                let builder = expect_builder!(self);
+                let next_block = builder.create_block();
+                let case =
+                    builder
+                        .ins()
+                        .load(cl::types::I8, cl::MemFlags::new(), value, 0);
+
+                // Only the cases holding managed values have something to release
+                for (i, (_, case_ty)) in enumerate(&cases) {
+                    if !rc::is_managed(case_ty) {
+                        continue;
+                    }
+
+                    let builder = expect_builder!(self);
+                    let case_block = builder.create_block();
+                    let else_block = builder.create_block();
+                    let is_case =
+                        builder.ins().icmp_imm(cl::IntCC::Equal, case, i as i64);
+                    builder
+                        .ins()
+                        .brif(is_case, case_block, &[], else_block, &[]);
+
+                    builder.switch_to_block(case_block);
+                    let case_value = builder.ins().load(
+                        get_type(case_ty, self.modules, &self.obj_module),
+                        cl::MemFlags::new(),
+                        value,
+                        types::variant_value_offset(
+                            case_ty,
+                            self.modules,
+                            &self.obj_module,
+                        ),
+                    );
+                    self.release(&RuntimeValue::new(
+                        Cow::Borrowed(case_ty),
+                        case_value.into(),
+                    ));
+                    expect_builder!(self).ins().jump(next_block, &[]);
+
+                    expect_builder!(self).switch_to_block(else_block);
+                }
+                expect_builder!(self).ins().jump(next_block, &[]);
+
+                expect_builder!(self).switch_to_block(next_block);
+            }
+            // The values bound to a function value are released by the function stored
+            // along with them
+            b::TypeBody::Func(_) => {
+                let sig = rc::rc_signature(&self.obj_module);
+                let builder = expect_builder!(self);
+                let drop_ptr = builder.ins().load(
+                    ptr_ty,
+                    cl::MemFlags::new(),
+                    value,
+                    ptr_bytes as i32,
+                );
+                let sig_ref = builder.import_signature(sig);
+                builder.ins().call_indirect(sig_ref, drop_ptr, &[value]);
+            }
+            _ => {}
+        }
+
+        let builder = expect_builder!(self);
+        let header = builder.ins().iadd_imm(value, -ptr_bytes);
+        self.call_libc("free", &[header], None);
+
+        let builder = expect_builder!(self);
+        builder.ins().jump(end_block, &[]);
+
+        builder.switch_to_block(end_block);
+        builder.ins().return_(&[]);
+    }
+
+    /// Builds the function that calls the target of a function value, with the values
+    /// bound to the function value and the arguments it's called with
+    pub fn build_closure_call(&mut self, key: &closure::ClosureKey) {
+        let (mod_idx, func_idx, bound) = key;
+        let binding = self.funcs.get(&(*mod_idx, *func_idx)).unwrap();
+        let (func_id, is_extern) = (binding.func_id, binding.is_extern);
+        let func = &self.modules[*mod_idx].funcs[*func_idx];
+
+        let builder = expect_builder!(self);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        let (closure_value, open_values) = match builder.block_params(block) {
+            [closure_value, open_values @ ..] => (*closure_value, open_values.to_vec()),
+            [] => unreachable!(),
+        };
+
+        let mut open_values = open_values.into_iter();
+        let mut offsets =
+            closure::bound_offsets(key, self.modules, &self.obj_module).into_iter();
+        let mut args = vec![];
+        let mut open_args = vec![];
+        for (param, is_bound) in izip!(&func.params, bound) {
+            let param_ty = get_type(&param.ty, self.modules, &self.obj_module);
+            if *is_bound {
+                let value = expect_builder!(self).ins().load(
+                    param_ty,
+                    cl::MemFlags::new(),
+                    closure_value,
+                    offsets.next().unwrap(),
+                );
+                let value = RuntimeValue::new(Cow::Borrowed(&param.ty), value.into());
+                // The function value keeps its values, so the callee needs its own
+                // references to them
+                if !is_extern {
+                    self.retain(&value);
+                }
+                args.push(value);
+            } else {
+                let value = open_values.next().unwrap();
+                let value = RuntimeValue::new(Cow::Borrowed(&param.ty), value.into());
+                open_args.push(value.clone());
+                args.push(value);
+            }
+        }
+
+        let builder = expect_builder!(self);
+        let args = args
+            .iter()
+            .map(|arg| arg.add_to_func(&self.obj_module, builder))
+            .collect_vec();
+        if is_extern {
+            // Extern functions only borrow their arguments, but the function value owns
+            // the ones it's called with
+            let result = self.call(func_id, &args).unwrap();
+            for arg in &open_args {
+                self.release(arg);
+            }
+            expect_builder!(self).ins().return_(&[result]);
+        } else {
+            let func_ref = self.func_ref(func_id);
+            expect_builder!(self).ins().return_call(func_ref, &args);
+        }
+    }
+    /// Builds the function that releases the values bound to a function value, called
+    /// when the function value is freed
+    pub fn build_closure_drop(&mut self, key: &closure::ClosureKey) {
+        let (mod_idx, func_idx, bound) = key;
+        let func = &self.modules[*mod_idx].funcs[*func_idx];
+
+        let builder = expect_builder!(self);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        let closure_value = builder.block_params(block)[0];
+
+        let bound_params = izip!(&func.params, bound)
+            .filter(|(_, is_bound)| **is_bound)
+            .map(|(param, _)| param);
+        let offsets = closure::bound_offsets(key, self.modules, &self.obj_module);
+        for (param, offset) in izip!(bound_params, offsets) {
+            if rc::is_managed(&param.ty) {
+                let param_ty = get_type(&param.ty, self.modules, &self.obj_module);
+                let value = expect_builder!(self).ins().load(
+                    param_ty,
+                    cl::MemFlags::new(),
+                    closure_value,
+                    offset,
+                );
+                self.release(&RuntimeValue::new(Cow::Borrowed(&param.ty), value.into()));
+            }
+        }
+
+        expect_builder!(self).ins().return_(&[]);
+    }
+
+    /// Builds the function that writes a value of the given type to stderr, with the same
+    /// syntax used to create it
+    pub fn build_dbg_write(&mut self, ty: &b::Type) {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let ptr_bytes = self.obj_module.isa().pointer_bytes() as i64;
+
+        let builder = expect_builder!(self);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        let value = builder.block_params(block)[0];
+
+        match &ty.body {
+            b::TypeBody::Bool => {
+                let builder = expect_builder!(self);
+                let true_block = builder.create_block();
+                let false_block = builder.create_block();
+                let next_block = builder.create_block();
+                builder.ins().brif(value, true_block, &[], false_block, &[]);
+
+                for (block, text) in [(true_block, "true"), (false_block, "false")] {
+                    expect_builder!(self).switch_to_block(block);
+                    self.write_static_str(text);
+                    expect_builder!(self).ins().jump(next_block, &[]);
+                }
+
+                expect_builder!(self).switch_to_block(next_block);
+            }
+            b::TypeBody::I8 | b::TypeBody::I16 | b::TypeBody::I32 | b::TypeBody::I64 => {
+                let builder = expect_builder!(self);
+                let n = if builder.func.dfg.value_type(value) == cl::types::I64 {
+                    value
+                } else {
+                    builder.ins().sextend(cl::types::I64, value)
+                };
+
+                let neg_block = builder.create_block();
+                let next_block = builder.create_block();
+                let abs = builder.append_block_param(next_block, cl::types::I64);
+                let is_neg = builder.ins().icmp_imm(cl::IntCC::SignedLessThan, n, 0);
+                builder.ins().brif(is_neg, neg_block, &[], next_block, &[n]);
+
+                builder.switch_to_block(neg_block);
+                self.write_static_str("-");
+                let builder = expect_builder!(self);
+                // The minimum value is its own negation, but its digits are still right
+                // when read as unsigned
+                let neg = builder.ins().ineg(n);
+                builder.ins().jump(next_block, &[neg]);
+
+                expect_builder!(self).switch_to_block(next_block);
+                self.write_digits(abs);
+            }
+            b::TypeBody::U8
+            | b::TypeBody::U16
+            | b::TypeBody::U32
+            | b::TypeBody::U64
+            | b::TypeBody::USize
+            | b::TypeBody::Ptr(_) => {
+                let builder = expect_builder!(self);
+                let n = if builder.func.dfg.value_type(value) == cl::types::I64 {
+                    value
+                } else {
+                    builder.ins().uextend(cl::types::I64, value)
+                };
+                self.write_digits(n);
+            }
+            b::TypeBody::F32 | b::TypeBody::F64 => {
+                let builder = expect_builder!(self);
+                let n = if builder.func.dfg.value_type(value) == cl::types::F64 {
+                    value
+                } else {
+                    builder.ins().fpromote(cl::types::F64, value)
+                };
+
+                let buf_size = 32;
+                let slot = builder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    buf_size,
+                ));
+                let buf = builder.ins().stack_addr(ptr_ty, slot, 0);
+                let size = builder.ins().iconst(ptr_ty, buf_size as i64);
+                let format = self.static_str_ptr("%g");
+                self.call_libc("strfromd", &[buf, size, format, n], Some(cl::types::I32));
+                let len = self.call_libc("strlen", &[buf], Some(ptr_ty)).unwrap();
+                self.write_stderr(buf, len);
+            }
+            b::TypeBody::String(_) => {
+                self.write_static_str("\"");
+                let builder = expect_builder!(self);
+                let len = builder.ins().load(ptr_ty, cl::MemFlags::new(), value, 0);
+                let content = builder.ins().iadd_imm(value, ptr_bytes);
+                self.write_stderr(content, len);
+                self.write_static_str("\"");
+            }
+            b::TypeBody::Array(array_ty) => {
+                let array_header = types::ArrayHeader::new(&self.obj_module);
+                let item_ty = get_type(&array_ty.item, self.modules, &self.obj_module);
+
+                self.write_static_str("[");
+
+                let builder = expect_builder!(self);
+                let len = builder.ins().load(
+                    ptr_ty,
+                    cl::MemFlags::new(),
+                    value,
+                    array_header.len,
+                );
+                let items = builder.ins().load(
+                    ptr_ty,
+                    cl::MemFlags::new(),
+                    value,
+                    array_header.items,
+                );
+
+                let loop_block = builder.create_block();
+                let i = builder.append_block_param(loop_block, ptr_ty);
+                let body_block = builder.create_block();
+                let sep_block = builder.create_block();
+                let item_block = builder.create_block();
+                let next_block = builder.create_block();
+
+                let zero = builder.ins().iconst(ptr_ty, 0);
+                builder.ins().jump(loop_block, &[zero]);
+
+                builder.switch_to_block(loop_block);
+                let cond = builder.ins().icmp(cl::IntCC::UnsignedLessThan, i, len);
+                builder.ins().brif(cond, body_block, &[], next_block, &[]);
+
+                builder.switch_to_block(body_block);
+                builder.ins().brif(i, sep_block, &[], item_block, &[]);
+
+                builder.switch_to_block(sep_block);
+                self.write_static_str(", ");
+                expect_builder!(self).ins().jump(item_block, &[]);
+
+                let builder = expect_builder!(self);
+                builder.switch_to_block(item_block);
+                let offset = builder.ins().imul_imm(i, item_ty.bytes() as i64);
+                let item_addr = builder.ins().iadd(items, offset);
+                let item = builder
+                    .ins()
+                    .load(item_ty, cl::MemFlags::new(), item_addr, 0);
+                self.write_value(&array_ty.item, item);
+                let builder = expect_builder!(self);
+                let next_i = builder.ins().iadd_imm(i, 1);
+                builder.ins().jump(loop_block, &[next_i]);
+
+                expect_builder!(self).switch_to_block(next_block);
+                self.write_static_str("]");
+            }
+            b::TypeBody::TypeRef(mod_idx, ty_idx) => {
+                let typedef = &self.modules[*mod_idx].typedefs[*ty_idx];
+                let b::TypeDefBody::Record(rec) = &typedef.body else {
+                    panic!("type should be a record type");
+                };
+
+                let fields = dbg::record_fields(rec, &self.obj_module);
+                if fields.is_empty() {
+                    self.write_static_str(&format!("{} {{}}", typedef.name));
+                } else {
+                    for (i, (field, offset)) in fields.into_iter().enumerate() {
+                        let sep = if i == 0 {
+                            format!("{} {{ ", typedef.name)
                         } else {
-                            break 'match_b None;
+                            ", ".to_string()
                         };
-                        Some(types::RuntimeValue::new(Cow::Borrowed(ty), src))
+                        self.write_static_str(&format!("{sep}{}: ", field.name.name));
+
+                        let builder = expect_builder!(self);
+                        let field_value = builder.ins().load(
+                            get_type(&field.ty, self.modules, &self.obj_module),
+                            cl::MemFlags::new(),
+                            value,
+                            offset,
+                        );
+                        self.write_value(&field.ty, field_value);
                     }
-                    b::InstrBody::GetGlobal(mod_idx, global_idx) => Some(
-                        this.globals
-                            .get_global(*mod_idx, *global_idx)
-                            .expect("global idx out of range")
-                            .value
-                            .clone(),
-                    ),
-                    _ => None,
+                    self.write_static_str(" }");
                 }
-            };
+            }
+            b::TypeBody::Option(item_ty) => {
+                let builder = expect_builder!(self);
+                let some_block = builder.create_block();
+                let none_block = builder.create_block();
+                let next_block = builder.create_block();
+                builder.ins().brif(value, some_block, &[], none_block, &[]);
 
-            (this, v)
-        })
+                builder.switch_to_block(some_block);
+                self.write_static_str("some(");
+                let builder = expect_builder!(self);
+                let item = builder.ins().load(
+                    get_type(item_ty, self.modules, &self.obj_module),
+                    cl::MemFlags::new(),
+                    value,
+                    0,
+                );
+                self.write_value(item_ty, item);
+                self.write_static_str(")");
+                expect_builder!(self).ins().jump(next_block, &[]);
+
+                expect_builder!(self).switch_to_block(none_block);
+                self.write_static_str("none");
+                expect_builder!(self).ins().jump(next_block, &[]);
+
+                expect_builder!(self).switch_to_block(next_block);
+            }
+            b::TypeBody::Variant(..) => {
+                let cases = ty
+                    .variant_cases(self.modules)
+                    .expect("type should be a variant type");
+
+                let builder = expect_builder!(self);
+                let next_block = builder.create_block();
+                let case =
+                    builder
+                        .ins()
+                        .load(cl::types::I8, cl::MemFlags::new(), value, 0);
+
+                for (i, (case_name, case_ty)) in enumerate(&cases) {
+                    let builder = expect_builder!(self);
+                    let case_block = builder.create_block();
+                    let else_block = builder.create_block();
+                    let is_case =
+                        builder.ins().icmp_imm(cl::IntCC::Equal, case, i as i64);
+                    builder
+                        .ins()
+                        .brif(is_case, case_block, &[], else_block, &[]);
+
+                    builder.switch_to_block(case_block);
+                    self.write_static_str(&format!("{case_name}("));
+                    let builder = expect_builder!(self);
+                    let case_value = builder.ins().load(
+                        get_type(case_ty, self.modules, &self.obj_module),
+                        cl::MemFlags::new(),
+                        value,
+                        types::variant_value_offset(
+                            case_ty,
+                            self.modules,
+                            &self.obj_module,
+                        ),
+                    );
+                    self.write_value(case_ty, case_value);
+                    self.write_static_str(")");
+                    expect_builder!(self).ins().jump(next_block, &[]);
+
+                    expect_builder!(self).switch_to_block(else_block);
+                }
+                expect_builder!(self).ins().jump(next_block, &[]);
+
+                expect_builder!(self).switch_to_block(next_block);
+            }
+            // Function values can't be created from source code, so only their type is
+            // written
+            b::TypeBody::Func(_) => {
+                self.write_static_str(&format!("<{}>", dbg::type_name(ty, self.modules)));
+            }
+            b::TypeBody::AnyNumber
+            | b::TypeBody::AnySignedNumber
+            | b::TypeBody::AnyFloat
+            | b::TypeBody::Inferred(_) => panic!("Type must be resolved before codegen"),
+            b::TypeBody::AnyOpaque => panic!("anyopaque cannot be used directly"),
+            b::TypeBody::Param(_) => panic!("Parameters must be replaced before codegen"),
+        }
+
+        expect_builder!(self).ins().return_(&[]);
     }
-    pub fn store_global(&mut self, value: RuntimeValue, global: &GlobalBinding) {
-        let types::ValueSource::Data(data_id) = &global.value.src else {
-            panic!("should never try to store a global that is a const");
-        };
+
+    /// Looks up an environment variable by the name at the top of the stack, in the
+    /// environment pointer stored by the entry point. Results in a pointer to the
+    /// null-terminated value, or a null pointer if the variable is not defined
+    fn env_var(&mut self) -> cl::Value {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let ptr_bytes = self.obj_module.isa().pointer_bytes() as i64;
+
+        let (envp_data, _) = self.globals.data_for_envp(&mut self.obj_module);
 
         let builder = expect_builder!(self);
 
-        let ty = types::get_type(&value.ty, self.modules, &self.obj_module);
-        let value = value.add_to_func(&mut self.obj_module, builder);
-        let gv = self
+        let name = self.stack.pop().add_to_func(&self.obj_module, builder);
+        let name_len = builder.ins().load(ptr_ty, cl::MemFlags::new(), name, 0);
+        let name_ptr = builder.ins().iadd_imm(name, ptr_bytes);
+
+        let envp_gv = self
             .obj_module
-            .declare_data_in_func(*data_id, &mut builder.func);
-        let ptr = builder.ins().global_value(ty, gv);
-        builder.ins().store(cl::MemFlags::new(), value, ptr, 0);
+            .declare_data_in_func(envp_data, builder.func);
+        let envp_addr = builder.ins().global_value(ptr_ty, envp_gv);
+        let envp = builder
+            .ins()
+            .load(ptr_ty, cl::MemFlags::new(), envp_addr, 0);
+
+        let loop_block = builder.create_block();
+        let item_addr = builder.append_block_param(loop_block, ptr_ty);
+        let cmp_block = builder.create_block();
+        let eq_block = builder.create_block();
+        let advance_block = builder.create_block();
+        let next_block = builder.create_block();
+        let result = builder.append_block_param(next_block, ptr_ty);
+
+        builder.ins().jump(loop_block, &[envp]);
+
+        // The environment is terminated by a null pointer
+        builder.switch_to_block(loop_block);
+        let item = builder
+            .ins()
+            .load(ptr_ty, cl::MemFlags::new(), item_addr, 0);
+        let is_end = builder.ins().icmp_imm(cl::IntCC::Equal, item, 0);
+        builder
+            .ins()
+            .brif(is_end, next_block, &[item], cmp_block, &[]);
+
+        // Each item has the format `NAME=value`
+        builder.switch_to_block(cmp_block);
+        let cmp = self
+            .call_libc("strncmp", &[item, name_ptr, name_len], Some(cl::types::I32))
+            .unwrap();
+        let builder = expect_builder!(self);
+        let same_prefix = builder.ins().icmp_imm(cl::IntCC::Equal, cmp, 0);
+        builder
+            .ins()
+            .brif(same_prefix, eq_block, &[], advance_block, &[]);
+
+        builder.switch_to_block(eq_block);
+        let eq_addr = builder.ins().iadd(item, name_len);
+        let eq_char = builder
+            .ins()
+            .load(cl::types::I8, cl::MemFlags::new(), eq_addr, 0);
+        let is_eq = builder
+            .ins()
+            .icmp_imm(cl::IntCC::Equal, eq_char, b'=' as i64);
+        let value = builder.ins().iadd_imm(eq_addr, 1);
+        builder
+            .ins()
+            .brif(is_eq, next_block, &[value], advance_block, &[]);
+
+        builder.switch_to_block(advance_block);
+        let next_item_addr = builder.ins().iadd_imm(item_addr, ptr_bytes);
+        builder.ins().jump(loop_block, &[next_item_addr]);
+
+        builder.switch_to_block(next_block);
+        result
     }
-    pub fn call(&mut self, func_id: cl::FuncId, args: &[cl::Value]) -> Option<cl::Value> {
+    /// Allocates a zero-filled heap value of the given size, with a reference count of
+    /// one. The result points right after the header with the reference count
+    pub fn alloc(&mut self, size: cl::Value) -> cl::Value {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let header_size = self.obj_module.isa().pointer_bytes() as i64;
+
         let builder = expect_builder!(self);
+        let one = builder.ins().iconst(ptr_ty, 1);
+        let total_size = builder.ins().iadd_imm(size, header_size);
+        let header = self
+            .call_libc("calloc", &[one, total_size], Some(ptr_ty))
+            .unwrap();
 
-        let func_ref = match self.declared_funcs.get(&func_id) {
-            Some(func_ref) => *func_ref,
-            None => {
-                let func_ref =
-                    self.obj_module.declare_func_in_func(func_id, builder.func);
-                self.declared_funcs.insert(func_id, func_ref);
-                func_ref
-            }
+        let builder = expect_builder!(self);
+        builder.ins().store(cl::MemFlags::new(), one, header, 0);
+        builder.ins().iadd_imm(header, header_size)
+    }
+    /// Allocates an array that owns its items, with room for `len` items of the given
+    /// size. Results in the array and a pointer to its first item
+    pub fn alloc_array(
+        &mut self,
+        len: cl::Value,
+        item_size: i64,
+    ) -> (cl::Value, cl::Value) {
+        let array_header = types::ArrayHeader::new(&self.obj_module);
+
+        let builder = expect_builder!(self);
+        let size = builder.ins().imul_imm(len, item_size);
+        let size = builder.ins().iadd_imm(size, array_header.size as i64);
+        let array = self.alloc(size);
+
+        // The owner is left null, since the array owns its items
+        let builder = expect_builder!(self);
+        let items = builder.ins().iadd_imm(array, array_header.size as i64);
+        builder
+            .ins()
+            .store(cl::MemFlags::new(), len, array, array_header.len);
+        builder
+            .ins()
+            .store(cl::MemFlags::new(), items, array, array_header.items);
+
+        (array, items)
+    }
+    /// Allocates the items in a new array in the heap, so they can outlive the function
+    /// that creates them
+    fn create_heap_array(
+        &mut self,
+        ty: &b::Type,
+        items: &[types::RuntimeValue],
+    ) -> cl::Value {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let b::TypeBody::Array(array_ty) = &ty.body else {
+            panic!("type should be an array");
         };
+        let item_size = get_type(&array_ty.item, self.modules, &self.obj_module).bytes();
 
-        let instr = builder.ins().call(func_ref, &args);
-        let results = builder.inst_results(instr);
-        assert!(results.len() <= 1);
+        // Items stored in the new array are owned by it as well
+        for item in items {
+            self.retain(item);
+        }
 
-        if results.is_empty() {
-            None
-        } else {
-            Some(results[0])
+        let builder = expect_builder!(self);
+        let len = builder.ins().iconst(ptr_ty, items.len() as i64);
+        let (array, items_ptr) = self.alloc_array(len, item_size as i64);
+
+        let builder = expect_builder!(self);
+        for (i, item) in items.iter().enumerate() {
+            let item = item.add_to_func(&self.obj_module, builder);
+            builder.ins().store(
+                cl::MemFlags::new(),
+                item,
+                items_ptr,
+                (i as u32 * item_size) as i32,
+            );
         }
+
+        array
     }
+    /// Allocates the values in the heap, so they can outlive the function that creates
+    /// them
+    fn create_heap_value(&mut self, values: &[types::RuntimeValue]) -> cl::Value {
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        // Values stored in the new value are owned by it as well
+        for value in values {
+            self.retain(value);
+        }
 
-    fn create_stack_slot(&mut self, values: &[types::RuntimeValue]) -> cl::StackSlot {
-        let Some(func) = &mut self.builder else {
-            panic!("cannot add stack slot without a function");
-        };
+        let layout = b::tuple_layout(
+            values
+                .iter()
+                .map(|v| v.native_type(self.modules, &self.obj_module).bytes() as usize),
+        );
 
-        let mut size = 0;
+        let builder = expect_builder!(self);
         let values = values
             .iter()
-            .map(|v| {
-                let offset = size;
-                size += v.native_type(self.modules, &self.obj_module).bytes();
-                (offset, v.add_to_func(&self.obj_module, func))
-            })
+            .map(|v| v.add_to_func(&self.obj_module, builder))
             .collect_vec();
 
-        let ss_data = cl::StackSlotData::new(cl::StackSlotKind::ExplicitSlot, size);
-        let ss = func.create_sized_stack_slot(ss_data);
-        for (offset, value) in values {
-            func.ins().stack_store(value, ss, offset as i32);
+        let size_value = builder.ins().iconst(ptr_ty, layout.size as i64);
+        let ptr = self.alloc(size_value);
+
+        let builder = expect_builder!(self);
+        for (offset, value) in izip!(layout.offsets, values) {
+            builder
+                .ins()
+                .store(cl::MemFlags::new(), value, ptr, offset as i32);
         }
 
-        ss
+        ptr
+    }
+    /// Registers a heap value as owned by the current scope
+    fn own(&mut self, value: &types::RuntimeValue<'a>) {
+        if rc::is_managed(&value.ty) && matches!(value.src, types::ValueSource::Value(_))
+        {
+            self.stack.get_scope_mut().payload.owned.push(value.clone());
+        }
+    }
+    fn retain(&mut self, value: &types::RuntimeValue) {
+        // Static values are never freed, so there's no need to count their references
+        let types::ValueSource::Value(v) = value.src else {
+            return;
+        };
+        if !rc::is_managed(&value.ty) {
+            return;
+        }
+        let func_id = self.rc.retain_func(&mut self.obj_module);
+        self.call(func_id, &[v]);
+    }
+    fn release(&mut self, value: &types::RuntimeValue) {
+        let types::ValueSource::Value(v) = value.src else {
+            return;
+        };
+        if !rc::is_managed(&value.ty) {
+            return;
+        }
+        let func_id = self.rc.release_func(&value.ty, &mut self.obj_module);
+        self.call(func_id, &[v]);
+    }
+    /// Releases the values owned by a scope that is ending. The ownership of the results
+    /// is passed to whoever receives them, so they are retained if the scope doesn't own
+    /// them already
+    fn release_scope(
+        &mut self,
+        results: &[types::RuntimeValue<'a>],
+        mut owned: Vec<types::RuntimeValue<'a>>,
+    ) {
+        for result in results {
+            if let Some(i) = owned.iter().position(|v| v.src == result.src) {
+                owned.remove(i);
+            } else {
+                self.retain(result);
+            }
+        }
+        for value in &owned {
+            self.release(value);
+        }
     }
     fn push_bin_op(
         &mut self,
@@ -660,9 +2447,62 @@ impl<'a, M: cl::Module> FuncCodegen<'a, '_, M> {
             value.into(),
         ));
     }
-    fn add_assert(&mut self, cond: cl::Value, code: cl::TrapCode) {
+    /// Exits the program with the message and the location of the instruction when the
+    /// condition is false
+    fn add_assert(&mut self, cond: cl::Value, message: &str, loc: &b::Loc) {
+        let builder = expect_builder!(self);
+        let fail_block = builder.create_block();
+        let next_block = builder.create_block();
+        builder.set_cold_block(fail_block);
+        builder.ins().brif(cond, next_block, &[], fail_block, &[]);
+
+        builder.switch_to_block(fail_block);
+        self.write_static_str(&format!("{}: {message}\n", self.loc_text(loc)));
+        self.exit_panicking();
+
+        expect_builder!(self).switch_to_block(next_block);
+    }
+    /// Exits the program with `PANIC_EXIT_CODE`, so the current block ends here
+    fn exit_panicking(&mut self) {
+        let code = expect_builder!(self)
+            .ins()
+            .iconst(cl::types::I32, PANIC_EXIT_CODE);
+        self.call_libc("exit", &[code], None);
+        expect_builder!(self)
+            .ins()
+            .trap(cl::TrapCode::UnreachableCodeReached);
+    }
+    /// Division of integers by zero, and of the smallest signed integer by -1, whose
+    /// result doesn't fit in its type, are checked before the operation, instead of
+    /// trapping
+    fn check_divisor(&mut self, loc: &b::Loc) {
+        let divisor = self.stack.get(0).unwrap().clone();
+        let dividend = self.stack.get(1).unwrap().clone();
+        if !divisor.ty.is_int() {
+            return;
+        }
+        let builder = expect_builder!(self);
+        let divisor_value = divisor.add_to_func(&self.obj_module, builder);
+        let cond = builder
+            .ins()
+            .icmp_imm(cl::IntCC::NotEqual, divisor_value, 0);
+        self.add_assert(cond, "division by zero", loc);
+
+        if !divisor.ty.is_sint() {
+            return;
+        }
         let builder = expect_builder!(self);
-        builder.ins().trapz(cond, code);
+        let dividend_value = dividend.add_to_func(&self.obj_module, builder);
+        let bits = builder.func.dfg.value_type(dividend_value).bits();
+        let min = i64::MIN >> (64 - bits);
+        let not_min = builder
+            .ins()
+            .icmp_imm(cl::IntCC::NotEqual, dividend_value, min);
+        let not_neg_one = builder
+            .ins()
+            .icmp_imm(cl::IntCC::NotEqual, divisor_value, -1);
+        let cond = builder.ins().bor(not_min, not_neg_one);
+        self.add_assert(cond, "division overflow", loc);
     }
 }
 
@@ -673,4 +2513,6 @@ pub struct ScopePayload<'a> {
     pub next_block: Option<cl::Block>,
     pub branches: Vec<cl::Block>,
     pub ty: Option<Cow<'a, b::Type>>,
+    /// Heap values created in this scope, which should be released when it ends
+    pub owned: Vec<types::RuntimeValue<'a>>,
 }
@@ -3,9 +3,11 @@ use std::collections::HashMap;
 
 use cranelift_shim::{self as cl};
 use derive_new::new;
-use itertools::repeat_n;
+use itertools::{izip, repeat_n};
 
+use super::dbg::DbgFuncs;
 use super::func::FuncCodegen;
+use super::rc::RcFuncs;
 use super::types;
 use crate::{bytecode as b, utils};
 
@@ -28,7 +30,13 @@ pub struct Globals<'a> {
     #[new(default)]
     tuples: HashMap<Vec<types::RuntimeValue<'a>>, cl::DataId>,
     #[new(default)]
+    arrays: HashMap<Vec<types::RuntimeValue<'a>>, cl::DataId>,
+    #[new(default)]
+    bytes: HashMap<Vec<u8>, cl::DataId>,
+    #[new(default)]
     pub globals: HashMap<(usize, usize), GlobalBinding<'a>>,
+    #[new(default)]
+    envp: Option<cl::DataId>,
 }
 impl<'a> Globals<'a> {
     pub fn get_global(&self, mod_idx: usize, idx: usize) -> Option<&GlobalBinding<'a>> {
@@ -47,8 +55,15 @@ impl<'a> Globals<'a> {
         let symbol_name = format!("$global_{mod_idx}_{idx}");
 
         let (value, is_const, module) = utils::replace_with(self, |s| {
-            let mut codegen =
-                FuncCodegen::new(self.modules, None, obj_module, s, HashMap::new());
+            let mut codegen = FuncCodegen::new(
+                self.modules,
+                None,
+                obj_module,
+                s,
+                HashMap::new(),
+                RcFuncs::default(),
+                DbgFuncs::default(),
+            );
 
             for instr in &global.body {
                 if let Some(value) = codegen.value_from_instr(instr) {
@@ -97,7 +112,8 @@ impl<'a> Globals<'a> {
         let data_id = obj_module.declare_anonymous_data(false, false).unwrap();
         let mut desc = cl::DataDescription::new();
 
-        let mut bytes = vec![];
+        // Static values have a reference count of zero, so they are never freed
+        let mut bytes = vec![0u8; obj_module.isa().pointer_bytes() as usize];
 
         match obj_module.isa().pointer_bytes() {
             1 => types::ValueSource::I8(value.len() as u8),
@@ -121,28 +137,6 @@ impl<'a> Globals<'a> {
         (data_id, obj_module)
     }
 
-    pub fn data_for_array<M: cl::Module>(
-        &mut self,
-        mut values: Vec<types::RuntimeValue<'a>>,
-        obj_module: M,
-    ) -> (Option<cl::DataId>, M) {
-        let len = match obj_module.isa().pointer_bytes() {
-            1 => types::ValueSource::I8(values.len() as u8),
-            2 => types::ValueSource::I16(values.len() as u16),
-            4 => types::ValueSource::I32(values.len() as u32),
-            8 => types::ValueSource::I64(values.len() as u64),
-            _ => panic!("how many bytes?"),
-        };
-        values.insert(
-            0,
-            types::RuntimeValue::new(
-                Cow::Owned(b::Type::new(b::TypeBody::USize, None)),
-                len,
-            ),
-        );
-        self.data_for_tuple(values, obj_module)
-    }
-
     pub fn data_for_tuple<M: cl::Module>(
         &mut self,
         values: Vec<types::RuntimeValue<'a>>,
@@ -155,24 +149,12 @@ impl<'a> Globals<'a> {
         let data_id = obj_module.declare_anonymous_data(false, false).unwrap();
         let mut desc = cl::DataDescription::new();
 
-        let mut bytes = vec![];
-        let mut included_datas = HashMap::new();
-
-        for item in &values {
-            if let types::ValueSource::Data(field_data_id) = item.src {
-                let offset = bytes.len();
-                bytes.extend(repeat_n(0u8, obj_module.isa().pointer_bytes() as usize));
-
-                let field_gv = included_datas.entry(field_data_id).or_insert_with(|| {
-                    obj_module.declare_data_in_data(field_data_id, &mut desc)
-                });
-                desc.write_data_addr(offset as u32, field_gv.clone(), 0);
-            } else {
-                if let Err(()) = item.serialize(&mut bytes, obj_module.isa().endianness())
-                {
-                    return (None, obj_module);
-                }
-            }
+        let header_size = obj_module.isa().pointer_bytes();
+        let mut bytes = vec![0u8; header_size as usize];
+
+        if let Err(()) = serialize_values(&values, &mut bytes, &mut desc, &mut obj_module)
+        {
+            return (None, obj_module);
         }
 
         desc.define(bytes.into());
@@ -183,6 +165,75 @@ impl<'a> Globals<'a> {
         (Some(data_id), obj_module)
     }
 
+    /// Static array that owns its items, so they are stored right after the array header
+    pub fn data_for_array<M: cl::Module>(
+        &mut self,
+        items: Vec<types::RuntimeValue<'a>>,
+        mut obj_module: M,
+    ) -> (Option<cl::DataId>, M) {
+        if let Some(id) = self.arrays.get(&items) {
+            return (Some(*id), obj_module);
+        }
+
+        let data_id = obj_module.declare_anonymous_data(false, false).unwrap();
+        let mut desc = cl::DataDescription::new();
+        let mut bytes =
+            array_header_bytes(items.len(), data_id, &mut desc, &mut obj_module);
+
+        if let Err(()) = serialize_values(&items, &mut bytes, &mut desc, &mut obj_module)
+        {
+            return (None, obj_module);
+        }
+
+        desc.define(bytes.into());
+        obj_module.define_data(data_id, &desc).unwrap();
+
+        self.data.insert(data_id, desc);
+        self.arrays.insert(items, data_id);
+        (Some(data_id), obj_module)
+    }
+
+    /// Static array of bytes, stored like the other static arrays
+    pub fn data_for_bytes<M: cl::Module>(
+        &mut self,
+        value: &[u8],
+        mut obj_module: M,
+    ) -> (cl::DataId, M) {
+        if let Some(id) = self.bytes.get(value) {
+            return (*id, obj_module);
+        }
+
+        let data_id = obj_module.declare_anonymous_data(false, false).unwrap();
+        let mut desc = cl::DataDescription::new();
+        let mut bytes =
+            array_header_bytes(value.len(), data_id, &mut desc, &mut obj_module);
+        bytes.extend(value);
+
+        desc.define(bytes.into());
+        obj_module.define_data(data_id, &desc).unwrap();
+
+        self.data.insert(data_id, desc);
+        self.bytes.insert(value.to_vec(), data_id);
+        (data_id, obj_module)
+    }
+
+    /// Data where the environment pointer received by the entry point is stored
+    pub fn data_for_envp<M: cl::Module>(&mut self, obj_module: M) -> (cl::DataId, M) {
+        if let Some(id) = self.envp {
+            return (id, obj_module);
+        }
+
+        let (data_id, obj_module) = self.create_writable_for_type(
+            &b::Type::new(
+                b::TypeBody::Ptr(b::Type::new(b::TypeBody::AnyOpaque, None).into()),
+                None,
+            ),
+            obj_module,
+        );
+        self.envp = Some(data_id);
+        (data_id, obj_module)
+    }
+
     pub fn create_writable_for_type<M: cl::Module>(
         &mut self,
         ty: &b::Type,
@@ -197,3 +248,78 @@ impl<'a> Globals<'a> {
         (data_id, obj_module)
     }
 }
+
+/// Bytes of a static array up to its items, which are stored right after the header. The
+/// owner is left null, since the array owns its items
+fn array_header_bytes(
+    len: usize,
+    data_id: cl::DataId,
+    desc: &mut cl::DataDescription,
+    obj_module: &mut impl cl::Module,
+) -> Vec<u8> {
+    // Static values have a reference count of zero, so they are never freed
+    let ptr_bytes = obj_module.isa().pointer_bytes() as usize;
+    let mut bytes = vec![0u8; ptr_bytes];
+
+    match ptr_bytes {
+        1 => types::ValueSource::I8(len as u8),
+        2 => types::ValueSource::I16(len as u16),
+        4 => types::ValueSource::I32(len as u32),
+        8 => types::ValueSource::I64(len as u64),
+        _ => panic!("how many bytes?"),
+    }
+    .serialize(&mut bytes, obj_module.isa().endianness())
+    .unwrap();
+
+    let array_header = types::ArrayHeader::new(obj_module);
+    let items_offset = ptr_bytes + array_header.size as usize;
+    bytes.resize(items_offset, 0);
+    let self_gv = obj_module.declare_data_in_data(data_id, desc);
+    desc.write_data_addr(
+        (ptr_bytes + array_header.items as usize) as u32,
+        self_gv,
+        items_offset as i64,
+    );
+    bytes
+}
+
+/// Appends the values to the bytes of a static data, with the same layout as values
+/// created at runtime. Values that are static data as well are stored as pointers to
+/// them. Fails if any value is only known at runtime
+fn serialize_values(
+    values: &[types::RuntimeValue],
+    bytes: &mut Vec<u8>,
+    desc: &mut cl::DataDescription,
+    obj_module: &mut impl cl::Module,
+) -> Result<(), ()> {
+    let header_size = obj_module.isa().pointer_bytes();
+    let mut included_datas = HashMap::new();
+
+    let mut items_bytes = vec![];
+    for item in values {
+        let mut item_bytes = vec![];
+        if let types::ValueSource::Data(_) = item.src {
+            item_bytes.extend(repeat_n(0u8, obj_module.isa().pointer_bytes() as usize));
+        } else {
+            item.serialize(&mut item_bytes, obj_module.isa().endianness())?;
+        }
+        items_bytes.push(item_bytes);
+    }
+
+    let start = bytes.len();
+    let layout = b::tuple_layout(items_bytes.iter().map(|item_bytes| item_bytes.len()));
+    bytes.resize(start + layout.size, 0);
+    for (item, item_bytes, offset) in izip!(values, items_bytes, layout.offsets) {
+        let offset = start + offset;
+        bytes[offset..offset + item_bytes.len()].copy_from_slice(&item_bytes);
+
+        if let types::ValueSource::Data(field_data_id) = item.src {
+            let field_gv = included_datas
+                .entry(field_data_id)
+                .or_insert_with(|| obj_module.declare_data_in_data(field_data_id, desc));
+            desc.write_data_addr(offset as u32, *field_gv, header_size as i64);
+        }
+    }
+
+    Ok(())
+}
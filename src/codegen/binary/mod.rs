@@ -1,5 +1,8 @@
+mod closure;
+mod dbg;
 mod func;
 mod globals;
+mod rc;
 mod types;
 
 use std::collections::HashMap;
@@ -8,17 +11,22 @@ use std::fs::File;
 use std::io::BufWriter;
 use std::path::Path;
 
+use cranelift_shim::settings::Configurable;
 use cranelift_shim::{self as cl, InstBuilder, Module};
-use itertools::Itertools;
+use itertools::{enumerate, Itertools};
 use target_lexicon::Triple;
 
+use self::closure::ClosureFuncs;
+use self::dbg::DbgFuncs;
 use self::func::FuncCodegen;
 use self::globals::Globals;
+use self::rc::RcFuncs;
 use crate::{bytecode as b, config, utils};
 
 utils::number_enum!(pub FuncNS: u32 {
     User = 0,
     SystemFunc = 1,
+    Runtime = 2,
 });
 
 utils::number_enum!(pub SystemFunc: u32 {
@@ -40,14 +48,23 @@ pub struct BinaryCodegen<'a> {
     module_ctx: cl::Context,
     globals: Globals<'a>,
     funcs: HashMap<(usize, usize), FuncBinding>,
+    rc: RcFuncs,
+    dbg: DbgFuncs,
+    closures: ClosureFuncs,
     declared_funcs: HashMap<(usize, usize), cl::Function>,
+    globals_order: Vec<(usize, usize)>,
     next_func_id: u32,
 }
 impl<'a> BinaryCodegen<'a> {
     pub fn new(modules: &'a [b::Module], cfg: &'a config::BuildConfig) -> Self {
         let triple = Triple::host();
 
-        let settings_builder = cl::settings::builder();
+        let mut settings_builder = cl::settings::builder();
+        // Required by Cranelift to emit tail calls
+        settings_builder.enable("preserve_frame_pointers").unwrap();
+        if cfg.release {
+            settings_builder.set("opt_level", "speed").unwrap();
+        }
         let flags = cl::settings::Flags::new(settings_builder);
         let isa_target = cl::isa::lookup(triple).unwrap().finish(flags).unwrap();
 
@@ -65,18 +82,29 @@ impl<'a> BinaryCodegen<'a> {
             module_ctx,
             globals: Globals::new(modules),
             funcs: HashMap::new(),
+            rc: RcFuncs::default(),
+            dbg: DbgFuncs::default(),
+            closures: ClosureFuncs::default(),
             declared_funcs: HashMap::new(),
+            globals_order: vec![],
             next_func_id: 0,
         }
     }
 }
 impl BinaryCodegen<'_> {
     pub fn write(mut self) {
-        for mod_idx in 0..self.modules.len() {
-            for idx in 0..self.modules[mod_idx].globals.len() {
-                self.declare_global(mod_idx, idx);
-            }
+        // Globals are declared and initialized after the ones they depend on, which can
+        // always be ordered since cycles are reported by the parser
+        let globals = enumerate(self.modules).flat_map(|(mod_idx, module)| {
+            (0..module.globals.len()).map(move |idx| (mod_idx, idx))
+        });
+        self.globals_order = b::sort_globals(self.modules, globals)
+            .expect("globals should not depend on each other");
+        for (mod_idx, idx) in self.globals_order.clone() {
+            self.declare_global(mod_idx, idx);
+        }
 
+        for mod_idx in 0..self.modules.len() {
             for idx in 0..self.modules[mod_idx].funcs.len() {
                 self.declare_function(mod_idx, idx);
             }
@@ -106,15 +134,29 @@ impl BinaryCodegen<'_> {
             .declare_function("exit", cl::Linkage::Import, &exit_func.signature)
             .unwrap();
 
+        // The entry is the C `main` function, called by libc's startup code, so we have
+        // access to the command-line arguments and the environment
+        let ptr_ty = self.obj_module.isa().pointer_type();
+        let mut sig = self.obj_module.make_signature();
+        sig.params.push(cl::AbiParam::new(cl::types::I32));
+        sig.params.push(cl::AbiParam::new(ptr_ty));
+        sig.params.push(cl::AbiParam::new(ptr_ty));
+        sig.returns.push(cl::AbiParam::new(cl::types::I32));
         let mut func = cl::Function::with_name_signature(
             cl::UserFuncName::user(FuncNS::SystemFunc.into(), SystemFunc::Start.into()),
-            self.obj_module.make_signature(),
+            sig,
         );
         let func_id = self
             .obj_module
-            .declare_function("_start", cl::Linkage::Export, &func.signature)
+            .declare_function("main", cl::Linkage::Export, &func.signature)
             .unwrap();
 
+        let modules = self.modules;
+        let entry_func = enumerate(modules).find_map(|(i, module)| {
+            let j = module.funcs.iter().position(|func| func.is_entry_point)?;
+            Some((i, j))
+        });
+
         utils::replace_with(self, |mut this| {
             let mut func_ctx = cl::FunctionBuilderContext::new();
             let func_builder = cl::FunctionBuilder::new(&mut func, &mut func_ctx);
@@ -124,12 +166,36 @@ impl BinaryCodegen<'_> {
                 this.obj_module,
                 this.globals,
                 this.funcs,
+                this.rc,
+                this.dbg,
             );
-            codegen.create_initial_block(&[]);
+            codegen.closures = this.closures;
+            codegen.create_initial_block(&[], false);
+
+            let (argc, argv, envp) = {
+                let builder = codegen.builder.as_ref().unwrap();
+                let block = builder.current_block().unwrap();
+                let &[argc, argv, envp] = builder.block_params(block) else {
+                    unreachable!()
+                };
+                (argc, argv, envp)
+            };
+
+            let (envp_data, module) = codegen.globals.data_for_envp(codegen.obj_module);
+            codegen.obj_module = module;
+            {
+                let builder = codegen.builder.as_mut().unwrap();
+                let gv = codegen
+                    .obj_module
+                    .declare_data_in_func(envp_data, builder.func);
+                let ptr = builder.ins().global_value(ptr_ty, gv);
+                builder.ins().store(cl::MemFlags::new(), envp, ptr, 0);
+            }
 
             let mut entry_point = None;
 
-            for ((i, j), global) in codegen.globals.globals.clone() {
+            for (i, j) in this.globals_order.clone() {
+                let global = codegen.globals.get_global(i, j).unwrap().clone();
                 if global.is_entry_point {
                     entry_point = Some((i, j));
                     continue;
@@ -137,31 +203,59 @@ impl BinaryCodegen<'_> {
                 if global.is_const {
                     continue;
                 };
-                for instr in &self.modules[i].globals[j].body {
+                for instr in &modules[i].globals[j].body {
                     codegen.add_instr(instr);
                 }
                 let res = codegen.stack.pop();
                 codegen.store_global(res, &global);
             }
 
-            let entry_point = entry_point.expect("entrypoint should be defined");
-            for instr in &self.modules[entry_point.0].globals[entry_point.1].body {
-                codegen.add_instr(instr);
-            }
-            let exit_code = codegen
-                .builder
-                .as_mut()
-                .unwrap()
-                .ins()
-                .iconst(cl::types::I32, 0);
+            let (result, result_ty) = if let Some((i, j)) = entry_func {
+                let func = &modules[i].funcs[j];
+                let mut args = vec![];
+                if !func.params.is_empty() {
+                    args.push(codegen.array_from_c_strs(argc, argv));
+                }
+                let func_id = codegen.funcs.get(&(i, j)).unwrap().func_id;
+                let result = codegen.call(func_id, &args).unwrap();
+                (result, &func.ret)
+            } else {
+                let (i, j) = entry_point.expect("entrypoint should be defined");
+                let global = &modules[i].globals[j];
+                for instr in &global.body {
+                    codegen.add_instr(instr);
+                }
+                let builder = codegen.builder.as_mut().unwrap();
+                let result = codegen
+                    .stack
+                    .pop()
+                    .add_to_func(&codegen.obj_module, builder);
+                (result, &global.ty)
+            };
+
+            let builder = codegen.builder.as_mut().unwrap();
+            let exit_code = match &result_ty.body {
+                b::TypeBody::I32 => result,
+                // The value of a `bool` entry point doesn't mean anything, so it
+                // always exits successfully
+                b::TypeBody::Bool => builder.ins().iconst(cl::types::I32, 0),
+                _ => panic!("entry point should be bool or i32"),
+            };
             codegen.call(exit_func_id, &[exit_code]);
 
-            (this.obj_module, this.globals, this.funcs) = codegen.return_never();
+            (
+                this.obj_module,
+                this.globals,
+                this.funcs,
+                this.rc,
+                this.dbg,
+                this.closures,
+            ) = codegen.return_never();
             this
         });
 
         if self.cfg.dump_clif {
-            println!("<_start> {func}");
+            println!("<main> {func}");
         }
 
         self.module_ctx.func = func;
@@ -187,6 +281,13 @@ impl BinaryCodegen<'_> {
             &self.obj_module,
         )));
 
+        // Extern functions are called by other languages, so they keep the system calling
+        // convention. The others use the tail calling convention, so calls in tail
+        // position don't grow the stack
+        if decl.extn.is_none() {
+            sig.call_conv = cl::isa::CallConv::Tail;
+        }
+
         let user_func_name =
             cl::UserFuncName::user(FuncNS::User.into(), self.next_func_id);
         self.next_func_id += 1;
@@ -241,19 +342,135 @@ impl BinaryCodegen<'_> {
                 this.obj_module,
                 this.globals,
                 this.funcs,
+                this.rc,
+                this.dbg,
             );
-            codegen.create_initial_block(&decl.params);
+            codegen.closures = this.closures;
+            codegen.create_initial_block(&decl.params, decl.extn.is_none());
 
             for instr in &decl.body {
                 codegen.add_instr(instr);
             }
 
-            (this.obj_module, this.globals, this.funcs) = codegen.return_value();
+            (
+                this.obj_module,
+                this.globals,
+                this.funcs,
+                this.rc,
+                this.dbg,
+                this.closures,
+            ) = codegen.return_value();
             this
         })
     }
+    /// Builds the functions used by the runtime, which are declared as they are needed by
+    /// the rest of the program
+    fn build_runtime_funcs(&mut self) {
+        // Function values release their bound values, so their functions are built
+        // before the release functions
+        loop {
+            let pending = self.closures.take_pending();
+            if pending.is_empty() {
+                break;
+            }
+            for (key, call_id, drop_id) in pending {
+                let decl = self.obj_module.declarations().get_function_decl(call_id);
+                let sig = decl.signature.clone();
+                self.build_runtime_func(call_id, sig, |codegen| {
+                    codegen.build_closure_call(&key)
+                });
+                let sig = rc::rc_signature(&self.obj_module);
+                self.build_runtime_func(drop_id, sig, |codegen| {
+                    codegen.build_closure_drop(&key)
+                });
+            }
+        }
+
+        loop {
+            let pending = self.dbg.take_pending();
+            if pending.is_empty() {
+                break;
+            }
+            for (ty, func_id) in pending {
+                let sig = dbg::write_signature(&ty, self.modules, &self.obj_module);
+                self.build_runtime_func(func_id, sig, |codegen| {
+                    codegen.build_dbg_write(&ty)
+                });
+            }
+        }
+
+        loop {
+            let pending = self.rc.take_pending();
+            if pending.is_empty() {
+                break;
+            }
+            for (ty, func_id) in pending {
+                let sig = rc::rc_signature(&self.obj_module);
+                self.build_runtime_func(func_id, sig, |codegen| {
+                    codegen.build_release(&ty)
+                });
+            }
+        }
+
+        if let Some(func_id) = self.rc.declared_retain() {
+            let sig = rc::rc_signature(&self.obj_module);
+            self.build_runtime_func(func_id, sig, |codegen| codegen.build_retain());
+        }
+    }
+    fn build_runtime_func(
+        &mut self,
+        func_id: cl::FuncId,
+        sig: cl::Signature,
+        f: impl FnOnce(&mut FuncCodegen<&mut cl::ObjectModule>),
+    ) {
+        let mut func = cl::Function::with_name_signature(
+            cl::UserFuncName::user(FuncNS::Runtime.into(), func_id.as_u32()),
+            sig,
+        );
+
+        utils::replace_with(self, |mut this| {
+            let mut func_ctx = cl::FunctionBuilderContext::new();
+            let func_builder = cl::FunctionBuilder::new(&mut func, &mut func_ctx);
+            let mut codegen = FuncCodegen::new(
+                this.modules,
+                Some(func_builder),
+                &mut this.obj_module,
+                this.globals,
+                this.funcs,
+                this.rc,
+                this.dbg,
+            );
+            codegen.closures = this.closures;
+            f(&mut codegen);
+            (
+                _,
+                this.globals,
+                this.funcs,
+                this.rc,
+                this.dbg,
+                this.closures,
+            ) = codegen.finish();
+            this
+        });
+
+        if self.cfg.dump_clif {
+            let name = &self
+                .obj_module
+                .declarations()
+                .get_function_decl(func_id)
+                .name;
+            println!("<{}> {func}", name.as_deref().unwrap_or_default());
+        }
+
+        self.module_ctx.func = func;
+        self.obj_module
+            .define_function(func_id, &mut self.module_ctx)
+            .unwrap();
+        self.obj_module.clear_context(&mut self.module_ctx)
+    }
     fn write_to_file(mut self) {
         self.build_entry();
+        self.build_runtime_funcs();
 
         if self.globals.data.len() > 0 && self.cfg.dump_clif {
             for (data_id, desc) in &self.globals.data {
@@ -316,14 +533,30 @@ impl BinaryCodegen<'_> {
         .find(|path| Path::new(path).is_file())
         .expect("libc.a not found");
 
+        let crt_dir = [
+            "/usr/lib64",
+            "/usr/lib/x86_64-linux-gnu",
+            "/usr/lib/aarch64-linux-gnu",
+            "/usr/lib",
+            "/lib64",
+            "/lib",
+        ]
+        .into_iter()
+        .map(Path::new)
+        .find(|path| path.join("crt1.o").is_file())
+        .expect("crt1.o not found");
+
         // TODO: windows support
         let status = std::process::Command::new("ld")
             .arg("-dynamic-linker")
             .arg(dyn_linker)
             .arg("-o")
             .arg(&self.cfg.out)
+            .arg(crt_dir.join("crt1.o"))
+            .arg(crt_dir.join("crti.o"))
             .arg(&obj_path)
             .arg("-lc")
+            .arg(crt_dir.join("crtn.o"))
             .status()
             .expect("failed to link object file");
 
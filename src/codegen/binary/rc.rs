@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::mem;
+
+use cranelift_shim::{self as cl};
+
+use crate::bytecode as b;
+
+/// Runtime functions that manage the reference count of heap values. Every string, array,
+/// record, variant value, function value and option with a value is preceded by a header
+/// with its reference count. Static values have a count of zero, which means they are
+/// never freed, and options without a value are null, which means there's nothing to
+/// count
+#[derive(Debug, Default)]
+pub struct RcFuncs {
+    retain: Option<cl::FuncId>,
+    releases: HashMap<b::Type, cl::FuncId>,
+    pending: Vec<(b::Type, cl::FuncId)>,
+}
+impl RcFuncs {
+    pub fn retain_func<M: cl::Module>(&mut self, obj_module: &mut M) -> cl::FuncId {
+        if let Some(func_id) = self.retain {
+            return func_id;
+        }
+
+        let func_id = obj_module
+            .declare_function("$rc_retain", cl::Linkage::Local, &rc_signature(obj_module))
+            .unwrap();
+        self.retain = Some(func_id);
+        func_id
+    }
+
+    /// Function that decrements the reference count of a value of the given type, freeing
+    /// it and releasing its items when it reaches zero. The function is only declared
+    /// here, it will be built later by the codegen
+    pub fn release_func<M: cl::Module>(
+        &mut self,
+        ty: &b::Type,
+        obj_module: &mut M,
+    ) -> cl::FuncId {
+        let ty = layout_type(ty);
+        if let Some(func_id) = self.releases.get(&ty) {
+            return *func_id;
+        }
+
+        let func_id = obj_module
+            .declare_function(
+                &format!("$rc_release_{}", self.releases.len()),
+                cl::Linkage::Local,
+                &rc_signature(obj_module),
+            )
+            .unwrap();
+        self.releases.insert(ty.clone(), func_id);
+        self.pending.push((ty, func_id));
+        func_id
+    }
+
+    pub fn declared_retain(&self) -> Option<cl::FuncId> {
+        self.retain
+    }
+
+    pub fn take_pending(&mut self) -> Vec<(b::Type, cl::FuncId)> {
+        mem::take(&mut self.pending)
+    }
+}
+
+pub fn rc_signature(obj_module: &impl cl::Module) -> cl::Signature {
+    let mut sig = obj_module.make_signature();
+    sig.params
+        .push(cl::AbiParam::new(obj_module.isa().pointer_type()));
+    sig
+}
+
+/// Whether values of the type live in the heap and are reference counted
+pub fn is_managed(ty: &b::Type) -> bool {
+    matches!(
+        &ty.body,
+        b::TypeBody::String(_)
+            | b::TypeBody::Array(_)
+            | b::TypeBody::Option(_)
+            | b::TypeBody::TypeRef(..)
+            | b::TypeBody::Variant(..)
+            | b::TypeBody::Func(_)
+    )
+}
+
+/// Strings and arrays have the same layout regardless of their length, so they can share
+/// the same release function
+pub fn layout_type(ty: &b::Type) -> b::Type {
+    let body = match &ty.body {
+        b::TypeBody::String(_) => b::TypeBody::String(b::StringType::new(None)),
+        b::TypeBody::Array(a) => {
+            b::TypeBody::Array(b::ArrayType::new(a.item.clone(), None))
+        }
+        body => body.clone(),
+    };
+    b::Type::new(body, None)
+}
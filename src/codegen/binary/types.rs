@@ -45,12 +45,12 @@ impl RuntimeValue<'_> {
             ValueSource::F64(n) => func.ins().f64const(n.to_float()),
             ValueSource::Data(data_id) => {
                 let field_gv = obj_module.declare_data_in_func(data_id, &mut func.func);
+                let ptr = func
+                    .ins()
+                    .global_value(obj_module.isa().pointer_type(), field_gv);
+                // Skip the header with the reference count
                 func.ins()
-                    .global_value(obj_module.isa().pointer_type(), field_gv.clone())
-            }
-            ValueSource::StackSlot(ss) => {
-                func.ins()
-                    .stack_addr(obj_module.isa().pointer_type(), ss, 0)
+                    .iadd_imm(ptr, obj_module.isa().pointer_bytes() as i64)
             }
         }
     }
@@ -65,8 +65,9 @@ pub enum ValueSource {
     F32(F32Bits),
     F64(F64Bits),
     Value(cl::Value),
+    /// Static value, which is preceded by a reference count of zero, just like heap
+    /// values are preceded by their reference count
     Data(cl::DataId),
-    StackSlot(cl::StackSlot),
 }
 impl ValueSource {
     pub fn serialize(
@@ -90,9 +91,7 @@ impl ValueSource {
             ValueSource::I64(n) => serialize_number!(n),
             ValueSource::F32(n) => serialize_number!(n.to_float()),
             ValueSource::F64(n) => serialize_number!(n.to_float()),
-            ValueSource::Value(_) | ValueSource::Data(_) | ValueSource::StackSlot(_) => {
-                return Err(())
-            }
+            ValueSource::Value(_) | ValueSource::Data(_) => return Err(()),
         }
 
         Ok(())
@@ -144,11 +143,47 @@ pub fn tuple_from_record<'a>(
                         .clone()
                 })
                 .collect(),
+            b::TypeDefBody::Variant(_) => panic!("type is not a record type"),
         },
         _ => panic!("type is not a record type"),
     }
 }
 
+/// Arrays and slices are a single pointer to a header with their length, a pointer to
+/// their first item and the array that owns the items, since every runtime value is a
+/// single native value. Arrays that own their items have a null owner and store the
+/// items right after the header. Slices point to the items of another array, and keep
+/// it alive by holding a reference to it, so each slice allocates its own header
+#[derive(Debug, Clone, Copy)]
+pub struct ArrayHeader {
+    pub len: i32,
+    pub items: i32,
+    pub owner: i32,
+    pub size: i32,
+}
+impl ArrayHeader {
+    pub fn new(obj_module: &impl cl::Module) -> Self {
+        let ptr = obj_module.isa().pointer_bytes() as i32;
+        ArrayHeader {
+            len: 0,
+            items: ptr,
+            owner: 2 * ptr,
+            size: 3 * ptr,
+        }
+    }
+}
+
+/// Variants store the index of their case as a `u8`, followed by the value of the case.
+/// Returns the offset of the value, which depends on its alignment
+pub fn variant_value_offset(
+    value_ty: &b::Type,
+    modules: &[b::Module],
+    obj_module: &impl cl::Module,
+) -> i32 {
+    let value_size = get_type(value_ty, modules, obj_module).bytes() as usize;
+    b::tuple_layout([1, value_size]).offsets[1] as i32
+}
+
 pub fn get_type(
     ty: &b::Type,
     modules: &[b::Module],
@@ -169,15 +204,20 @@ pub fn get_type(
         b::TypeBody::USize
         | b::TypeBody::String(_)
         | b::TypeBody::Array(_)
-        | b::TypeBody::Ptr(_) => obj_module.isa().pointer_type(),
+        | b::TypeBody::Ptr(_)
+        | b::TypeBody::Option(_)
+        | b::TypeBody::Variant(..)
+        | b::TypeBody::Func(_) => obj_module.isa().pointer_type(),
         b::TypeBody::TypeRef(i, j) => match &modules[*i].typedefs[*j].body {
             b::TypeDefBody::Record(_) => obj_module.isa().pointer_type(),
+            b::TypeDefBody::Variant(_) => unreachable!("variant types are not type refs"),
         },
         b::TypeBody::AnyNumber
         | b::TypeBody::AnySignedNumber
         | b::TypeBody::AnyFloat
         | b::TypeBody::Inferred(_) => panic!("Type must be resolved before codegen"),
         b::TypeBody::AnyOpaque => panic!("anyopaque cannot be used directly"),
+        b::TypeBody::Param(_) => panic!("Parameters must be replaced before codegen"),
     }
 }
 
@@ -186,37 +226,5 @@ pub fn get_size(
     modules: &[b::Module],
     obj_module: &impl cl::Module,
 ) -> usize {
-    let ptr = obj_module.isa().pointer_bytes() as usize;
-
-    match &ty.body {
-        b::TypeBody::String(s) => s.len.map_or(ptr, |len| len + 1),
-        b::TypeBody::Array(a) => a.len.map_or(ptr, |len| {
-            len * get_type(&a.item, modules, obj_module).bytes() as usize
-        }),
-        b::TypeBody::TypeRef(i, j) => match &modules[*i].typedefs[*j].body {
-            b::TypeDefBody::Record(rec) => rec
-                .fields
-                .values()
-                .map(|field| get_type(&field.ty, modules, obj_module).bytes() as usize)
-                .sum(),
-        },
-        b::TypeBody::Bool
-        | b::TypeBody::I8
-        | b::TypeBody::U8
-        | b::TypeBody::I16
-        | b::TypeBody::U16
-        | b::TypeBody::I32
-        | b::TypeBody::U32
-        | b::TypeBody::I64
-        | b::TypeBody::U64
-        | b::TypeBody::USize
-        | b::TypeBody::F32
-        | b::TypeBody::F64
-        | b::TypeBody::Ptr(_) => get_type(ty, modules, obj_module).bytes() as usize,
-        b::TypeBody::AnyNumber
-        | b::TypeBody::AnySignedNumber
-        | b::TypeBody::AnyFloat
-        | b::TypeBody::Inferred(_) => panic!("Type must be resolved before codegen"),
-        b::TypeBody::AnyOpaque => panic!("anyopaque cannot be used directly"),
-    }
+    b::size_of(ty, modules, obj_module.isa().pointer_bytes())
 }
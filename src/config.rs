@@ -13,4 +13,10 @@ pub struct BuildConfig {
     pub dump_bytecode: bool,
     /// Whether to dump the CLIF of the source file, if using Cranelift
     pub dump_clif: bool,
+    /// Whether to build with optimizations and without the declarations marked with
+    /// `@cfg(debug)`
+    pub release: bool,
+    /// Options that enable the declarations marked with `@cfg`, given as a key and an
+    /// optional value
+    pub cfg: Vec<(String, Option<String>)>,
 }
@@ -4,10 +4,16 @@ use std::sync::{Mutex, RwLock};
 
 use derive_more::{Deref, DerefMut};
 use derive_new::new;
+use target_lexicon::{OperatingSystem, Triple};
 use tree_sitter as ts;
 
 use crate::{bytecode as b, codegen, config, errors, parser, sources, typecheck};
 
+/// Options of `@cfg` that are set by the target and the build settings, which can't be
+/// given in the build configuration
+pub const BUILTIN_CFG_KEYS: [&str; 4] =
+    ["target_os", "target_arch", "target_pointer_width", "debug"];
+
 #[derive(Debug, Deref, DerefMut, new)]
 pub struct BuildContext {
     pub cfg: config::BuildConfig,
@@ -18,6 +24,8 @@ pub struct BuildContext {
     #[new(default)]
     pub errors: Mutex<Vec<errors::Error>>,
     #[new(default)]
+    pub warnings: Mutex<Vec<errors::Warning>>,
+    #[new(default)]
     modules: RwLock<Vec<b::Module>>,
     #[new(default)]
     core_mod_idx: Option<usize>,
@@ -29,9 +37,45 @@ impl BuildContext {
     pub fn lock_modules_mut(&self) -> impl DerefMut<Target = Vec<b::Module>> + '_ {
         self.modules.write().unwrap()
     }
+    pub fn core_mod_idx(&self) -> Option<usize> {
+        self.core_mod_idx
+    }
+    /// Size of pointers on the target, which the layout of values depends on
+    pub fn ptr_bytes(&self) -> u8 {
+        Triple::host()
+            .pointer_width()
+            .expect("host should have a known pointer width")
+            .bytes()
+    }
+    /// Whether an option of `@cfg` is enabled. The target options only depend on the
+    /// target, `debug` on whether it's a release build, and the other options on the
+    /// build configuration
+    pub fn cfg_enabled(&self, key: &str, value: Option<&str>) -> bool {
+        let triple = Triple::host();
+        let target_value = match key {
+            "target_os" => match triple.operating_system {
+                OperatingSystem::Darwin | OperatingSystem::MacOSX { .. } => {
+                    "macos".to_string()
+                }
+                os => os.to_string(),
+            },
+            "target_arch" => triple.architecture.to_string(),
+            "target_pointer_width" => (self.ptr_bytes() as usize * 8).to_string(),
+            "debug" => return value.is_none() && !self.cfg.release,
+            _ => {
+                return self.cfg.cfg.iter().any(|(k, v)| {
+                    k == key && (value.is_none() || v.as_deref() == value)
+                });
+            }
+        };
+        value == Some(target_value.as_str())
+    }
     pub fn push_error(&self, value: errors::Error) {
         self.errors.lock().unwrap().push(value);
     }
+    pub fn push_warning(&self, value: errors::Warning) {
+        self.warnings.lock().unwrap().push(value);
+    }
     pub fn parse(&self, src_idx: usize) -> usize {
         let mut ts_parser = ts::Parser::new();
         ts_parser
@@ -65,6 +109,7 @@ impl BuildContext {
         module_parser.add_root(root_node);
         module_parser.finish();
         typecheck::TypeChecker::new(self, mod_idx).check();
+        typecheck::check_static_asserts(self, mod_idx);
 
         if self.cfg.dump_bytecode {
             println!("{}", &self.lock_modules()[mod_idx]);
@@ -83,6 +128,7 @@ impl BuildContext {
         self.core_mod_idx = Some(self.parse(core_src_idx));
     }
     pub fn compile(&self) {
+        b::fold_globals(&mut self.lock_modules_mut(), self.ptr_bytes());
         let modules = self.lock_modules();
 
         fs::create_dir_all(self.cfg.out.parent().unwrap()).unwrap();
@@ -13,47 +13,144 @@ pub struct Error {
     loc: b::Loc,
 }
 
+#[derive(Debug, Clone, Error, new)]
+#[error("{}:{}:{}: warning: {detail}", loc.source_idx, loc.start_line, loc.start_col)]
+pub struct Warning {
+    detail: WarningDetail,
+    loc: b::Loc,
+}
+
 #[derive(Debug, Clone, Error, new)]
 pub struct DisplayError<'a>(&'a context::BuildContext, &'a Error);
 impl fmt::Display for DisplayError<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let DisplayError(ctx, err) = self;
+        write_loc(f, ctx, &err.loc)?;
+        writeln!(f, "error: {}", err.detail)?;
 
-        let idx = err.loc.source_idx;
-        let src = ctx.source(idx);
-
-        let line = err.loc.start_line;
-        let col = err.loc.start_col;
-        writeln!(f, "{}:{line}:{col}", src.path.display())?;
+        Ok(())
+    }
+}
 
-        let num = format!("{line}");
-        let line_content = src.content().line(line).expect("line should be valid");
-        writeln!(f, "{} |", " ".repeat(num.len()))?;
-        writeln!(f, "{num} | {line_content}",)?;
-        writeln!(f, "{} | {}^", " ".repeat(num.len()), " ".repeat(col - 1))?;
-        writeln!(f, "error: {}", err.detail)?;
+#[derive(Debug, Clone, Error, new)]
+pub struct DisplayWarning<'a>(&'a context::BuildContext, &'a Warning);
+impl fmt::Display for DisplayWarning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let DisplayWarning(ctx, warning) = self;
+        write_loc(f, ctx, &warning.loc)?;
+        writeln!(f, "warning: {}", warning.detail)?;
 
         Ok(())
     }
 }
 
+fn write_loc(
+    f: &mut fmt::Formatter<'_>,
+    ctx: &context::BuildContext,
+    loc: &b::Loc,
+) -> fmt::Result {
+    let src = ctx.source(loc.source_idx);
+
+    let line = loc.start_line;
+    let col = loc.start_col;
+    writeln!(f, "{}:{line}:{col}", src.path.display())?;
+
+    let num = format!("{line}");
+    let line_content = src.content().line(line).expect("line should be valid");
+    writeln!(f, "{} |", " ".repeat(num.len()))?;
+    writeln!(f, "{num} | {line_content}",)?;
+    writeln!(f, "{} | {}^", " ".repeat(num.len()), " ".repeat(col - 1))?;
+
+    Ok(())
+}
+
 #[derive(Debug, Clone, Display, From)]
 pub enum ErrorDetail {
     ValueNotFound(ValueNotFound),
+    CapturedValue(CapturedValue),
     TypeNotFound(TypeNotFound),
     UnexpectedType(UnexpectedType),
     TypeMisatch(TypeMisatch),
     #[display("Type should be known at this point")]
     TypeNotFinal,
+    #[display(
+        "Entry point should have no parameters or a single parameter of type [str]"
+    )]
+    InvalidEntryPointParams,
+    InvalidEntryPointType(InvalidEntryPointType),
+    #[display("`?` can only be used inside a function, since it may return from it")]
+    TryOutsideFunc,
+    #[display(
+        "Statements of a block should start on the same column, wrap the inner block in \
+         parentheses if it's part of the previous statement"
+    )]
+    UnalignedStatement,
+    AmbiguousMethod(AmbiguousMethod),
+    FieldNotFunction(FieldNotFunction),
+    UnknownArgument(UnknownArgument),
+    DuplicatedArgument(DuplicatedArgument),
+    WrongArgumentCount(WrongArgumentCount),
+    TooManyArguments(TooManyArguments),
+    WrongMacroArgCount(WrongMacroArgCount),
+    InfiniteSizeType(InfiniteSizeType),
+    UnexpectedDirective(UnexpectedDirective),
+    UnknownDerive(UnknownDerive),
+    CoreOnlyMacro(CoreOnlyMacro),
+    CannotDerive(CannotDerive),
+    CyclicGlobals(CyclicGlobals),
+    #[display("Expected a string literal")]
+    ExpectedStringLiteral,
+    #[display("Expected the name of a function")]
+    ExpectedFunction,
+    #[display("Condition of `@static_assert` should be known at compile time")]
+    StaticAssertNotConstant,
+    StaticAssertFailed(StaticAssertFailed),
+    CannotReadFile(CannotReadFile),
+    InvalidUtf8File(InvalidUtf8File),
+    #[display("Expected an option like `key` or `key = \"value\"`")]
+    InvalidCfgOption,
     Todo(Todo),
 }
 
+#[derive(Debug, Clone, Display)]
+pub enum WarningDetail {
+    #[display("Value is discarded without being used, and it's not unit")]
+    DiscardedValue,
+}
+
 #[derive(Debug, Clone, Display, new)]
 #[display("Cannot find value `{ident}` on the current scope")]
 pub struct ValueNotFound {
     pub ident: String,
 }
 
+#[derive(Debug, Clone, Display, new)]
+#[display(
+    "Local functions cannot capture `{ident}` from the enclosing function, pass it \
+     as an argument instead"
+)]
+pub struct CapturedValue {
+    pub ident: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display(
+    "Cannot tell if `{name}` is a field or a function of {}, the type should be known \
+     before the call",
+    &ty.body
+)]
+pub struct AmbiguousMethod {
+    pub name: String,
+    pub ty: b::Type,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Field `{name}` has type {}, which cannot be called", &ty.body)]
+pub struct FieldNotFunction {
+    pub name: String,
+    pub ty: b::Type,
+}
+
 #[derive(Debug, Clone, Display, new)]
 #[display("Cannot find type `{ident}` on the current scope")]
 pub struct TypeNotFound {
@@ -76,6 +173,117 @@ pub struct TypeMisatch {
     pub types: Vec<b::Type>,
 }
 
+#[derive(Debug, Clone, Display, new)]
+#[display("Entry point should have type bool or i32, found {}", &ty.body)]
+pub struct InvalidEntryPointType {
+    pub ty: b::Type,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Function has no parameter named `{name}`")]
+pub struct UnknownArgument {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Parameter `{name}` is given more than one argument")]
+pub struct DuplicatedArgument {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Function value expects {expected} arguments, found {found}")]
+pub struct WrongArgumentCount {
+    pub expected: usize,
+    pub found: usize,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("`@{name}` expects {}, found {found}", expected_args(*min, *max))]
+pub struct WrongMacroArgCount {
+    pub name: String,
+    pub min: usize,
+    pub max: usize,
+    pub found: usize,
+}
+
+fn expected_args(min: usize, max: usize) -> String {
+    match (min, max) {
+        (1, 1) => "a single argument".to_string(),
+        _ if min == max => format!("{min} arguments"),
+        _ => format!("{min} or {max} arguments"),
+    }
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Function expects at most {expected} arguments, found {found}")]
+pub struct TooManyArguments {
+    pub expected: usize,
+    pub found: usize,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display(
+    "Type `{name}` has infinite size, it should refer to itself through an array, an \
+     option or a pointer"
+)]
+pub struct InfiniteSizeType {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Directive `@{name}` cannot be used here")]
+pub struct UnexpectedDirective {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Cannot derive `{name}`, expected one of `Show`, `Eq` or `Hash`")]
+pub struct UnknownDerive {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("`@{name}` can only be used by the core library")]
+pub struct CoreOnlyMacro {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Cannot derive `{derive}`, the type of field `{field}` doesn't support it")]
+pub struct CannotDerive {
+    pub derive: String,
+    pub field: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display(
+    "Globals depend on each other, so they can't be initialized: {}",
+    names.iter().map(|name| format!("`{name}`")).collect::<Vec<_>>().join(" -> "),
+)]
+pub struct CyclicGlobals {
+    pub names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Static assertion failed: {message}")]
+pub struct StaticAssertFailed {
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("Cannot read file `{path}`: {reason}")]
+pub struct CannotReadFile {
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Display, new)]
+#[display("File `{path}` is not valid UTF-8, use `@embed_bytes` to embed it as bytes")]
+pub struct InvalidUtf8File {
+    pub path: String,
+}
+
 #[derive(Debug, Clone, Display, new)]
 #[display("Feature is not implemented yet: {feature}")]
 pub struct Todo {
@@ -5,7 +5,7 @@ use std::process::exit;
 use clap::{Parser, Subcommand};
 use nasin::config::BuildConfig;
 use nasin::context;
-use nasin::errors::DisplayError;
+use nasin::errors::{DisplayError, DisplayWarning};
 
 #[derive(Parser, Debug)]
 #[command(name = "Nasin Language")]
@@ -37,6 +37,13 @@ enum CliCommand {
         #[arg(long)]
         /// Whether to dump the parsed CLIF of the source file, if using Cranelift
         dump_clif: bool,
+        #[arg(long)]
+        /// Build with optimizations and without the declarations marked with
+        /// `@cfg(debug)`
+        release: bool,
+        #[arg(long, value_name = "KEY[=VALUE]")]
+        /// Enable the declarations marked with a matching `@cfg` directive
+        cfg: Vec<String>,
     },
 }
 
@@ -53,7 +60,28 @@ fn main() {
             dump_ast,
             dump_bytecode,
             dump_clif,
+            release,
+            cfg,
         } => {
+            let cfg: Vec<_> = cfg
+                .iter()
+                .map(|option| match option.split_once('=') {
+                    Some((key, value)) => (
+                        key.trim().to_string(),
+                        Some(value.trim().trim_matches('"').to_string()),
+                    ),
+                    None => (option.trim().to_string(), None),
+                })
+                .collect();
+            for (key, _) in &cfg {
+                if context::BUILTIN_CFG_KEYS.contains(&key.as_str()) {
+                    eprintln!(
+                        "`{key}` is set by the build and cannot be given with --cfg"
+                    );
+                    exit(1);
+                }
+            }
+
             let mut ctx = context::BuildContext::new(BuildConfig {
                 out: out.unwrap_or_else(|| {
                     env::current_dir()
@@ -65,6 +93,8 @@ fn main() {
                 dump_ast,
                 dump_bytecode,
                 dump_clif,
+                release,
+                cfg,
             });
 
             ctx.parse_library();
@@ -72,6 +102,12 @@ fn main() {
 
             ctx.parse(src_idx);
 
+            if !ctx.cfg.silent {
+                for warning in ctx.warnings.lock().unwrap().iter() {
+                    eprintln!("{}", DisplayWarning::new(&ctx, warning));
+                }
+            }
+
             {
                 let errors = ctx.errors.lock().unwrap();
                 if errors.len() > 0 {
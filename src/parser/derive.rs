@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+use derive_new::new;
+use itertools::{repeat_n, Itertools};
+
+use crate::utils::ValueStack;
+use crate::{bytecode as b, context, errors};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Derive {
+    Show,
+    Eq,
+    Hash,
+}
+
+impl Derive {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Show" => Some(Derive::Show),
+            "Eq" => Some(Derive::Eq),
+            "Hash" => Some(Derive::Hash),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Derive::Show => "Show",
+            Derive::Eq => "Eq",
+            Derive::Hash => "Hash",
+        }
+    }
+    /// Name of the function derived for a type, e.g. `show_http_request` for the type
+    /// `HttpRequest`
+    pub fn func_name(&self, type_name: &str) -> String {
+        let prefix = match self {
+            Derive::Show => "show",
+            Derive::Eq => "eq",
+            Derive::Hash => "hash",
+        };
+        format!("{prefix}_{}", snake_case(type_name))
+    }
+    pub fn params_names(&self) -> &'static [&'static str] {
+        match self {
+            Derive::Show | Derive::Hash => &["value"],
+            Derive::Eq => &["a", "b"],
+        }
+    }
+    pub fn ret(&self) -> b::Type {
+        let body = match self {
+            Derive::Show => b::TypeBody::String(b::StringType::new(None)),
+            Derive::Eq => b::TypeBody::Bool,
+            Derive::Hash => b::TypeBody::U64,
+        };
+        b::Type::new(body, None)
+    }
+}
+
+/// Generates the bodies of the derived functions directly as bytecode. Fields of
+/// primitive types are handled by functions of the core module, while fields of other
+/// records use the functions derived for them
+#[derive(new)]
+pub struct DeriveBuilder<'a> {
+    ctx: &'a context::BuildContext,
+    mod_idx: usize,
+    typedefs: &'a [b::TypeDef],
+    /// Index of the function derived for each type of the module
+    derived: &'a HashMap<(usize, Derive), usize>,
+}
+
+impl<'a> DeriveBuilder<'a> {
+    /// Builds the body of the function. If any field can't be handled, the errors are
+    /// reported and the body is left empty
+    pub fn build(
+        &self,
+        typedef_idx: usize,
+        derive: Derive,
+        loc: b::Loc,
+    ) -> Vec<b::Instr> {
+        let typedef = &self.typedefs[typedef_idx];
+        let b::TypeDefBody::Record(rec) = &typedef.body else {
+            panic!("type should be a record type");
+        };
+        let fields = rec
+            .fields
+            .values()
+            .sorted_by_key(|field| field.loc.start_byte)
+            .collect_vec();
+
+        let mut func = FuncBuilder::new(loc, derive.params_names().len());
+        let ok = match derive {
+            Derive::Show => self.build_show(&mut func, &typedef.name, &fields),
+            Derive::Eq => self.build_eq(&mut func, &fields),
+            Derive::Hash => self.build_hash(&mut func, &fields),
+        };
+        if ok {
+            func.instrs
+        } else {
+            vec![]
+        }
+    }
+
+    fn build_show(
+        &self,
+        func: &mut FuncBuilder,
+        name: &str,
+        fields: &[&b::RecordField],
+    ) -> bool {
+        if fields.is_empty() {
+            func.push(b::InstrBody::CreateString(format!("{name} {{}}")), 0, 1);
+            return true;
+        }
+
+        let concat = self.core_func("_str_concat");
+        let mut ok = true;
+        for (i, field) in fields.iter().enumerate() {
+            let sep = if i == 0 {
+                format!("{name} {{ ")
+            } else {
+                ", ".to_string()
+            };
+            func.push(
+                b::InstrBody::CreateString(format!("{sep}{}: ", field.name.name)),
+                0,
+                1,
+            );
+            if i > 0 {
+                func.call(concat, 2);
+            }
+
+            func.dup(0);
+            func.push(b::InstrBody::GetField(field.name.name.clone()), 1, 1);
+            let ty = &field.ty;
+            if ty.is_sint() {
+                func.push(b::InstrBody::Cast(number_type(b::TypeBody::I64)), 1, 1);
+                func.call(self.core_func("_show_i64"), 1);
+            } else if ty.is_uint() {
+                func.push(b::InstrBody::Cast(number_type(b::TypeBody::U64)), 1, 1);
+                func.call(self.core_func("_show_u64"), 1);
+            } else if matches!(&ty.body, b::TypeBody::Bool) {
+                func.call(self.core_func("_show_bool"), 1);
+            } else if matches!(&ty.body, b::TypeBody::String(_)) {
+                func.call(self.core_func("_show_str"), 1);
+            } else if let Some(derived) = self.derived_func(field, Derive::Show) {
+                func.call(derived, 1);
+            } else {
+                ok = false;
+                continue;
+            }
+            func.call(concat, 2);
+        }
+        func.push(b::InstrBody::CreateString(" }".to_string()), 0, 1);
+        func.call(concat, 2);
+        ok
+    }
+
+    fn build_eq(&self, func: &mut FuncBuilder, fields: &[&b::RecordField]) -> bool {
+        let Some((field, rest)) = fields.split_first() else {
+            func.push(b::InstrBody::CreateBool(true), 0, 1);
+            return true;
+        };
+
+        for param in [0, 1] {
+            func.dup(param);
+            func.push(b::InstrBody::GetField(field.name.name.clone()), 1, 1);
+        }
+        let ty = &field.ty;
+        let mut ok = true;
+        if ty.is_number() {
+            func.push(b::InstrBody::Eq, 2, 1);
+        } else if matches!(&ty.body, b::TypeBody::Bool) {
+            func.call(self.core_func("_bool_eq"), 2);
+        } else if matches!(&ty.body, b::TypeBody::String(_)) {
+            func.call(self.core_func("_str_eq"), 2);
+        } else if let Some(derived) = self.derived_func(field, Derive::Eq) {
+            func.call(derived, 2);
+        } else {
+            ok = false;
+            func.push(b::InstrBody::CreateBool(false), 0, 1);
+        }
+
+        if rest.is_empty() {
+            return ok;
+        }
+
+        // The remaining fields are only compared if this one is equal
+        func.start_if();
+        let rest_ok = self.build_eq(func, rest);
+        func.start_else();
+        func.push(b::InstrBody::CreateBool(false), 0, 1);
+        func.end();
+        ok && rest_ok
+    }
+
+    fn build_hash(&self, func: &mut FuncBuilder, fields: &[&b::RecordField]) -> bool {
+        func.push(
+            b::InstrBody::CreateNumber(number_type(b::TypeBody::U64), "0".to_string()),
+            0,
+            1,
+        );
+
+        let mut ok = true;
+        for field in fields {
+            func.push(
+                b::InstrBody::CreateNumber(
+                    number_type(b::TypeBody::U64),
+                    "31".to_string(),
+                ),
+                0,
+                1,
+            );
+            func.push(b::InstrBody::Mul, 2, 1);
+
+            func.dup(0);
+            func.push(b::InstrBody::GetField(field.name.name.clone()), 1, 1);
+            let ty = &field.ty;
+            if ty.is_int() {
+                func.push(b::InstrBody::Cast(number_type(b::TypeBody::U64)), 1, 1);
+            } else if matches!(&ty.body, b::TypeBody::Bool) {
+                func.call(self.core_func("_hash_bool"), 1);
+            } else if matches!(&ty.body, b::TypeBody::String(_)) {
+                func.call(self.core_func("_hash_str"), 1);
+            } else if let Some(derived) = self.derived_func(field, Derive::Hash) {
+                func.call(derived, 1);
+            } else {
+                ok = false;
+                continue;
+            }
+            func.push(b::InstrBody::Add, 2, 1);
+        }
+        ok
+    }
+
+    /// Function derived for the type of the field, which should be a record deriving the
+    /// same function. Reports an error if there's none
+    fn derived_func(
+        &self,
+        field: &b::RecordField,
+        derive: Derive,
+    ) -> Option<(usize, usize)> {
+        let func = match &field.ty.body {
+            b::TypeBody::TypeRef(mod_idx, ty_idx) if *mod_idx == self.mod_idx => self
+                .derived
+                .get(&(*ty_idx, derive))
+                .map(|func_idx| (self.mod_idx, *func_idx)),
+            b::TypeBody::TypeRef(mod_idx, ty_idx) => {
+                let modules = self.ctx.lock_modules();
+                let module = &modules[*mod_idx];
+                let func_name = derive.func_name(&module.typedefs[*ty_idx].name);
+                module
+                    .funcs
+                    .iter()
+                    .position(|func| func.name == func_name)
+                    .map(|func_idx| (*mod_idx, func_idx))
+            }
+            _ => None,
+        };
+
+        if func.is_none() {
+            self.ctx.push_error(errors::Error::new(
+                errors::CannotDerive::new(
+                    derive.name().to_string(),
+                    field.name.name.clone(),
+                )
+                .into(),
+                field.ty.loc.unwrap_or(field.loc),
+            ));
+        }
+        func
+    }
+
+    fn core_func(&self, name: &str) -> (usize, usize) {
+        let core_mod_idx = self
+            .ctx
+            .core_mod_idx()
+            .expect("core module should be parsed before deriving functions");
+        let func_idx = self.ctx.lock_modules()[core_mod_idx]
+            .funcs
+            .iter()
+            .position(|func| func.name == name)
+            .unwrap_or_else(|| panic!("core module should have function `{name}`"));
+        (core_mod_idx, func_idx)
+    }
+}
+
+/// Keeps track of the stack while the instructions are added, so values can be
+/// duplicated by their absolute position. The bodies aren't built by `ExprParser`,
+/// since it parses them from syntax nodes, while derived functions have no source and
+/// choose how to handle each field by its declared type. Only the stack is shared with
+/// it, for the same checks of the values left by each branch
+struct FuncBuilder {
+    instrs: Vec<b::Instr>,
+    stack: ValueStack<(), ()>,
+    loc: b::Loc,
+}
+
+impl FuncBuilder {
+    fn new(loc: b::Loc, params_len: usize) -> Self {
+        let mut stack = ValueStack::new(());
+        stack.extend(repeat_n((), params_len));
+        Self {
+            instrs: vec![],
+            stack,
+            loc,
+        }
+    }
+    fn push(&mut self, body: b::InstrBody, pops: usize, pushes: usize) {
+        self.stack.pop_many(pops);
+        self.stack.extend(repeat_n((), pushes));
+        self.instrs.push(b::Instr::new(body, self.loc));
+    }
+    fn dup(&mut self, pos: usize) {
+        self.push(b::InstrBody::Dup(self.stack.len() - 1 - pos), 0, 1);
+    }
+    fn call(&mut self, (mod_idx, func_idx): (usize, usize), args_len: usize) {
+        self.push(b::InstrBody::Call(mod_idx, func_idx), args_len, 1);
+    }
+    fn start_if(&mut self) {
+        self.push(
+            b::InstrBody::If(b::Type::new(b::TypeBody::Bool, None)),
+            1,
+            0,
+        );
+        self.stack.create_scope(());
+    }
+    fn start_else(&mut self) {
+        self.stack.branch_scope();
+        self.push(b::InstrBody::Else, 0, 0);
+    }
+    fn end(&mut self) {
+        self.stack.end_scope();
+        self.push(b::InstrBody::End, 0, 1);
+    }
+}
+
+fn number_type(body: b::TypeBody) -> b::Type {
+    b::Type::new(body, None)
+}
+
+fn snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c.is_uppercase() && prev_lower {
+            result.push('_');
+        }
+        result.extend(c.to_lowercase());
+        prev_lower = c.is_lowercase() || c.is_ascii_digit();
+    }
+    result
+}
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::usize;
 
 use derive_new::new;
-use itertools::Itertools;
+use itertools::{izip, Itertools};
 use tree_sitter as ts;
 
 use super::module_parser::ModuleParser;
@@ -12,6 +12,9 @@ use crate::utils::{TreeSitterUtils, ValueStack};
 use crate::{bytecode as b, context, errors, utils};
 
 type Stack = ValueStack<(), ScopePayload>;
+/// Argument of a call, along with its name and location if it's named. The value of a
+/// `_` argument is `None`
+type Arg = (Option<(String, b::Loc)>, Option<Value>);
 
 pub struct ExprParser<'a, 't> {
     pub module_parser: ModuleParser<'a, 't>,
@@ -34,7 +37,10 @@ impl<'a, 't> ExprParser<'a, 't> {
         func_idx: Option<usize>,
         inputs: impl IntoIterator<Item = (String, b::Loc)>,
     ) -> Self {
-        let mut idents = module_parser.idents.clone();
+        let mut idents = match func_idx {
+            Some(idx) => module_parser.func_idents(idx).clone(),
+            None => module_parser.idents.clone(),
+        };
 
         let mut stack = Stack::new(ScopePayload::new(idents.clone()));
         for (i, (ident, loc)) in inputs.into_iter().enumerate() {
@@ -145,8 +151,28 @@ impl<'a, 't> ExprParser<'a, 't> {
             }
             "ident" => {
                 let ident = node.get_text(&self.ctx.source(self.src_idx).content().text);
-                if let Some(value) = self.idents.get(ident) {
+                if let Some(Value {
+                    body: ValueBody::Captured,
+                    ..
+                }) = self.idents.get(ident)
+                {
+                    self.ctx.push_error(errors::Error::new(
+                        errors::CapturedValue::new(ident.to_string()).into(),
+                        loc,
+                    ));
+                    Value::new(ValueBody::CompileError, loc)
+                } else if let Some(value) = self.idents.get(ident) {
                     value.with_loc(loc)
+                } else if ident == "none" {
+                    let ty = b::Type::new(
+                        b::TypeBody::Option(b::Type::unknown(None).into()),
+                        None,
+                    );
+                    let idx = self.add_instr_with_result(
+                        0,
+                        b::Instr::new(b::InstrBody::CreateNone(ty), loc),
+                    );
+                    Value::new(ValueBody::Local(idx), loc)
                 } else {
                     self.ctx.push_error(errors::Error::new(
                         errors::ValueNotFound::new(ident.to_string()).into(),
@@ -158,8 +184,13 @@ impl<'a, 't> ExprParser<'a, 't> {
             "bin_op" => {
                 let op = node.required_field("op");
                 let left = self.add_expr_node(node.required_field("left"), false);
-                let right = self.add_expr_node(node.required_field("right"), false);
-                self.add_bin_op(op, left, right)
+                if op.kind() == "double_question" {
+                    let right_node = node.required_field("right");
+                    self.add_or_else(left, right_node, loc)
+                } else {
+                    let right = self.add_expr_node(node.required_field("right"), false);
+                    self.add_bin_op(op, left, right)
+                }
             }
             "get_prop" => {
                 let parent = self.add_expr_node(node.required_field("parent"), false);
@@ -172,17 +203,109 @@ impl<'a, 't> ExprParser<'a, 't> {
                     Loc::from_node(self.src_idx, &prop_name_node),
                 )
             }
+            "slice" => {
+                let parent = self.add_expr_node(node.required_field("parent"), false);
+                let start = match node.field("start") {
+                    Some(start_node) => self.add_expr_node(start_node, false),
+                    None => Value::new(ValueBody::Number("0".to_string()), loc),
+                };
+                let end = match node.field("end") {
+                    Some(end_node) => self.add_expr_node(end_node, false),
+                    None => {
+                        self.push_values([&parent], false);
+                        let idx = self.add_instr_with_result(
+                            1,
+                            b::Instr::new(b::InstrBody::ArrayLen, loc),
+                        );
+                        Value::new(ValueBody::Local(idx), loc)
+                    }
+                };
+                self.push_values([&parent, &start, &end], false);
+                let idx = self.add_instr_with_result(
+                    3,
+                    b::Instr::new(b::InstrBody::ArraySlice, loc),
+                );
+                Value::new(ValueBody::Local(idx), loc)
+            }
             "call" => {
-                let callee = self.add_expr_node(node.required_field("callee"), false);
-                let args: Vec<_> = node
-                    .iter_field("args")
-                    .map(|arg_node| self.add_expr_node(arg_node, false))
-                    .collect();
-                self.add_call(callee, args, loc, returning)
+                let callee_node = node.required_field("callee");
+                let arg_nodes = node.iter_field("args").collect_vec();
+                // `value.func(args)` is a call to `func(value, args)` when there is such
+                // function in scope, unless the type of the value has a field with the
+                // same name, which is checked by the type checker
+                let method_node = (callee_node.kind() == "get_prop")
+                    .then(|| callee_node.required_field("prop_name"))
+                    .filter(|prop_name_node| self.is_func_ident(*prop_name_node));
+                if let Some(method_node) = method_node {
+                    let parent_node = callee_node.required_field("parent");
+                    let parent = self.add_expr_node(parent_node, false);
+                    self.add_call_node(
+                        method_node,
+                        Some(parent),
+                        arg_nodes,
+                        loc,
+                        true,
+                        false,
+                    )
+                } else {
+                    self.add_call_node(
+                        callee_node,
+                        None,
+                        arg_nodes,
+                        loc,
+                        false,
+                        returning,
+                    )
+                }
+            }
+            "pipe" => {
+                let value = self.add_expr_node(node.required_field("value"), false);
+                let func_node = node.required_field("func");
+                let func_loc = Loc::from_node(self.src_idx, &func_node);
+                if func_node.kind() == "call" {
+                    let callee_node = func_node.required_field("callee");
+                    let arg_nodes = func_node.iter_field("args").collect_vec();
+                    self.add_call_node(
+                        callee_node,
+                        Some(value),
+                        arg_nodes,
+                        func_loc,
+                        false,
+                        returning,
+                    )
+                } else {
+                    self.add_call_node(
+                        func_node,
+                        Some(value),
+                        [],
+                        func_loc,
+                        false,
+                        returning,
+                    )
+                }
+            }
+            "try" => {
+                let value = self.add_expr_node(node.required_field("value"), false);
+                self.add_try(value, loc)
             }
             "block" => {
                 let old_idents = self.idents.clone();
 
+                // Indentation is not part of the grammar, so a statement that is indented
+                // differently was probably meant to be part of the previous one
+                let value_node = node.required_field("value");
+                let col = loc.start_col;
+                for item_node in node.iter_field("body").chain([value_node]) {
+                    let item_loc = Loc::from_node(self.src_idx, &item_node);
+                    if item_loc.start_col != col {
+                        self.ctx.push_error(errors::Error::new(
+                            errors::ErrorDetail::UnalignedStatement,
+                            item_loc,
+                        ));
+                        break;
+                    }
+                }
+
                 for stmt_node in node.iter_field("body") {
                     self.add_stmt_node(stmt_node);
                 }
@@ -194,69 +317,18 @@ impl<'a, 't> ExprParser<'a, 't> {
             }
             "if" => {
                 let cond_value = self.add_expr_node(node.required_field("cond"), false);
-                self.push_values([&cond_value], false);
-                self.stack.pop(); // consume condition
-
-                let stack_len = self.stack.len();
-                let block_len = self.stack.scope_len();
-
-                self.stack
-                    .create_scope(ScopePayload::new(self.idents.clone()));
-
-                self.instrs.push(b::Instr::new(
-                    b::InstrBody::If(b::Type::unknown(None)),
-                    b::Loc::from_node(self.src_idx, &node),
-                ));
-                let then_value =
-                    self.add_expr_node(node.required_field("then"), returning);
-
-                if !then_value.is_never() {
-                    self.push_values([&then_value], true);
-                    assert!(self.stack.len() >= stack_len + 1);
-                }
-
-                let else_value = if let Some(else_node) = node.field("else") {
-                    assert!(self.stack.scope_len() >= block_len + 1);
-                    self.instrs.push(b::Instr::new(
-                        b::InstrBody::Else,
+                let else_branch = node.field("else").map(|else_node| {
+                    (
                         Loc::from_node(self.src_idx, &else_node),
-                    ));
-
-                    let (scope, _) = self.stack.branch_scope();
-                    self.idents = scope.payload.idents.clone();
-
-                    let else_value = self.add_expr_node(else_node, returning);
-
-                    if !else_value.is_never() {
-                        self.push_values([&else_value], true);
-                        assert!(self.stack.len() >= stack_len + 1);
-                    }
-
-                    else_value
-                } else {
-                    self.ctx.push_error(errors::Error::new(
-                        errors::Todo::new("if without else".to_string()).into(),
-                        loc,
-                    ));
-                    Value::new(ValueBody::CompileError, loc)
-                };
-
-                assert!(self.stack.scope_len() >= block_len + 1);
-                let (scope, _) = self.stack.end_scope();
-                self.idents = scope.payload.idents;
-
-                if !then_value.is_never() || !else_value.is_never() {
-                    let idx = self.add_instr_with_result(
-                        0,
-                        b::Instr::new(
-                            b::InstrBody::End,
-                            Loc::from_node(self.src_idx, &node),
-                        ),
-                    );
-                    Value::new(ValueBody::Local(idx), loc)
-                } else {
-                    Value::new(ValueBody::Never, loc)
-                }
+                        move |this: &mut Self| this.add_expr_node(else_node, returning),
+                    )
+                });
+                self.add_if(
+                    cond_value,
+                    |this| this.add_expr_node(node.required_field("then"), returning),
+                    else_branch,
+                    loc,
+                )
             }
             "macro" => {
                 let name = node
@@ -290,9 +362,21 @@ impl<'a, 't> ExprParser<'a, 't> {
                         ),
                     );
                 }
-                ValueBody::Func(_, _) => {
+                ValueBody::Func(mod_idx, func_idx) => {
+                    let params_len = self.func_params(*mod_idx, *func_idx).len();
+                    self.push_closure(
+                        *mod_idx,
+                        *func_idx,
+                        &vec![None; params_len],
+                        value.loc,
+                    );
+                }
+                ValueBody::PartialFunc(mod_idx, func_idx, bound) => {
+                    self.push_closure(*mod_idx, *func_idx, bound, value.loc);
+                }
+                ValueBody::Variant(..) => {
                     self.ctx.push_error(errors::Error::new(
-                        errors::Todo::new("function as value".to_string()).into(),
+                        errors::Todo::new("constructor as value".to_string()).into(),
                         value.loc,
                     ));
                     self.add_instr_with_result(
@@ -337,6 +421,9 @@ impl<'a, 't> ExprParser<'a, 't> {
                         ),
                     );
                 }
+                ValueBody::Captured => {
+                    unreachable!("captured values are reported when referenced")
+                }
                 ValueBody::Never | ValueBody::CompileError => {
                     self.add_instr_with_result(
                         0,
@@ -347,6 +434,42 @@ impl<'a, 't> ExprParser<'a, 't> {
         }
     }
 
+    /// Pushes a function value that calls the function with the bound arguments. The
+    /// open parameters that have default values take them, so the function value only
+    /// receives the parameters without a value
+    fn push_closure(
+        &mut self,
+        mod_idx: usize,
+        func_idx: usize,
+        bound: &[Option<Value>],
+        loc: b::Loc,
+    ) {
+        let params = self.func_params(mod_idx, func_idx);
+        let values = izip!(bound, params)
+            .map(|(value, param)| match (value, param.default) {
+                (Some(value), _) => Some(value.clone()),
+                (None, Some(default)) => {
+                    Some(Value::new(ValueBody::Global(mod_idx, default), loc))
+                }
+                (None, None) => None,
+            })
+            .collect_vec();
+        let bound_values = values.iter().flatten().collect_vec();
+        self.push_values(bound_values.iter().copied(), false);
+        self.add_instr_with_result(
+            bound_values.len(),
+            b::Instr::new(
+                b::InstrBody::CreateClosure(
+                    b::Type::unknown(None),
+                    mod_idx,
+                    func_idx,
+                    values.iter().map(Option::is_some).collect(),
+                ),
+                loc,
+            ),
+        );
+    }
+
     fn add_instr_with_result(&mut self, input_count: usize, instr: b::Instr) -> usize {
         self.instrs.push(instr);
         for _ in 0..input_count {
@@ -384,6 +507,99 @@ impl<'a, 't> ExprParser<'a, 't> {
         Value::new(ValueBody::Local(idx), loc)
     }
 
+    /// Adds the instructions to get the value of an option, or the fallback value when
+    /// there is none. The fallback is only evaluated when it's used
+    fn add_or_else(
+        &mut self,
+        option: Value,
+        fallback_node: ts::Node<'t>,
+        loc: b::Loc,
+    ) -> Value {
+        self.push_values([&option], false);
+        let cond_idx = self.add_instr_with_result(
+            1,
+            b::Instr::new(b::InstrBody::OptionIsSome, option.loc),
+        );
+        let cond_value = Value::new(ValueBody::Local(cond_idx), option.loc);
+
+        let fallback_loc = Loc::from_node(self.src_idx, &fallback_node);
+        self.add_if(
+            cond_value,
+            |this| {
+                this.push_values([&option], false);
+                let idx = this.add_instr_with_result(
+                    1,
+                    b::Instr::new(b::InstrBody::OptionUnwrap, option.loc),
+                );
+                Value::new(ValueBody::Local(idx), option.loc)
+            },
+            Some((fallback_loc, |this: &mut Self| {
+                this.add_expr_node(fallback_node, false)
+            })),
+            loc,
+        )
+    }
+
+    /// Adds the instructions to get the value of a result, or to return its error from
+    /// the current function when there is one
+    fn add_try(&mut self, result: Value, loc: b::Loc) -> Value {
+        if self.func_idx.is_none() {
+            self.ctx
+                .push_error(errors::Error::new(errors::ErrorDetail::TryOutsideFunc, loc));
+            return Value::new(ValueBody::CompileError, loc);
+        }
+
+        let (result_ty, ok) = self.result_case("ok");
+        let (_, err) = self.result_case("err");
+
+        self.push_values([&result], false);
+        let cond_idx = self.add_instr_with_result(
+            1,
+            b::Instr::new(b::InstrBody::VariantIs(result_ty.clone(), ok), result.loc),
+        );
+        let cond_value = Value::new(ValueBody::Local(cond_idx), result.loc);
+
+        self.add_if(
+            cond_value,
+            |this| {
+                this.push_values([&result], false);
+                let idx = this.add_instr_with_result(
+                    1,
+                    b::Instr::new(
+                        b::InstrBody::VariantUnwrap(result_ty.clone(), ok),
+                        result.loc,
+                    ),
+                );
+                Value::new(ValueBody::Local(idx), result.loc)
+            },
+            Some((loc, |this: &mut Self| {
+                this.push_values([&result], false);
+                this.add_instr_with_result(
+                    1,
+                    b::Instr::new(
+                        b::InstrBody::VariantUnwrap(result_ty.clone(), err),
+                        loc,
+                    ),
+                );
+                // The error is returned in a new result, since the function may return
+                // a different type when it's ok
+                this.add_instr_with_result(
+                    1,
+                    b::Instr::new(
+                        b::InstrBody::CreateVariant(result_ty.clone(), err),
+                        loc,
+                    ),
+                );
+
+                this.instrs.push(b::Instr::new(b::InstrBody::Return, loc));
+                this.stack.pop();
+                this.stack.get_scope_mut().mark_as_never();
+                Value::new(ValueBody::Never, loc)
+            })),
+            loc,
+        )
+    }
+
     fn add_get_prop(&mut self, parent: Value, prop_name: &str, loc: b::Loc) -> Value {
         self.push_values([&parent], false);
         let idx = self.add_instr_with_result(
@@ -393,6 +609,339 @@ impl<'a, 't> ExprParser<'a, 't> {
         Value::new(ValueBody::Local(idx), loc)
     }
 
+    /// Type of the results declared by the core module, used by `?` and by the macros
+    /// that handle results, along with the index of one of its cases
+    fn result_case(&self, case_name: &str) -> (b::Type, usize) {
+        let core_mod_idx = self
+            .ctx
+            .core_mod_idx()
+            .expect("core module should be parsed before handling results");
+        let modules = self.ctx.lock_modules();
+        let (ty_idx, typedef) = modules[core_mod_idx]
+            .typedefs
+            .iter()
+            .find_position(|typedef| typedef.name == "Result")
+            .expect("core module should have type `Result`");
+        let b::TypeDefBody::Variant(v) = &typedef.body else {
+            panic!("`Result` should be a variant type");
+        };
+        let case = v
+            .cases
+            .iter()
+            .position(|case| case.name == case_name)
+            .unwrap_or_else(|| panic!("`Result` should have case `{case_name}`"));
+        let ty = b::Type::new(typedef.type_ref(core_mod_idx, ty_idx), None);
+        (ty, case)
+    }
+
+    fn is_func_ident(&self, node: ts::Node<'t>) -> bool {
+        let ident = node.get_text(&self.ctx.source(self.src_idx).content().text);
+        match self.idents.get(ident) {
+            Some(value) => matches!(
+                value.body,
+                ValueBody::Func(..) | ValueBody::PartialFunc(..) | ValueBody::Variant(..)
+            ),
+            None => ident == "some",
+        }
+    }
+
+    /// Adds a call to the function referenced by `callee_node`. When `first_arg` is
+    /// given, it's passed before the arguments from `arg_nodes`, as done by the pipe
+    /// operator and the method syntax
+    fn add_call_node(
+        &mut self,
+        callee_node: ts::Node<'t>,
+        first_arg: Option<Value>,
+        arg_nodes: impl IntoIterator<Item = ts::Node<'t>>,
+        loc: b::Loc,
+        is_method: bool,
+        returning: bool,
+    ) -> Value {
+        let callee_name = match callee_node.kind() {
+            "ident" => {
+                callee_node.get_text(&self.ctx.source(self.src_idx).content().text)
+            }
+            _ => "",
+        };
+        let variant = match self.idents.get(callee_name) {
+            Some(Value {
+                body: ValueBody::Variant(mod_idx, ty_idx, case),
+                ..
+            }) => Some((*mod_idx, *ty_idx, *case)),
+            _ => None,
+        };
+        let is_constructor = variant.is_some()
+            || callee_name == "some" && !self.idents.contains_key("some");
+        if is_constructor {
+            let args = first_arg
+                .into_iter()
+                .chain(arg_nodes.into_iter().map(|arg_node| {
+                    let (name, value) = self.add_arg_node(arg_node);
+                    if let Some((name, name_loc)) = name {
+                        self.ctx.push_error(errors::Error::new(
+                            errors::UnknownArgument::new(name).into(),
+                            name_loc,
+                        ));
+                    }
+                    value.unwrap_or_else(|| {
+                        let arg_loc = Loc::from_node(self.src_idx, &arg_node);
+                        self.ctx.push_error(errors::Error::new(
+                            errors::Todo::new(
+                                "partial application of constructors".to_string(),
+                            )
+                            .into(),
+                            arg_loc,
+                        ));
+                        Value::new(ValueBody::CompileError, arg_loc)
+                    })
+                }))
+                .collect_vec();
+            // TODO: better error handling
+            assert!(args.len() == 1, "{callee_name}() expects a single argument");
+
+            self.push_values([&args[0]], false);
+
+            let instr_body = match variant {
+                Some((mod_idx, ty_idx, case)) => {
+                    let ty_body = if mod_idx == self.mod_idx {
+                        self.module_parser.types.typedefs[ty_idx]
+                            .type_ref(mod_idx, ty_idx)
+                    } else {
+                        self.ctx.lock_modules()[mod_idx].typedefs[ty_idx]
+                            .type_ref(mod_idx, ty_idx)
+                    };
+                    b::InstrBody::CreateVariant(b::Type::new(ty_body, None), case)
+                }
+                None => b::InstrBody::CreateSome(b::Type::new(
+                    b::TypeBody::Option(b::Type::unknown(None).into()),
+                    None,
+                )),
+            };
+            let idx = self.add_instr_with_result(1, b::Instr::new(instr_body, loc));
+            Value::new(ValueBody::Local(idx), loc)
+        } else {
+            let callee = self.add_expr_node(callee_node, false);
+            let args = first_arg
+                .into_iter()
+                .map(|value| (None, Some(value)))
+                .chain(
+                    arg_nodes
+                        .into_iter()
+                        .map(|arg_node| self.add_arg_node(arg_node)),
+                )
+                .collect_vec();
+            let Some(args) = self.match_args(&callee, args, loc) else {
+                return Value::new(ValueBody::CompileError, loc);
+            };
+            match callee.body {
+                ValueBody::Func(mod_idx, func_idx)
+                | ValueBody::PartialFunc(mod_idx, func_idx, _)
+                    if args.iter().any(Option::is_none) =>
+                {
+                    Value::new(ValueBody::PartialFunc(mod_idx, func_idx, args), loc)
+                }
+                ValueBody::Func(mod_idx, func_idx)
+                | ValueBody::PartialFunc(mod_idx, func_idx, _)
+                    if is_method =>
+                {
+                    let args = args.into_iter().flatten().collect_vec();
+                    self.push_values(&args, false);
+                    let idx = self.add_instr_with_result(
+                        args.len(),
+                        b::Instr::new(
+                            b::InstrBody::CallMethod(
+                                callee_name.to_string(),
+                                mod_idx,
+                                func_idx,
+                            ),
+                            loc,
+                        ),
+                    );
+                    Value::new(ValueBody::Local(idx), loc)
+                }
+                ValueBody::PartialFunc(mod_idx, func_idx, _) => {
+                    let callee =
+                        Value::new(ValueBody::Func(mod_idx, func_idx), callee.loc);
+                    self.add_call(callee, args.into_iter().flatten(), loc, returning)
+                }
+                _ => self.add_call(callee, args.into_iter().flatten(), loc, returning),
+            }
+        }
+    }
+
+    /// Adds the value of an argument, along with its name and location if it's named.
+    /// The value of a `_` argument is `None`, so its parameter is left open
+    fn add_arg_node(&mut self, node: ts::Node<'t>) -> Arg {
+        let (name, value_node) = if node.kind() == "named_arg" {
+            let name_node = node.required_field("name");
+            let name = name_node.get_text(&self.ctx.source(self.src_idx).content().text);
+            let name_loc = Loc::from_node(self.src_idx, &name_node);
+            (
+                Some((name.to_string(), name_loc)),
+                node.required_field("value"),
+            )
+        } else {
+            (None, node)
+        };
+        let is_placeholder = value_node.kind() == "ident"
+            && value_node.get_text(&self.ctx.source(self.src_idx).content().text) == "_";
+        if is_placeholder {
+            (name, None)
+        } else {
+            (name, Some(self.add_expr_node(value_node, false)))
+        }
+    }
+
+    fn func_params(&self, mod_idx: usize, func_idx: usize) -> Vec<b::Param> {
+        if mod_idx == self.mod_idx {
+            self.module_parser.funcs[func_idx].func.params.clone()
+        } else {
+            self.ctx.lock_modules()[mod_idx].funcs[func_idx]
+                .params
+                .clone()
+        }
+    }
+
+    /// Places the arguments in the order of the function's parameters. Positional
+    /// arguments are placed first, and the parameters left without arguments get their
+    /// default values. Parameters given `_`, left without an argument, or left open by a
+    /// previous partial application, are `None`. Function values only take positional
+    /// arguments, which are kept in order. Returns `None` if the arguments can't be
+    /// matched
+    fn match_args(
+        &mut self,
+        callee: &Value,
+        args: Vec<Arg>,
+        loc: b::Loc,
+    ) -> Option<Vec<Option<Value>>> {
+        let (mod_idx, func_idx, bound) = match &callee.body {
+            ValueBody::Func(mod_idx, func_idx) => (*mod_idx, *func_idx, None),
+            ValueBody::PartialFunc(mod_idx, func_idx, bound) => {
+                (*mod_idx, *func_idx, Some(bound.clone()))
+            }
+            _ => {
+                let mut is_valid = true;
+                for (name, value) in &args {
+                    if let Some((name, name_loc)) = name {
+                        self.ctx.push_error(errors::Error::new(
+                            errors::UnknownArgument::new(name.clone()).into(),
+                            *name_loc,
+                        ));
+                        is_valid = false;
+                    }
+                    if value.is_none() {
+                        self.ctx.push_error(errors::Error::new(
+                            errors::Todo::new(
+                                "partial application of function values".to_string(),
+                            )
+                            .into(),
+                            loc,
+                        ));
+                        is_valid = false;
+                    }
+                }
+                return is_valid
+                    .then(|| args.into_iter().map(|(_, value)| value).collect());
+            }
+        };
+        let params = self.func_params(mod_idx, func_idx);
+        let bound = bound.unwrap_or_else(|| vec![None; params.len()]);
+        let open_params = (0..params.len())
+            .filter(|i| bound[*i].is_none())
+            .collect_vec();
+
+        let positional_total = args.iter().filter(|(name, _)| name.is_none()).count();
+        let mut given: Vec<Option<Option<Value>>> = vec![None; params.len()];
+        let mut positional_count = 0;
+        for (name, value) in args {
+            let (idx, name_loc) = match name {
+                Some((name, name_loc)) => {
+                    match params.iter().position(|param| param.name == name) {
+                        Some(idx) => (idx, name_loc),
+                        None => {
+                            self.ctx.push_error(errors::Error::new(
+                                errors::UnknownArgument::new(name).into(),
+                                name_loc,
+                            ));
+                            continue;
+                        }
+                    }
+                }
+                None if positional_count < open_params.len() => {
+                    positional_count += 1;
+                    let value_loc = value.as_ref().map_or(loc, |value| value.loc);
+                    (open_params[positional_count - 1], value_loc)
+                }
+                None => {
+                    if positional_count == open_params.len() {
+                        positional_count += 1;
+                        self.ctx.push_error(errors::Error::new(
+                            errors::TooManyArguments::new(
+                                open_params.len(),
+                                positional_total,
+                            )
+                            .into(),
+                            value.map_or(loc, |value| value.loc),
+                        ));
+                    }
+                    continue;
+                }
+            };
+            if bound[idx].is_some() || given[idx].is_some() {
+                self.ctx.push_error(errors::Error::new(
+                    errors::DuplicatedArgument::new(params[idx].name.clone()).into(),
+                    name_loc,
+                ));
+                continue;
+            }
+            given[idx] = Some(value);
+        }
+
+        // Functions are curried, so parameters without arguments are left open. Those
+        // with default values are left open as well while the function is partially
+        // applied, so they can still be given when it's called
+        let is_partial = izip!(&bound, &given, &params).any(|(bound, given, param)| {
+            bound.is_none()
+                && match given {
+                    Some(value) => value.is_none(),
+                    None => param.default.is_none(),
+                }
+        });
+        let args = izip!(bound, given, params)
+            .map(
+                |(bound, given, param)| match (bound, given, param.default) {
+                    (Some(value), _, _) => Some(value),
+                    (None, Some(value), _) => value,
+                    (None, None, Some(_)) if is_partial => None,
+                    (None, None, Some(default)) => {
+                        Some(Value::new(ValueBody::Global(mod_idx, default), loc))
+                    }
+                    (None, None, None) => None,
+                },
+            )
+            .collect();
+        Some(args)
+    }
+
+    /// Tail calls replace the frame of the current function by the callee's, which
+    /// needs both of them to use the tail calling convention. Extern functions use the
+    /// system's calling convention instead
+    fn can_tail_call(&self, mod_idx: usize, func_idx: usize) -> bool {
+        let Some(current_idx) = self.func_idx else {
+            return false;
+        };
+        let is_extern = |mod_idx: usize, func_idx: usize| {
+            if mod_idx == self.mod_idx {
+                self.module_parser.funcs[func_idx].func.extn.is_some()
+            } else {
+                self.ctx.lock_modules()[mod_idx].funcs[func_idx]
+                    .extn
+                    .is_some()
+            }
+        };
+        !is_extern(self.mod_idx, current_idx) && !is_extern(mod_idx, func_idx)
+    }
+
     fn add_call(
         &mut self,
         callee: Value,
@@ -414,6 +963,16 @@ impl<'a, 't> ExprParser<'a, 't> {
 
                     self.instrs.push(b::Instr::new(b::InstrBody::Continue, loc));
                     Value::new(ValueBody::Never, loc)
+                } else if returning && self.can_tail_call(mod_idx, func_idx) {
+                    self.push_values(&args, false);
+
+                    self.instrs.push(b::Instr::new(
+                        b::InstrBody::TailCall(mod_idx, func_idx),
+                        loc,
+                    ));
+                    self.stack.pop_many(args.len());
+                    self.stack.get_scope_mut().mark_as_never();
+                    Value::new(ValueBody::Never, loc)
                 } else {
                     self.push_values(&args, false);
 
@@ -425,11 +984,13 @@ impl<'a, 't> ExprParser<'a, 't> {
                 }
             }
             ValueBody::Local(_) | ValueBody::Global(_, _) => {
-                self.ctx.push_error(errors::Error::new(
-                    errors::Todo::new("inderect call".to_string()).into(),
-                    loc,
-                ));
-                callee.with_loc(loc)
+                self.push_values([&callee], false);
+                self.push_values(&args, false);
+                let idx = self.add_instr_with_result(
+                    args.len() + 1,
+                    b::Instr::new(b::InstrBody::CallValue(args.len()), loc),
+                );
+                Value::new(ValueBody::Local(idx), loc)
             }
             ValueBody::CompileError => callee.with_loc(loc),
             _ => {
@@ -439,18 +1000,152 @@ impl<'a, 't> ExprParser<'a, 't> {
         }
     }
 
+    /// Adds the instructions of a conditional. The branches are added by the given
+    /// functions, so only one of them is evaluated at runtime
+    fn add_if(
+        &mut self,
+        cond_value: Value,
+        then_branch: impl FnOnce(&mut Self) -> Value,
+        else_branch: Option<(b::Loc, impl FnOnce(&mut Self) -> Value)>,
+        loc: b::Loc,
+    ) -> Value {
+        self.push_values([&cond_value], false);
+        self.stack.pop(); // consume condition
+
+        let stack_len = self.stack.len();
+        let block_len = self.stack.scope_len();
+
+        self.stack
+            .create_scope(ScopePayload::new(self.idents.clone()));
+
+        self.instrs
+            .push(b::Instr::new(b::InstrBody::If(b::Type::unknown(None)), loc));
+        let then_value = then_branch(self);
+
+        if !then_value.is_never() {
+            self.push_values([&then_value], true);
+            assert!(self.stack.len() > stack_len);
+        }
+
+        let else_value = if let Some((else_loc, else_branch)) = else_branch {
+            assert!(self.stack.scope_len() > block_len);
+            self.instrs
+                .push(b::Instr::new(b::InstrBody::Else, else_loc));
+
+            let (scope, _) = self.stack.branch_scope();
+            self.idents = scope.payload.idents.clone();
+
+            let else_value = else_branch(self);
+
+            if !else_value.is_never() {
+                self.push_values([&else_value], true);
+                assert!(self.stack.len() > stack_len);
+            }
+
+            else_value
+        } else {
+            self.ctx.push_error(errors::Error::new(
+                errors::Todo::new("if without else".to_string()).into(),
+                loc,
+            ));
+            Value::new(ValueBody::CompileError, loc)
+        };
+
+        assert!(self.stack.scope_len() > block_len);
+        let (scope, _) = self.stack.end_scope();
+        self.idents = scope.payload.idents;
+
+        if !then_value.is_never() || !else_value.is_never() {
+            let idx =
+                self.add_instr_with_result(0, b::Instr::new(b::InstrBody::End, loc));
+            Value::new(ValueBody::Local(idx), loc)
+        } else {
+            self.instrs.push(b::Instr::new(b::InstrBody::End, loc));
+            Value::new(ValueBody::Never, loc)
+        }
+    }
+
+    /// Adds the instructions to exit the program with the message, which never results
+    /// in a value
+    fn add_panic(&mut self, message: Value, loc: b::Loc) -> Value {
+        self.push_values([&message], false);
+        self.instrs.push(b::Instr::new(b::InstrBody::Panic, loc));
+        self.stack.pop();
+        self.stack.get_scope_mut().mark_as_never();
+        Value::new(ValueBody::Never, loc)
+    }
+
+    /// Content of an argument that should be a string literal, since its value is needed
+    /// at compile time. Reports an error if it's anything else
+    fn string_lit_arg(&self, node: ts::Node<'t>) -> Option<String> {
+        if node.kind() != "string_lit" {
+            self.ctx.push_error(errors::Error::new(
+                errors::ErrorDetail::ExpectedStringLiteral,
+                Loc::from_node(self.src_idx, &node),
+            ));
+            return None;
+        }
+        Some(utils::decode_string_lit(
+            node.required_field("content")
+                .get_text(&self.ctx.source(self.src_idx).content().text),
+        ))
+    }
+
+    /// Whether the macro is given from `min` to `max` arguments. Reports an error at the
+    /// macro otherwise
+    fn check_macro_args(
+        &self,
+        name: &str,
+        args: &[ts::Node<'t>],
+        min: usize,
+        max: usize,
+        loc: b::Loc,
+    ) -> bool {
+        if (min..=max).contains(&args.len()) {
+            return true;
+        }
+        self.ctx.push_error(errors::Error::new(
+            errors::WrongMacroArgCount::new(name.to_string(), min, max, args.len())
+                .into(),
+            loc,
+        ));
+        false
+    }
+
     fn add_macro(&mut self, name: &str, args: &[ts::Node<'t>], loc: b::Loc) -> Value {
         match name {
-            "str_len" | "array_len" => {
+            "str_len" | "array_len" | "str_from_ptr" | "str_alloc" | "ptr_is_null"
+            | "ptr_load" | "env_var" | "is_some" | "unwrap" | "is_ok" | "unwrap_ok"
+            | "unwrap_err" => {
                 // TODO: better error handling
                 assert!(args.len() == 1, "@{name}() expects a single argument");
 
                 let source = self.add_expr_node(args[0], false);
                 self.push_values(&[source], false);
 
+                let result_case = |case_name| self.result_case(case_name);
                 let instr_body = match name {
                     "str_len" => b::InstrBody::StrLen,
                     "array_len" => b::InstrBody::ArrayLen,
+                    "str_from_ptr" => b::InstrBody::StrFromPtr,
+                    "str_alloc" => b::InstrBody::StrAlloc,
+                    "ptr_is_null" => b::InstrBody::PtrIsNull,
+                    "ptr_load" => b::InstrBody::PtrLoad,
+                    "env_var" => b::InstrBody::EnvVar,
+                    "is_some" => b::InstrBody::OptionIsSome,
+                    "unwrap" => b::InstrBody::OptionUnwrap,
+                    "is_ok" => {
+                        let (ty, case) = result_case("ok");
+                        b::InstrBody::VariantIs(ty, case)
+                    }
+                    "unwrap_ok" => {
+                        let (ty, case) = result_case("ok");
+                        b::InstrBody::VariantUnwrap(ty, case)
+                    }
+                    "unwrap_err" => {
+                        let (ty, case) = result_case("err");
+                        b::InstrBody::VariantUnwrap(ty, case)
+                    }
                     _ => unreachable!(),
                 };
 
@@ -482,6 +1177,223 @@ impl<'a, 't> ExprParser<'a, 't> {
                 let idx = self.add_instr_with_result(1, b::Instr::new(instr_body, loc));
                 Value::new(ValueBody::Local(idx), loc)
             }
+            "array_from_fn" => {
+                // TODO: better error handling
+                assert!(args.len() == 2, "@{name}() expects 2 arguments");
+
+                let len = self.add_expr_node(args[0], false);
+
+                let (mod_idx, func_idx) = match self.add_expr_node(args[1], false).body {
+                    ValueBody::Func(mod_idx, func_idx) => (mod_idx, func_idx),
+                    // Already reported
+                    ValueBody::CompileError => {
+                        return Value::new(ValueBody::CompileError, loc);
+                    }
+                    _ => {
+                        self.ctx.push_error(errors::Error::new(
+                            errors::ErrorDetail::ExpectedFunction,
+                            Loc::from_node(self.src_idx, &args[1]),
+                        ));
+                        return Value::new(ValueBody::CompileError, loc);
+                    }
+                };
+
+                self.push_values([&len], false);
+
+                let idx = self.add_instr_with_result(
+                    1,
+                    b::Instr::new(b::InstrBody::ArrayFromFn(mod_idx, func_idx), loc),
+                );
+                Value::new(ValueBody::Local(idx), loc)
+            }
+            "cast" => {
+                // TODO: better error handling
+                assert!(args.len() == 2, "@{name}() expects 2 arguments");
+
+                // Only meant for the helpers of the core library, like the ones called
+                // by derived functions
+                if self
+                    .ctx
+                    .core_mod_idx()
+                    .is_some_and(|core_mod_idx| core_mod_idx != self.mod_idx)
+                {
+                    self.ctx.push_error(errors::Error::new(
+                        errors::CoreOnlyMacro::new(name.to_string()).into(),
+                        loc,
+                    ));
+                    return Value::new(ValueBody::CompileError, loc);
+                }
+
+                let value = self.add_expr_node(args[0], false);
+                let ty = self.module_parser.types.parse_type(args[1]);
+                if !ty.is_number() {
+                    self.ctx.push_error(errors::Error::new(
+                        errors::UnexpectedType::new(
+                            b::Type::new(b::TypeBody::AnyNumber, None),
+                            ty.clone(),
+                        )
+                        .into(),
+                        Loc::from_node(self.src_idx, &args[1]),
+                    ));
+                }
+                self.push_values([&value], false);
+
+                let idx = self
+                    .add_instr_with_result(1, b::Instr::new(b::InstrBody::Cast(ty), loc));
+                Value::new(ValueBody::Local(idx), loc)
+            }
+            "dbg" => {
+                if !self.check_macro_args(name, args, 1, 1, loc) {
+                    return Value::new(ValueBody::CompileError, loc);
+                }
+
+                let value = self.add_expr_node(args[0], false);
+                self.push_values([&value], false);
+
+                let label = args[0]
+                    .get_text(&self.ctx.source(self.src_idx).content().text)
+                    .to_string();
+                let instr =
+                    b::Instr::new(b::InstrBody::Dbg(b::Type::unknown(None), label), loc);
+                let idx = self.add_instr_with_result(1, instr);
+                Value::new(ValueBody::Local(idx), loc)
+            }
+            "panic" => {
+                if !self.check_macro_args(name, args, 1, 1, loc) {
+                    return Value::new(ValueBody::CompileError, loc);
+                }
+
+                let message = self.add_expr_node(args[0], false);
+                self.add_panic(message, loc)
+            }
+            "assert" => {
+                if !self.check_macro_args(name, args, 1, 2, loc) {
+                    return Value::new(ValueBody::CompileError, loc);
+                }
+
+                let cond = self.add_expr_node(args[0], false);
+                let message_node = args.get(1).copied();
+                let cond_text = args[0]
+                    .get_text(&self.ctx.source(self.src_idx).content().text)
+                    .to_string();
+                // The assertion results in `true`, since there's no unit type yet
+                self.add_if(
+                    cond,
+                    |_| Value::new(ValueBody::Bool(true), loc),
+                    Some((loc, |this: &mut Self| {
+                        let message = match message_node {
+                            Some(node) => this.add_expr_node(node, false),
+                            None => {
+                                let idx = this.add_instr_with_result(
+                                    0,
+                                    b::Instr::new(
+                                        b::InstrBody::CreateString(format!(
+                                            "assertion failed: {cond_text}"
+                                        )),
+                                        loc,
+                                    ),
+                                );
+                                Value::new(ValueBody::Local(idx), loc)
+                            }
+                        };
+                        this.add_panic(message, loc)
+                    })),
+                    loc,
+                )
+            }
+            "static_assert" => {
+                if !self.check_macro_args(name, args, 1, 2, loc) {
+                    return Value::new(ValueBody::CompileError, loc);
+                }
+
+                // The instructions of the condition are counted, so it can be evaluated
+                // on its own after the types are known
+                let cond_start = self.instrs.len();
+                let cond = self.add_expr_node(args[0], false);
+                self.push_values([&cond], false);
+                let cond_len = self.instrs.len() - cond_start;
+
+                let message = match args.get(1) {
+                    Some(node) => self.string_lit_arg(*node).unwrap_or_default(),
+                    None => format!(
+                        "`{}`",
+                        args[0].get_text(&self.ctx.source(self.src_idx).content().text)
+                    ),
+                };
+
+                let idx = self.add_instr_with_result(
+                    1,
+                    b::Instr::new(b::InstrBody::StaticAssert(message, cond_len), loc),
+                );
+                Value::new(ValueBody::Local(idx), loc)
+            }
+            "embed_file" | "embed_bytes" => {
+                if !self.check_macro_args(name, args, 1, 1, loc) {
+                    return Value::new(ValueBody::CompileError, loc);
+                }
+
+                let Some(path) = self.string_lit_arg(args[0]) else {
+                    return Value::new(ValueBody::CompileError, loc);
+                };
+                let path_loc = Loc::from_node(self.src_idx, &args[0]);
+                let bytes = match self.ctx.read_relative(self.src_idx, &path) {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        self.ctx.push_error(errors::Error::new(
+                            errors::CannotReadFile::new(path, err.to_string()).into(),
+                            path_loc,
+                        ));
+                        return Value::new(ValueBody::CompileError, loc);
+                    }
+                };
+
+                if name == "embed_bytes" {
+                    let idx = self.add_instr_with_result(
+                        0,
+                        b::Instr::new(b::InstrBody::CreateBytes(bytes), loc),
+                    );
+                    return Value::new(ValueBody::Local(idx), loc);
+                }
+
+                let Ok(content) = String::from_utf8(bytes) else {
+                    self.ctx.push_error(errors::Error::new(
+                        errors::InvalidUtf8File::new(path).into(),
+                        path_loc,
+                    ));
+                    return Value::new(ValueBody::CompileError, loc);
+                };
+                let idx = self.add_instr_with_result(
+                    0,
+                    b::Instr::new(b::InstrBody::CreateString(content), loc),
+                );
+                Value::new(ValueBody::Local(idx), loc)
+            }
+            "sizeof" | "alignof" => {
+                if !self.check_macro_args(name, args, 1, 1, loc) {
+                    return Value::new(ValueBody::CompileError, loc);
+                }
+
+                let ty = self.module_parser.types.parse_type(args[0]);
+                let instr_body = match name {
+                    "sizeof" => b::InstrBody::SizeOf(ty),
+                    "alignof" => b::InstrBody::AlignOf(ty),
+                    _ => unreachable!(),
+                };
+                let idx = self.add_instr_with_result(0, b::Instr::new(instr_body, loc));
+                Value::new(ValueBody::Local(idx), loc)
+            }
+            "ptr_add" => {
+                // TODO: better error handling
+                assert!(args.len() == 2, "@{name}() expects 2 arguments");
+
+                let ptr = self.add_expr_node(args[0], false);
+                let offset = self.add_expr_node(args[1], false);
+                self.push_values([&ptr, &offset], false);
+
+                let idx = self
+                    .add_instr_with_result(2, b::Instr::new(b::InstrBody::PtrAdd, loc));
+                Value::new(ValueBody::Local(idx), loc)
+            }
             _ => {
                 panic!("unhandled macro: `{name}`")
             }
@@ -491,7 +1403,17 @@ impl<'a, 't> ExprParser<'a, 't> {
     fn add_stmt_node(&mut self, node: ts::Node<'t>) {
         match node.kind() {
             "let_stmt" => {
-                let value = self.add_expr_node(node.required_field("value"), false);
+                let mut value = self.add_expr_node(node.required_field("value"), false);
+                if let Some(type_node) = node.field("type") {
+                    let ty = self.module_parser.types.parse_type(type_node);
+                    self.push_values([&value], true);
+                    let idx = self.stack.len() - 1;
+                    self.instrs.push(b::Instr::new(
+                        b::InstrBody::Annotate(ty),
+                        Loc::from_node(self.src_idx, &type_node),
+                    ));
+                    value = Value::new(ValueBody::Local(idx), value.loc);
+                }
                 let pat_node = node.required_field("pat");
                 match pat_node.kind() {
                     "ident" => {
@@ -505,6 +1427,53 @@ impl<'a, 't> ExprParser<'a, 't> {
                     kind => panic!("Found unexpected pattern `{kind}`"),
                 }
             }
+            "expr_stmt" => {
+                let value_node = node.required_field("value");
+                let value = self.add_expr_node(value_node, false);
+                match &value.body {
+                    // Whether the value is unit is only known after type checking
+                    ValueBody::Local(_)
+                    | ValueBody::Global(..)
+                    | ValueBody::Bool(_)
+                    | ValueBody::Number(_) => {
+                        self.push_values([&value], true);
+                        self.instrs.push(b::Instr::new(
+                            b::InstrBody::Unused,
+                            Loc::from_node(self.src_idx, &value_node),
+                        ));
+                    }
+                    ValueBody::Func(..)
+                    | ValueBody::PartialFunc(..)
+                    | ValueBody::Variant(..) => {
+                        self.ctx.push_warning(errors::Warning::new(
+                            errors::WarningDetail::DiscardedValue,
+                            Loc::from_node(self.src_idx, &value_node),
+                        ));
+                    }
+                    ValueBody::Captured | ValueBody::Never | ValueBody::CompileError => {}
+                }
+            }
+            "let_func_stmt" => {
+                let ident = node
+                    .required_field("name")
+                    .of_kind("ident")
+                    .get_text(&self.ctx.source(self.src_idx).content().text);
+                // Local functions are lifted to the module, so they can't capture the
+                // local values of the enclosing function. These are still kept, so
+                // using them is reported instead of resolving to some other value
+                let idents = self
+                    .idents
+                    .iter()
+                    .map(|(name, value)| match value.body {
+                        ValueBody::Local(_) => {
+                            (name.clone(), Value::new(ValueBody::Captured, value.loc))
+                        }
+                        _ => (name.clone(), value.clone()),
+                    })
+                    .collect();
+                let value = self.module_parser.declare_func(ident, node, Some(idents));
+                self.idents.insert(ident.to_string(), value);
+            }
             kind => panic!("Found unexpected statement `{kind}`"),
         }
     }
@@ -1,3 +1,4 @@
+mod derive;
 mod expr_parser;
 mod module_parser;
 mod parser_value;
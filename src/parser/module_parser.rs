@@ -1,15 +1,16 @@
 use std::collections::HashMap;
 
 use derive_new::new;
-use itertools::enumerate;
+use itertools::{enumerate, izip, Itertools};
 use tree_sitter as ts;
 
+use super::derive::{Derive, DeriveBuilder};
 use super::parser_value::Value;
 use super::type_parser::TypeParser;
 use crate::parser::expr_parser::ExprParser;
 use crate::parser::parser_value::ValueBody;
 use crate::utils::TreeSitterUtils;
-use crate::{bytecode as b, context, utils};
+use crate::{bytecode as b, context, errors, utils};
 
 #[derive(new)]
 pub struct ModuleParser<'a, 't> {
@@ -31,53 +32,164 @@ impl<'a, 't> ModuleParser<'a, 't> {
         let src_idx = self.src_idx;
         let mod_idx = self.mod_idx;
 
-        for i in 0..self.globals.len() {
-            let value_node = self.globals[i].value_node.clone();
+        // Local functions are declared while parsing the bodies, so they are added to the
+        // end of the list, along with the globals holding the default values of their
+        // parameters, and parsed in the same loop
+        let mut globals_count = 0;
+        let mut funcs_count = 0;
+        while globals_count < self.globals.len() || funcs_count < self.funcs.len() {
+            while globals_count < self.globals.len() {
+                let i = globals_count;
+                let value_node = self.globals[i].value_node;
 
-            let mut value_parser =
-                ExprParser::new(self.ctx, self, src_idx, mod_idx, None, []);
+                let mut value_parser =
+                    ExprParser::new(self.ctx, self, src_idx, mod_idx, None, []);
 
-            let value = value_parser.add_expr_node(value_node, true);
-            value_parser.push_values([&value], true);
+                let value = value_parser.add_expr_node(value_node, true);
+                value_parser.push_values([&value], true);
 
-            (self, self.globals[i].global.body) = value_parser.finish();
-        }
+                (self, self.globals[i].global.body) = value_parser.finish();
+                globals_count += 1;
+            }
 
-        for i in 0..self.funcs.len() {
-            let Some(value_node) = self.funcs[i].value_node else {
-                continue;
-            };
-            let params_names = self.funcs[i].params_names.clone();
+            while funcs_count < self.funcs.len() {
+                let i = funcs_count;
+                funcs_count += 1;
+                let Some(value_node) = self.funcs[i].value_node else {
+                    continue;
+                };
+                let params_names = self.funcs[i].params_names.clone();
 
-            let mut value_parser =
-                ExprParser::new(self.ctx, self, src_idx, mod_idx, Some(i), params_names);
+                let mut value_parser = ExprParser::new(
+                    self.ctx,
+                    self,
+                    src_idx,
+                    mod_idx,
+                    Some(i),
+                    params_names,
+                );
 
-            let value = value_parser.add_expr_node(value_node, true);
-            value_parser.push_values([&value], true);
+                let value = value_parser.add_expr_node(value_node, true);
+                if !value.is_never() {
+                    value_parser.push_values([&value], true);
+                }
 
-            (self, self.funcs[i].func.body) = value_parser.finish();
+                (self, self.funcs[i].func.body) = value_parser.finish();
+            }
+        }
+
+        {
+            let module = &mut self.ctx.lock_modules_mut()[self.mod_idx];
+            module.typedefs = self.types.typedefs;
+            module.globals = self.globals.into_iter().map(|x| x.global).collect();
+            module.funcs = self.funcs.into_iter().map(|x| x.func).collect();
         }
 
-        let module = &mut self.ctx.lock_modules_mut()[self.mod_idx];
-        module.typedefs = self.types.typedefs;
-        module.globals = self.globals.into_iter().map(|x| x.global).collect();
-        module.funcs = self.funcs.into_iter().map(|x| x.func).collect();
+        check_globals_cycles(self.ctx, mod_idx);
     }
     pub fn add_root(&mut self, node: ts::Node<'t>) {
         node.of_kind("root");
 
-        for sym_node in node.iter_children() {
+        // Declarations disabled by `@cfg` are skipped before anything is declared, so
+        // they can't be referred to and others can be declared with the same name
+        let sym_nodes = node
+            .iter_children()
+            .filter(|sym_node| self.cfg_enabled(*sym_node))
+            .collect_vec();
+
+        // Types are declared first, so they can be used anywhere in the module, even in
+        // their own declaration
+        for &sym_node in &sym_nodes {
+            if sym_node.kind() == "type_decl" {
+                let ident = sym_node
+                    .required_field("name")
+                    .of_kind("ident")
+                    .get_text(&self.ctx.source(self.src_idx).content().text);
+                self.types.declare_type(ident, sym_node);
+            }
+        }
+
+        let mut derives = vec![];
+        for &sym_node in &sym_nodes {
             let ident_node = sym_node.required_field("name").of_kind("ident");
             let ident =
                 ident_node.get_text(&self.ctx.source(self.src_idx).content().text);
 
             match sym_node.kind() {
-                "type_decl" => self.types.add_type(ident, sym_node),
+                "type_decl" => {
+                    let idx = self.types.add_type(sym_node);
+                    let type_derives = self.type_derives(sym_node);
+                    if let b::TypeDefBody::Variant(v) = &self.types.typedefs[idx].body {
+                        for (k, case) in enumerate(&v.cases) {
+                            let value = Value::new(
+                                ValueBody::Variant(self.mod_idx, idx, k),
+                                case.loc,
+                            );
+                            self.idents.insert(case.name.clone(), value);
+                        }
+                        for (_, loc) in type_derives {
+                            self.ctx.push_error(errors::Error::new(
+                                errors::Todo::new(
+                                    "deriving functions for variant types".to_string(),
+                                )
+                                .into(),
+                                loc,
+                            ));
+                        }
+                        continue;
+                    }
+                    derives.extend(
+                        type_derives
+                            .into_iter()
+                            .map(|(derive, loc)| (idx, derive, loc)),
+                    );
+                }
                 "func_decl" => self.add_func(ident, sym_node),
                 "global_decl" => self.add_global(ident, sym_node),
                 _ => panic!("Unexpected symbol kind: {}", sym_node.kind()),
             }
         }
+
+        self.types.check_infinite_size();
+        self.add_derived_funcs(derives);
+    }
+    /// Whether every option of the `@cfg` directives of the declaration is enabled.
+    /// Options are either a key, like `debug`, or a key and a value, like
+    /// `target_os = "linux"`
+    fn cfg_enabled(&self, node: ts::Node<'t>) -> bool {
+        let text = &self.ctx.source(self.src_idx).content().text;
+        let mut enabled = true;
+        for directive_node in node.iter_field("directives") {
+            if directive_node.required_field("name").get_text(text) != "cfg" {
+                continue;
+            }
+
+            for arg_node in directive_node.iter_field("args") {
+                let option = match arg_node.kind() {
+                    "ident" => Some((arg_node.get_text(text), None)),
+                    "directive_pair" => {
+                        let value_node = arg_node.required_field("value");
+                        (value_node.kind() == "string_lit").then(|| {
+                            let value = utils::decode_string_lit(
+                                value_node.required_field("content").get_text(text),
+                            );
+                            (arg_node.required_field("name").get_text(text), Some(value))
+                        })
+                    }
+                    _ => None,
+                };
+                match option {
+                    Some((key, value)) => {
+                        enabled &= self.ctx.cfg_enabled(key, value.as_deref());
+                    }
+                    None => self.ctx.push_error(errors::Error::new(
+                        errors::ErrorDetail::InvalidCfgOption,
+                        b::Loc::from_node(self.src_idx, &arg_node),
+                    )),
+                }
+            }
+        }
+        enabled
     }
     pub fn open_module(&mut self, mod_idx: usize) {
         let module = &self.ctx.lock_modules()[mod_idx];
@@ -86,8 +198,15 @@ impl<'a, 't> ModuleParser<'a, 't> {
             if item.name.starts_with('_') {
                 continue;
             }
-            let ty = b::TypeBody::TypeRef(mod_idx, i);
+            let ty = item.type_ref(mod_idx, i);
             self.types.idents.insert(item.name.clone(), ty);
+
+            if let b::TypeDefBody::Variant(v) = &item.body {
+                for (k, case) in enumerate(&v.cases) {
+                    let value = Value::new(ValueBody::Variant(mod_idx, i, k), case.loc);
+                    self.idents.insert(case.name.clone(), value);
+                }
+            }
         }
 
         for (i, item) in enumerate(&module.funcs) {
@@ -118,8 +237,20 @@ impl<'a, 't> ModuleParser<'a, 't> {
         }
     }
 
-    fn add_func(&mut self, name: &'a str, node: ts::Node<'t>) {
-        assert_eq!(node.kind(), "func_decl");
+    fn add_func(&mut self, name: &str, node: ts::Node<'t>) {
+        let value = self.declare_func(name, node, None);
+        self.idents.insert(name.to_string(), value);
+    }
+    /// Declares a function without adding it to the module's scope. `idents` are the
+    /// values visible inside a local function, while other functions see the module's
+    /// scope
+    pub fn declare_func(
+        &mut self,
+        name: &str,
+        node: ts::Node<'t>,
+        idents: Option<HashMap<String, Value>>,
+    ) -> Value {
+        assert!(matches!(node.kind(), "func_decl" | "let_func_stmt"));
 
         let (params, params_names): (Vec<_>, Vec<_>) = node
             .iter_field("params")
@@ -133,9 +264,27 @@ impl<'a, 't> ModuleParser<'a, 't> {
                     None => b::Type::unknown(None),
                 };
 
+                // Default values are evaluated once, like any other global, and are
+                // private to the module, even though they are used by the callers
+                let default = param_node.field("default").map(|default_node| {
+                    self.globals.push(DeclaredGlobal {
+                        global: b::Global {
+                            name: format!("_{name}_{param_name}"),
+                            ty: param_ty.clone(),
+                            body: vec![],
+                            is_entry_point: false,
+                            loc: b::Loc::from_node(self.src_idx, &default_node),
+                        },
+                        value_node: default_node,
+                    });
+                    self.globals.len() - 1
+                });
+
                 (
                     b::Param {
+                        name: param_name.to_string(),
                         ty: param_ty,
+                        default,
                         loc: b::Loc::from_node(self.src_idx, &param_node),
                     },
                     (
@@ -170,30 +319,138 @@ impl<'a, 't> ModuleParser<'a, 't> {
                     );
                     extn = Some(b::Extern { name: symbol_name });
                 }
-                _ => todo!(),
+                // Already checked when the declarations of the module were added
+                "cfg" => {}
+                name => {
+                    self.ctx.push_error(errors::Error::new(
+                        errors::UnexpectedDirective::new(name.to_string()).into(),
+                        b::Loc::from_node(self.src_idx, &directive_node),
+                    ));
+                }
             }
         }
 
+        // Local functions are private to the module, even though they are lifted to it
+        let is_local = idents.is_some();
         let func = b::Func {
-            name: name.to_string(),
+            name: if is_local {
+                format!("_{name}")
+            } else {
+                name.to_string()
+            },
             params,
             ret: ret_ty,
             extn,
             body: vec![],
+            is_entry_point: !is_local && name == "main",
             loc: b::Loc::from_node(self.src_idx, &node),
         };
-        self.idents.insert(
-            func.name.clone(),
-            Value::new(
-                ValueBody::Func(self.mod_idx, self.funcs.len()),
-                b::Loc::from_node(self.src_idx, &node),
-            ),
+        let value = Value::new(
+            ValueBody::Func(self.mod_idx, self.funcs.len()),
+            b::Loc::from_node(self.src_idx, &node),
         );
+        // The function is visible inside itself, so it can be recursive
+        let idents = idents.map(|mut idents| {
+            idents.insert(name.to_string(), value.clone());
+            idents
+        });
         self.funcs.push(DeclaredFunc {
             func,
             value_node: node.field("return"),
             params_names,
+            idents,
         });
+        value
+    }
+    /// Functions requested by the `@derive` directives of a type declaration
+    fn type_derives(&self, node: ts::Node<'t>) -> Vec<(Derive, b::Loc)> {
+        let mut derives: Vec<(Derive, b::Loc)> = vec![];
+        for directive_node in node.iter_field("directives") {
+            let name = directive_node
+                .required_field("name")
+                .get_text(&self.ctx.source(self.src_idx).content().text);
+            if name == "cfg" {
+                continue;
+            }
+            if name != "derive" {
+                self.ctx.push_error(errors::Error::new(
+                    errors::UnexpectedDirective::new(name.to_string()).into(),
+                    b::Loc::from_node(self.src_idx, &directive_node),
+                ));
+                continue;
+            }
+
+            for arg_node in directive_node.iter_field("args") {
+                let arg =
+                    arg_node.get_text(&self.ctx.source(self.src_idx).content().text);
+                let loc = b::Loc::from_node(self.src_idx, &arg_node);
+                match Derive::from_name(arg) {
+                    Some(derive) if derives.iter().any(|(d, _)| *d == derive) => {}
+                    Some(derive) => derives.push((derive, loc)),
+                    None => self.ctx.push_error(errors::Error::new(
+                        errors::UnknownDerive::new(arg.to_string()).into(),
+                        loc,
+                    )),
+                }
+            }
+        }
+        derives
+    }
+    /// Declares the derived functions and builds their bodies. All of them are declared
+    /// first, so records can use the functions derived for the types of their fields
+    fn add_derived_funcs(&mut self, derives: Vec<(usize, Derive, b::Loc)>) {
+        let mut derived = HashMap::new();
+        for (typedef_idx, derive, loc) in &derives {
+            let type_name = &self.types.typedefs[*typedef_idx].name;
+            let name = derive.func_name(type_name);
+            let params = derive
+                .params_names()
+                .iter()
+                .map(|param_name| b::Param {
+                    name: param_name.to_string(),
+                    ty: b::Type::new(
+                        b::TypeBody::TypeRef(self.mod_idx, *typedef_idx),
+                        None,
+                    ),
+                    default: None,
+                    loc: *loc,
+                })
+                .collect();
+
+            derived.insert((*typedef_idx, *derive), self.funcs.len());
+            self.idents.insert(
+                name.clone(),
+                Value::new(ValueBody::Func(self.mod_idx, self.funcs.len()), *loc),
+            );
+            self.funcs.push(DeclaredFunc {
+                func: b::Func {
+                    name,
+                    params,
+                    ret: derive.ret(),
+                    extn: None,
+                    body: vec![],
+                    is_entry_point: false,
+                    loc: *loc,
+                },
+                value_node: None,
+                params_names: vec![],
+                idents: None,
+            });
+        }
+
+        let builder =
+            DeriveBuilder::new(self.ctx, self.mod_idx, &self.types.typedefs, &derived);
+        let bodies = derives
+            .iter()
+            .map(|(typedef_idx, derive, loc)| builder.build(*typedef_idx, *derive, *loc))
+            .collect_vec();
+        for ((typedef_idx, derive, _), body) in izip!(derives, bodies) {
+            self.funcs[derived[&(typedef_idx, derive)]].func.body = body;
+        }
+    }
+    /// Values visible inside the body of the function
+    pub fn func_idents(&self, idx: usize) -> &HashMap<String, Value> {
+        self.funcs[idx].idents.as_ref().unwrap_or(&self.idents)
     }
     fn add_global(&mut self, name: &'a str, node: ts::Node<'t>) {
         assert_eq!(node.kind(), "global_decl");
@@ -203,6 +460,18 @@ impl<'a, 't> ModuleParser<'a, 't> {
             None => b::Type::unknown(None),
         };
 
+        for directive_node in node.iter_field("directives") {
+            let name = directive_node
+                .required_field("name")
+                .get_text(&self.ctx.source(self.src_idx).content().text);
+            if name != "cfg" {
+                self.ctx.push_error(errors::Error::new(
+                    errors::UnexpectedDirective::new(name.to_string()).into(),
+                    b::Loc::from_node(self.src_idx, &directive_node),
+                ));
+            }
+        }
+
         let global = b::Global {
             name: name.to_string(),
             ty,
@@ -228,9 +497,32 @@ pub struct DeclaredFunc<'t> {
     pub func: b::Func,
     value_node: Option<ts::Node<'t>>,
     params_names: Vec<(String, b::Loc)>,
+    idents: Option<HashMap<String, Value>>,
 }
 
 pub struct DeclaredGlobal<'t> {
     pub global: b::Global,
     value_node: ts::Node<'t>,
 }
+
+/// Reports the first globals that depend on each other, since there's no order in which
+/// they could be initialized. Globals of other modules can't depend on this one's, so
+/// only this module is checked
+fn check_globals_cycles(ctx: &context::BuildContext, mod_idx: usize) {
+    let modules = ctx.lock_modules();
+    let globals = (0..modules[mod_idx].globals.len()).map(|idx| (mod_idx, idx));
+    let Err(cycle) = b::sort_globals(&modules, globals) else {
+        return;
+    };
+
+    let names = cycle
+        .globals
+        .iter()
+        .map(|(mod_idx, idx)| modules[*mod_idx].globals[*idx].name.clone())
+        .collect();
+    let (mod_idx, idx) = cycle.globals[0];
+    ctx.push_error(errors::Error::new(
+        errors::CyclicGlobals::new(names).into(),
+        modules[mod_idx].globals[idx].loc,
+    ));
+}
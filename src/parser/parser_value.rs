@@ -5,8 +5,17 @@ use crate::bytecode as b;
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum ValueBody {
     Func(usize, usize),
+    /// A function with some of its arguments already given, in the order of its
+    /// parameters. Calling it passes the new arguments to the parameters that are `None`
+    PartialFunc(usize, usize, Vec<Option<Value>>),
     Global(usize, usize),
+    /// Case of a variant type, by the module and index of the type and its own index.
+    /// Calling it creates a value of the type
+    Variant(usize, usize, usize),
     Local(usize),
+    /// Local value of the function enclosing a local function, which the local
+    /// function can't use since it's lifted to the module
+    Captured,
     Bool(bool),
     Number(String),
     Never,
@@ -1,11 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use derive_new::new;
-use itertools::Itertools;
+use itertools::{enumerate, Itertools};
 use tree_sitter as ts;
 
 use crate::utils::{IntoItem, TreeSitterUtils};
-use crate::{bytecode as b, context, errors};
+use crate::{bytecode as b, context, errors, utils};
 
 #[derive(new)]
 pub struct TypeParser<'a> {
@@ -13,6 +13,9 @@ pub struct TypeParser<'a> {
     pub typedefs: Vec<b::TypeDef>,
     #[new(value = "default_idents()")]
     pub idents: HashMap<String, b::TypeBody>,
+    /// Parameters of the variant type being parsed
+    #[new(default)]
+    params: Vec<String>,
     ctx: &'a context::BuildContext,
     src_idx: usize,
     mod_idx: usize,
@@ -23,6 +26,12 @@ impl<'a> TypeParser<'a> {
         let body = match node.kind() {
             "ident" => {
                 let ident = node.get_text(&self.ctx.source(self.src_idx).content().text);
+                if let Some(idx) = self.params.iter().position(|param| param == ident) {
+                    return b::Type::new(
+                        b::TypeBody::Param(idx),
+                        Some(b::Loc::from_node(self.src_idx, &node)),
+                    );
+                }
                 match self.idents.get(ident) {
                     Some(body) => body.clone(),
                     None => {
@@ -60,18 +69,110 @@ impl<'a> TypeParser<'a> {
                         assert!(args.len() == 1, "Ptr accepts only one parameter");
                         b::TypeBody::Ptr(args.into_item(0).unwrap().into())
                     }
-                    _ => panic!("unhandled generic type: `{name}`"),
+                    "Option" => {
+                        // TODO: Better error handling
+                        assert!(args.len() == 1, "Option accepts only one parameter");
+                        b::TypeBody::Option(args.into_item(0).unwrap().into())
+                    }
+                    _ => match self.idents.get(name) {
+                        Some(b::TypeBody::Variant(mod_idx, ty_idx, params)) => {
+                            // TODO: Better error handling
+                            assert!(
+                                args.len() == params.len(),
+                                "{name} accepts {} parameters",
+                                params.len()
+                            );
+                            b::TypeBody::Variant(*mod_idx, *ty_idx, args)
+                        }
+                        _ => panic!("unhandled generic type: `{name}`"),
+                    },
                 }
             }
+            "func_type" => {
+                let params = node
+                    .iter_field("params")
+                    .map(|param_node| self.parse_type(param_node))
+                    .collect_vec();
+                let ret = self.parse_type(node.required_field("ret_type"));
+                b::TypeBody::Func(b::FuncType::new(params, ret).into())
+            }
             k => panic!("Unhandled type node `{k}`"),
         };
         b::Type::new(body, Some(b::Loc::from_node(self.src_idx, &node)))
     }
 
-    pub fn add_type<'t>(&mut self, name: &'a str, node: ts::Node<'t>) {
+    /// Makes the type available by its name before its body is parsed, so it can be used
+    /// by the types declared before it and by itself
+    pub fn declare_type<'t>(&mut self, name: &'a str, node: ts::Node<'t>) {
         assert_eq!(node.kind(), "type_decl");
 
+        let body = match node.required_field("body").kind() {
+            "variant_type" => {
+                let params = node
+                    .iter_field("params")
+                    .map(|param_node| {
+                        param_node
+                            .get_text(&self.ctx.source(self.src_idx).content().text)
+                            .to_string()
+                    })
+                    .collect();
+                b::TypeDefBody::Variant(b::VariantType::new(params, vec![]))
+            }
+            _ => b::TypeDefBody::Record(b::RecordType {
+                fields: utils::SortedMap::new(),
+            }),
+        };
+        let value = b::TypeDef {
+            name: name.to_string(),
+            body,
+            loc: b::Loc::from_node(self.src_idx, &node),
+        };
+        self.idents.insert(
+            value.name.clone(),
+            value.type_ref(self.mod_idx, self.typedefs.len()),
+        );
+        self.typedefs.push(value);
+    }
+
+    pub fn add_type<'t>(&mut self, node: ts::Node<'t>) -> usize {
+        assert_eq!(node.kind(), "type_decl");
+
+        let loc = b::Loc::from_node(self.src_idx, &node);
+        let idx = self
+            .typedefs
+            .iter()
+            .position(|typedef| typedef.loc == loc)
+            .expect("type should be declared before being added");
+
         let body_node = node.required_field("body");
+        if body_node.kind() == "variant_type" {
+            let b::TypeDefBody::Variant(v) = &self.typedefs[idx].body else {
+                panic!("type should be declared as a variant type");
+            };
+            self.params = v.params.clone();
+            let cases = body_node
+                .iter_field("variants")
+                .map(|case_node| {
+                    let name = case_node
+                        .required_field("name")
+                        .get_text(&self.ctx.source(self.src_idx).content().text)
+                        .to_string();
+                    b::VariantCase::new(
+                        name,
+                        self.parse_type(case_node.required_field("type")),
+                        b::Loc::from_node(self.src_idx, &case_node),
+                    )
+                })
+                .collect();
+            self.params = vec![];
+
+            let b::TypeDefBody::Variant(v) = &mut self.typedefs[idx].body else {
+                unreachable!();
+            };
+            v.cases = cases;
+            return idx;
+        }
+
         let fields = match body_node.kind() {
             "record_type" => body_node
                 .iter_field("fields")
@@ -96,16 +197,47 @@ impl<'a> TypeParser<'a> {
             v => panic!("Unexpected type body kind: {v}"),
         };
 
-        let value = b::TypeDef {
-            name: name.to_string(),
-            body: b::TypeDefBody::Record(b::RecordType { fields }),
-            loc: b::Loc::from_node(self.src_idx, &node),
+        self.typedefs[idx].body = b::TypeDefBody::Record(b::RecordType { fields });
+        idx
+    }
+
+    /// Reports the types that contain themselves in their own fields, which would need
+    /// an infinite amount of values to be created. Types can only refer to themselves
+    /// through an array or a pointer, which can be empty
+    pub fn check_infinite_size(&self) {
+        for (idx, typedef) in enumerate(&self.typedefs) {
+            let mut visited = HashSet::new();
+            if self.contains_type(idx, idx, &mut visited) {
+                self.ctx.push_error(errors::Error::new(
+                    errors::InfiniteSizeType::new(typedef.name.clone()).into(),
+                    typedef.loc,
+                ));
+            }
+        }
+    }
+
+    fn contains_type(
+        &self,
+        idx: usize,
+        target: usize,
+        visited: &mut HashSet<usize>,
+    ) -> bool {
+        if !visited.insert(idx) {
+            return false;
+        }
+
+        // Variants are stored as pointers to the value of their case, like options, so
+        // their size is always finite
+        let b::TypeDefBody::Record(rec) = &self.typedefs[idx].body else {
+            return false;
         };
-        self.idents.insert(
-            value.name.clone(),
-            b::TypeBody::TypeRef(self.mod_idx, self.typedefs.len()),
-        );
-        self.typedefs.push(value);
+        rec.fields.values().any(|field| match &field.ty.body {
+            // Types from other modules are complete, so they can't refer to this one
+            b::TypeBody::TypeRef(mod_idx, field_idx) if *mod_idx == self.mod_idx => {
+                *field_idx == target || self.contains_type(*field_idx, target, visited)
+            }
+            _ => false,
+        })
     }
 }
 
@@ -1,4 +1,4 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::io::Read;
 use std::path::{Path, PathBuf};
@@ -31,6 +31,12 @@ impl SourceManager {
         self.source(idx).content();
         Ok(idx)
     }
+    /// Reads a file whose path is relative to the directory of the source, without
+    /// loading it as a source itself
+    pub fn read_relative(&self, src_idx: usize, path: &str) -> io::Result<Vec<u8>> {
+        let dir = self.source(src_idx).path.parent().unwrap_or(Path::new(""));
+        fs::read(dir.join(path))
+    }
 }
 
 #[derive(Derivative)]
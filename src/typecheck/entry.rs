@@ -23,4 +23,13 @@ pub enum Constraint {
     Property(String, TypeCheckEntryIdx),
     Array(TypeCheckEntryIdx),
     Ptr(TypeCheckEntryIdx),
+    Option(TypeCheckEntryIdx),
+    /// Types given to the parameters of a variant type, by the module and index of the
+    /// type
+    Variant(usize, usize, Vec<TypeCheckEntryIdx>),
+    /// Function value, by the entries of its parameters and of its result
+    Func(Vec<TypeCheckEntryIdx>, TypeCheckEntryIdx),
+    /// Type expected by the value that contains the entry, like the type of a field of a
+    /// record. Only used when the type of the entry can't be known otherwise
+    Expected(b::Type),
 }
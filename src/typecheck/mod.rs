@@ -1,4 +1,5 @@
 mod entry;
+mod static_assert;
 
 use std::collections::HashSet;
 use std::{cmp, mem, usize};
@@ -7,6 +8,7 @@ use derive_new::new;
 use itertools::{enumerate, izip, Itertools};
 
 use self::entry::{Constraint, TypeCheckEntry, TypeCheckEntryIdx};
+pub use self::static_assert::check_static_asserts;
 use crate::utils::SortedMap;
 use crate::{bytecode as b, context, errors, utils};
 
@@ -37,6 +39,10 @@ pub struct TypeChecker<'a> {
     globals: Vec<GlobalEntry>,
     #[new(default)]
     funcs: Vec<FuncEntry>,
+    /// Receivers of the calls made with the method syntax, along with the name of the
+    /// method and the location of the call
+    #[new(default)]
+    methods: Vec<(TypeCheckEntryIdx, String, b::Loc)>,
 }
 
 impl<'a> TypeChecker<'a> {
@@ -54,6 +60,9 @@ impl<'a> TypeChecker<'a> {
                     func.ret.clone(),
                     func.ret.loc.unwrap_or(func.loc),
                 );
+                if func.is_entry_point {
+                    self.add_entry_point_params(&params_idxs, func.loc);
+                }
                 self.funcs.push(FuncEntry::new(params_idxs, ret_idx));
             }
             for global in &module.globals {
@@ -75,8 +84,36 @@ impl<'a> TypeChecker<'a> {
             }
         };
 
+        self.add_expected_types();
         self.validate();
 
+        for (receiver, name, loc) in &self.methods {
+            let ty = &self.entries[*receiver].ty;
+            if self.is_record_with_field(ty, name) {
+                self.ctx.push_error(errors::Error::new(
+                    errors::AmbiguousMethod::new(name.clone(), ty.clone()).into(),
+                    *loc,
+                ));
+            }
+        }
+
+        {
+            let module = &self.ctx.lock_modules()[self.mod_idx];
+
+            for (global, entry) in izip!(&module.globals, &self.globals) {
+                if global.is_entry_point {
+                    self.validate_entry_point(entry.result, global.loc);
+                }
+                self.report_unused_values(&global.body, &entry.instrs);
+            }
+            for (func, entry) in izip!(&module.funcs, &self.funcs) {
+                if func.is_entry_point {
+                    self.validate_entry_point(entry.ret, func.loc);
+                }
+                self.report_unused_values(&func.body, &entry.instrs);
+            }
+        }
+
         {
             let module = &mut self.ctx.lock_modules_mut()[self.mod_idx];
 
@@ -86,8 +123,13 @@ impl<'a> TypeChecker<'a> {
                         if let b::InstrBody::CreateNumber(ty, _)
                         | b::InstrBody::CreateArray(ty, _)
                         | b::InstrBody::CreateRecord(ty, _)
+                        | b::InstrBody::CreateSome(ty)
+                        | b::InstrBody::CreateNone(ty)
+                        | b::InstrBody::CreateVariant(ty, _)
+                        | b::InstrBody::CreateClosure(ty, ..)
                         | b::InstrBody::If(ty)
-                        | b::InstrBody::Loop(ty, _) = &mut instr.body
+                        | b::InstrBody::Loop(ty, _)
+                        | b::InstrBody::Dbg(ty, _) = &mut instr.body
                         {
                             *ty = self.entries[instr_entry.unwrap()].ty.clone();
                         }
@@ -131,9 +173,11 @@ impl<'a> TypeChecker<'a> {
             instrs_entries.push(entry);
         }
 
-        assert!(stack.len() >= 1);
         assert!(stack.scope_len() == 1);
-        self.merge_entries(&[stack.pop(), result]);
+        if !stack.get_scope().is_never() {
+            assert!(stack.len() >= 1);
+            self.merge_entries(&[stack.pop(), result]);
+        }
 
         instrs_entries
     }
@@ -192,6 +236,21 @@ impl<'a> TypeChecker<'a> {
                 stack.push(entry);
                 Some(entry)
             }
+            b::InstrBody::CreateBytes(v) => {
+                let byte_ty = b::Type::new(b::TypeBody::U8, None);
+                let entry = self.add_entry_from_type(
+                    b::Type::new(
+                        b::TypeBody::Array(b::ArrayType::new(
+                            byte_ty.into(),
+                            Some(v.len()),
+                        )),
+                        None,
+                    ),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
+            }
             b::InstrBody::CreateArray(ty, len) => {
                 assert!(stack.len() >= *len);
                 let item_entry = if *len > 0 {
@@ -216,6 +275,88 @@ impl<'a> TypeChecker<'a> {
                 stack.push(entry);
                 Some(entry)
             }
+            b::InstrBody::CreateSome(ty) => {
+                assert!(stack.len() >= 1);
+                let value = stack.pop();
+                let entry = self.add_entry(instr.loc);
+                self.add_constraint(entry, Constraint::Is(ty.clone()));
+                self.add_constraint(entry, Constraint::Option(value));
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::CreateNone(ty) => {
+                let item_entry = self.add_entry(instr.loc);
+                let entry = self.add_entry(instr.loc);
+                self.add_constraint(entry, Constraint::Is(ty.clone()));
+                self.add_constraint(entry, Constraint::Option(item_entry));
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::CreateVariant(ty, case) => {
+                assert!(stack.len() >= 1);
+                let value = stack.pop();
+                let b::TypeBody::Variant(mod_idx, ty_idx, params) = &ty.body else {
+                    panic!("type should be a variant type");
+                };
+                let args = params
+                    .iter()
+                    .map(|_| self.add_entry(instr.loc))
+                    .collect_vec();
+                let case_ty = self.case_type(*mod_idx, *ty_idx, *case);
+                let case_entry = self.add_case_entry(case_ty, &args, instr.loc);
+                self.merge_entries(&[value, case_entry]);
+
+                let entry = self.add_entry(instr.loc);
+                self.add_constraint(entry, Constraint::Is(ty.clone()));
+                self.add_constraint(entry, Constraint::Variant(*mod_idx, *ty_idx, args));
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::CreateClosure(_, mod_idx, idx, bound) => {
+                let bound_len = bound.iter().filter(|is_bound| **is_bound).count();
+                assert!(stack.len() >= bound_len);
+                let mut values = stack.pop_many(bound_len).into_iter();
+
+                // The closure only takes the types of the function, so calling it can't
+                // change the type of the function
+                let mut params = vec![];
+                let ret;
+                if *mod_idx == self.mod_idx {
+                    let func = self.funcs[*idx].clone();
+                    for (param, is_bound) in izip!(func.params, bound) {
+                        if *is_bound {
+                            let value = values.next().unwrap();
+                            self.add_constraint(value, Constraint::TypeOf(param));
+                        } else {
+                            let entry = self.add_entry(instr.loc);
+                            self.add_constraint(entry, Constraint::TypeOf(param));
+                            params.push(entry);
+                        }
+                    }
+                    ret = self.add_entry(instr.loc);
+                    if func_idx.is_some_and(|i| i == *idx) {
+                        self.merge_entries(&[ret, func.ret]);
+                    } else {
+                        self.add_constraint(ret, Constraint::TypeOf(func.ret));
+                    }
+                } else {
+                    let func = self.ctx.lock_modules()[*mod_idx].funcs[*idx].clone();
+                    for (param, is_bound) in izip!(func.params, bound) {
+                        if *is_bound {
+                            let value = values.next().unwrap();
+                            self.add_constraint(value, Constraint::Is(param.ty));
+                        } else {
+                            params.push(self.add_entry_from_type(param.ty, instr.loc));
+                        }
+                    }
+                    ret = self.add_entry_from_type(func.ret, instr.loc);
+                }
+
+                let entry = self.add_entry(instr.loc);
+                self.add_constraint(entry, Constraint::Func(params, ret));
+                stack.push(entry);
+                Some(entry)
+            }
             b::InstrBody::Add
             | b::InstrBody::Sub
             | b::InstrBody::Mul
@@ -231,6 +372,18 @@ impl<'a> TypeChecker<'a> {
                 stack.push(entry);
                 Some(entry)
             }
+            b::InstrBody::Cast(ty) => {
+                assert!(stack.len() >= 1);
+                let source = stack.pop();
+                // FIXME: use interface/trait
+                self.add_constraint(
+                    source,
+                    Constraint::Is(b::Type::new(b::TypeBody::AnyNumber, None)),
+                );
+                let entry = self.add_entry_from_type(ty.clone(), instr.loc);
+                stack.push(entry);
+                Some(entry)
+            }
             b::InstrBody::Eq
             | b::InstrBody::Neq
             | b::InstrBody::Gt
@@ -251,40 +404,67 @@ impl<'a> TypeChecker<'a> {
                 stack.push(entry);
                 Some(entry)
             }
-            b::InstrBody::Call(mod_idx, idx) => {
-                let entry = if *mod_idx == self.mod_idx {
-                    let func = self.funcs[*idx].clone();
-                    assert!(stack.len() >= func.params.len());
+            b::InstrBody::Call(mod_idx, idx) | b::InstrBody::TailCall(mod_idx, idx) => {
+                let entry = self.add_call(*mod_idx, *idx, stack, func_idx, instr.loc);
 
-                    let args = stack.pop_many(func.params.len());
-                    for (param, arg) in izip!(func.params, args) {
-                        self.add_constraint(arg, Constraint::TypeOf(param));
-                    }
-
-                    let entry = self.add_entry(instr.loc);
+                if let b::InstrBody::TailCall(..) = &instr.body {
+                    let result = stack.scopes()[0].payload.result;
+                    self.merge_entries(&[entry, result]);
 
-                    if func_idx.is_some_and(|i| i == *idx) {
-                        self.merge_entries(&[entry, func.ret]);
-                    } else {
-                        self.add_constraint(entry, Constraint::TypeOf(func.ret));
-                    }
+                    stack.get_scope_mut().mark_as_never();
+                    return None;
+                }
 
-                    entry
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::CallMethod(name, mod_idx, idx) => {
+                let params_len = if *mod_idx == self.mod_idx {
+                    self.funcs[*idx].params.len()
                 } else {
-                    let modules = self.ctx.lock_modules();
-                    let func = &modules[*mod_idx].funcs[*idx];
-
-                    let args = stack.pop_many(func.params.len());
-                    for (param, arg) in izip!(&func.params, args) {
-                        self.add_constraint(arg, Constraint::Is(param.ty.clone()));
-                    }
+                    self.ctx.lock_modules()[*mod_idx].funcs[*idx].params.len()
+                };
+                assert!(stack.len() >= params_len);
+                let receiver = *stack.get(params_len - 1).unwrap();
 
-                    self.add_entry_from_type(func.ret.clone(), instr.loc)
+                let field_ty = self.field_type(receiver, name, &mut HashSet::new());
+                let entry = if let Some(ty) = field_ty
+                    .as_ref()
+                    .filter(|ty| !matches!(ty.body, b::TypeBody::Func(_)))
+                {
+                    // The function is called instead, so the arguments are still
+                    // checked
+                    self.ctx.push_error(errors::Error::new(
+                        errors::FieldNotFunction::new(name.clone(), ty.clone()).into(),
+                        instr.loc,
+                    ));
+                    self.add_call(*mod_idx, *idx, stack, func_idx, instr.loc)
+                } else if field_ty.is_some()
+                    || self.has_field(receiver, name, &mut HashSet::new())
+                {
+                    // The field holds a function value, which is called with the
+                    // arguments after the receiver
+                    let args = stack.pop_many(params_len);
+                    let field = self.property(args[0], name, instr.loc);
+                    self.add_value_call(field, &args[1..], instr.loc)
+                } else {
+                    // The type may only be known later, so it's checked again after
+                    // every type is resolved
+                    self.methods.push((receiver, name.clone(), instr.loc));
+                    self.add_call(*mod_idx, *idx, stack, func_idx, instr.loc)
                 };
 
                 stack.push(entry);
                 Some(entry)
             }
+            b::InstrBody::CallValue(n) => {
+                assert!(stack.len() > *n);
+                let args = stack.pop_many(*n);
+                let callee = stack.pop();
+                let entry = self.add_value_call(callee, &args, instr.loc);
+                stack.push(entry);
+                Some(entry)
+            }
             b::InstrBody::If(_) => {
                 let cond = stack.pop();
                 self.add_constraint(
@@ -321,6 +501,13 @@ impl<'a> TypeChecker<'a> {
                     self.merge_entries(&[res, result]);
                 }
 
+                // Every branch is never, so the result is never produced. It still needs
+                // a type, so it's the same as the function's result
+                if stack.get_scope().is_never() {
+                    self.merge_entries(&[stack.scopes()[0].payload.result, result]);
+                    return Some(result);
+                }
+
                 stack.push(result);
                 Some(result)
             }
@@ -356,6 +543,29 @@ impl<'a> TypeChecker<'a> {
                 stack.get_scope_mut().mark_as_never();
                 None
             }
+            b::InstrBody::Return => {
+                assert!(stack.len() >= 1);
+                let value = stack.pop();
+                let result = stack.scopes()[0].payload.result;
+                self.merge_entries(&[value, result]);
+
+                stack.get_scope_mut().mark_as_never();
+                None
+            }
+            b::InstrBody::Panic => {
+                assert!(stack.len() >= 1);
+                let message = stack.pop();
+                self.add_constraint(
+                    message,
+                    Constraint::Is(b::Type::new(
+                        b::TypeBody::String(b::StringType::new(None)),
+                        None,
+                    )),
+                );
+
+                stack.get_scope_mut().mark_as_never();
+                None
+            }
             b::InstrBody::ArrayLen => {
                 assert!(stack.scope_len() >= 1);
                 let array = stack.pop();
@@ -385,6 +595,61 @@ impl<'a> TypeChecker<'a> {
                 stack.push(entry);
                 Some(entry)
             }
+            b::InstrBody::ArraySlice => {
+                assert!(stack.len() >= 3);
+                let [source, start, end] = stack.pop_many(3)[..] else {
+                    unreachable!()
+                };
+                for idx in [start, end] {
+                    self.add_constraint(
+                        idx,
+                        Constraint::Is(b::Type::new(b::TypeBody::USize, None)),
+                    );
+                }
+                let item = self.array_item(source, instr.loc);
+                let entry = self.add_entry(instr.loc);
+                self.add_constraint(entry, Constraint::Array(item));
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::ArrayFromFn(mod_idx, idx) => {
+                assert!(stack.len() >= 1);
+                let len = stack.pop();
+                self.add_constraint(
+                    len,
+                    Constraint::Is(b::Type::new(b::TypeBody::USize, None)),
+                );
+
+                let item = if *mod_idx == self.mod_idx {
+                    let func = self.funcs[*idx].clone();
+                    // TODO: better error handling
+                    assert!(func.params.len() == 1, "generator should have 1 parameter");
+
+                    self.add_constraint(
+                        func.params[0],
+                        Constraint::Is(b::Type::new(b::TypeBody::USize, None)),
+                    );
+                    let item = self.add_entry(instr.loc);
+                    self.add_constraint(item, Constraint::TypeOf(func.ret));
+                    item
+                } else {
+                    let modules = self.ctx.lock_modules();
+                    let func = &modules[*mod_idx].funcs[*idx];
+                    // TODO: better error handling
+                    assert!(func.params.len() == 1, "generator should have 1 parameter");
+                    assert!(
+                        func.params[0].ty.body == b::TypeBody::USize,
+                        "generator should receive an usize"
+                    );
+
+                    self.add_entry_from_type(func.ret.clone(), instr.loc)
+                };
+
+                let entry = self.add_entry(instr.loc);
+                self.add_constraint(entry, Constraint::Array(item));
+                stack.push(entry);
+                Some(entry)
+            }
             b::InstrBody::StrLen => {
                 assert!(stack.scope_len() >= 1);
                 let string = stack.pop();
@@ -419,86 +684,487 @@ impl<'a> TypeChecker<'a> {
                 stack.push(entry);
                 Some(entry)
             }
-            b::InstrBody::CompileError => {
-                Some(self.add_entry_from_type(b::Type::unknown(None), instr.loc))
+            b::InstrBody::StrFromPtr => {
+                assert!(stack.scope_len() >= 1);
+                let ptr = stack.pop();
+                self.add_constraint(
+                    ptr,
+                    Constraint::Is(b::Type::new(
+                        b::TypeBody::Ptr(b::Type::new(b::TypeBody::U8, None).into()),
+                        None,
+                    )),
+                );
+                let entry = self.add_entry_from_type(
+                    b::Type::new(b::TypeBody::String(b::StringType::new(None)), None),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
             }
-        }
-    }
-
-    fn add_entry(&mut self, loc: b::Loc) -> TypeCheckEntryIdx {
-        self.entries
-            .push(TypeCheckEntry::new(b::Type::unknown(None), loc));
-        self.entries.len() - 1
-    }
-
-    fn add_entry_from_type(&mut self, ty: b::Type, loc: b::Loc) -> TypeCheckEntryIdx {
-        let mut entry = TypeCheckEntry::new(ty.clone(), loc);
-
-        if let b::TypeBody::Inferred(b::InferredType { properties }) = ty.body {
-            for (prop_name, prop_ty) in properties {
-                let prop_idx = self.add_entry_from_type(prop_ty, loc);
-                entry
-                    .constraints
-                    .push(Constraint::Property(prop_name, prop_idx));
+            b::InstrBody::StrAlloc => {
+                assert!(stack.scope_len() >= 1);
+                let len = stack.pop();
+                self.add_constraint(
+                    len,
+                    Constraint::Is(b::Type::new(b::TypeBody::USize, None)),
+                );
+                let entry = self.add_entry_from_type(
+                    b::Type::new(b::TypeBody::String(b::StringType::new(None)), None),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
             }
-        } else {
-            entry.constraints.push(Constraint::Is(ty.clone()));
-        }
-
-        self.entries.push(entry);
-        self.entries.len() - 1
-    }
-
-    fn add_constraint(&mut self, idx: TypeCheckEntryIdx, constraint: Constraint) {
-        let entry = &mut self.entries[idx];
-
-        if entry.same_of.len() > 0 {
-            for idx in entry.same_of.clone() {
-                self.add_constraint(idx, constraint.clone());
+            b::InstrBody::PtrIsNull => {
+                assert!(stack.scope_len() >= 1);
+                let ptr = stack.pop();
+                self.add_constraint(
+                    ptr,
+                    Constraint::Is(b::Type::new(
+                        b::TypeBody::Ptr(b::Type::unknown(None).into()),
+                        None,
+                    )),
+                );
+                let entry = self.add_entry_from_type(
+                    b::Type::new(b::TypeBody::Bool, None),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
             }
-            return;
-        }
-
-        entry.constraints.push(constraint);
-    }
-
-    fn merge_entries(&mut self, entries: &[TypeCheckEntryIdx]) -> TypeCheckEntryIdx {
-        let mut visited = HashSet::new();
-
-        let head = entries[0];
-        visited.insert(head);
-
-        for idx in &entries[1..] {
-            if visited.contains(idx) {
-                continue;
+            b::InstrBody::PtrAdd => {
+                assert!(stack.scope_len() >= 1);
+                let offset = stack.pop();
+                let ptr = stack.pop();
+                self.add_constraint(
+                    ptr,
+                    Constraint::Is(b::Type::new(
+                        b::TypeBody::Ptr(b::Type::unknown(None).into()),
+                        None,
+                    )),
+                );
+                self.add_constraint(
+                    offset,
+                    Constraint::Is(b::Type::new(b::TypeBody::USize, None)),
+                );
+                let item = self.ptr_item(ptr, instr.loc);
+                let entry = self.add_entry(instr.loc);
+                self.add_constraint(entry, Constraint::Ptr(item));
+                stack.push(entry);
+                Some(entry)
             }
-
-            self.entries[*idx].same_of.insert(head);
-            let constraints = mem::replace(&mut self.entries[*idx].constraints, vec![]);
-
-            for constraint in constraints {
-                self.add_constraint(head, constraint);
+            b::InstrBody::PtrLoad => {
+                assert!(stack.scope_len() >= 1);
+                let ptr = stack.pop();
+                let entry = self.ptr_item(ptr, instr.loc);
+                stack.push(entry);
+                Some(entry)
             }
-
-            visited.insert(*idx);
-        }
-
-        head
-    }
-
-    fn validate(&mut self) {
-        let mut visited = HashSet::new();
-        for entry in 0..self.entries.len() {
-            self.validate_entry(entry, &mut visited);
-        }
-    }
-
-    fn validate_entry(
-        &mut self,
-        idx: TypeCheckEntryIdx,
-        visited: &mut HashSet<TypeCheckEntryIdx>,
-    ) -> bool {
-        if visited.contains(&idx) {
+            b::InstrBody::EnvVar => {
+                assert!(stack.scope_len() >= 1);
+                let name = stack.pop();
+                self.add_constraint(
+                    name,
+                    Constraint::Is(b::Type::new(
+                        b::TypeBody::String(b::StringType::new(None)),
+                        None,
+                    )),
+                );
+                let entry = self.add_entry_from_type(
+                    b::Type::new(
+                        b::TypeBody::Ptr(b::Type::new(b::TypeBody::U8, None).into()),
+                        None,
+                    ),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::OptionIsSome => {
+                assert!(stack.len() >= 1);
+                let option = stack.pop();
+                self.option_item(option, instr.loc);
+                let entry = self.add_entry_from_type(
+                    b::Type::new(b::TypeBody::Bool, None),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::OptionUnwrap => {
+                assert!(stack.len() >= 1);
+                let option = stack.pop();
+                let entry = self.option_item(option, instr.loc);
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::VariantIs(ty, _) => {
+                assert!(stack.len() >= 1);
+                let variant = stack.pop();
+                self.variant_args(variant, ty, instr.loc);
+                let entry = self.add_entry_from_type(
+                    b::Type::new(b::TypeBody::Bool, None),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::VariantUnwrap(ty, case) => {
+                assert!(stack.len() >= 1);
+                let variant = stack.pop();
+                let args = self.variant_args(variant, ty, instr.loc);
+                let b::TypeBody::Variant(mod_idx, ty_idx, _) = &ty.body else {
+                    panic!("type should be a variant type");
+                };
+                let case_ty = self.case_type(*mod_idx, *ty_idx, *case);
+                let entry = self.add_case_entry(case_ty, &args, instr.loc);
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::Dbg(..) => {
+                assert!(stack.len() >= 1);
+                // The value is returned unchanged, its type is only needed to write it
+                let value = stack.pop();
+                stack.push(value);
+                Some(value)
+            }
+            b::InstrBody::SizeOf(_) | b::InstrBody::AlignOf(_) => {
+                let entry = self.add_entry_from_type(
+                    b::Type::new(b::TypeBody::USize, None),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::StaticAssert(..) => {
+                assert!(stack.len() >= 1);
+                let cond = stack.pop();
+                self.add_constraint(
+                    cond,
+                    Constraint::Is(b::Type::new(b::TypeBody::Bool, None)),
+                );
+                let entry = self.add_entry_from_type(
+                    b::Type::new(b::TypeBody::Bool, None),
+                    instr.loc,
+                );
+                stack.push(entry);
+                Some(entry)
+            }
+            b::InstrBody::Annotate(ty) => {
+                assert!(stack.len() >= 1);
+                let entry = *stack.get(0).unwrap();
+                self.add_constraint(entry, Constraint::Is(ty.clone()));
+                Some(entry)
+            }
+            b::InstrBody::Unused => {
+                assert!(stack.len() >= 1);
+                Some(*stack.get(0).unwrap())
+            }
+            b::InstrBody::CompileError => {
+                let entry = self.add_entry_from_type(b::Type::unknown(None), instr.loc);
+                stack.push(entry);
+                Some(entry)
+            }
+        }
+    }
+
+    /// Constraint the parameters of a function used as entry point. It can receive no
+    /// parameters, or a single one with the command-line arguments
+    fn add_entry_point_params(&mut self, params: &[TypeCheckEntryIdx], loc: b::Loc) {
+        match params {
+            [] => {}
+            [args] => {
+                let str_ty =
+                    b::Type::new(b::TypeBody::String(b::StringType::new(None)), None);
+                self.add_constraint(
+                    *args,
+                    Constraint::Is(b::Type::new(
+                        b::TypeBody::Array(b::ArrayType::new(str_ty.into(), None)),
+                        None,
+                    )),
+                );
+            }
+            _ => {
+                self.ctx.push_error(errors::Error::new(
+                    errors::ErrorDetail::InvalidEntryPointParams,
+                    loc,
+                ));
+            }
+        }
+    }
+
+    /// Adds the constraints of a call to the arguments at the top of the stack, returning
+    /// the entry of its result
+    fn add_call(
+        &mut self,
+        mod_idx: usize,
+        idx: usize,
+        stack: &mut Stack,
+        func_idx: Option<usize>,
+        loc: b::Loc,
+    ) -> TypeCheckEntryIdx {
+        if mod_idx == self.mod_idx {
+            let func = self.funcs[idx].clone();
+            assert!(stack.len() >= func.params.len());
+
+            let args = stack.pop_many(func.params.len());
+            for (param, arg) in izip!(func.params, args) {
+                self.add_constraint(arg, Constraint::TypeOf(param));
+            }
+
+            let entry = self.add_entry(loc);
+
+            if func_idx.is_some_and(|i| i == idx) {
+                self.merge_entries(&[entry, func.ret]);
+            } else {
+                self.add_constraint(entry, Constraint::TypeOf(func.ret));
+            }
+
+            entry
+        } else {
+            let modules = self.ctx.lock_modules();
+            let func = &modules[mod_idx].funcs[idx];
+
+            let args = stack.pop_many(func.params.len());
+            for (param, arg) in izip!(&func.params, args) {
+                self.add_constraint(arg, Constraint::Is(param.ty.clone()));
+            }
+
+            self.add_entry_from_type(func.ret.clone(), loc)
+        }
+    }
+
+    /// Adds the constraints of a call to a function value, returning the entry of its
+    /// result
+    fn add_value_call(
+        &mut self,
+        callee: TypeCheckEntryIdx,
+        args: &[TypeCheckEntryIdx],
+        loc: b::Loc,
+    ) -> TypeCheckEntryIdx {
+        let (params, ret) = self.func_parts(callee, args.len(), loc);
+        for (param, arg) in izip!(&params, args) {
+            self.add_constraint(*arg, Constraint::TypeOf(*param));
+        }
+        if params.len() != args.len() {
+            self.ctx.push_error(errors::Error::new(
+                errors::WrongArgumentCount::new(params.len(), args.len()).into(),
+                loc,
+            ));
+            return self.add_entry_from_type(b::Type::unknown(None), loc);
+        }
+
+        let entry = self.add_entry(loc);
+        self.add_constraint(entry, Constraint::TypeOf(ret));
+        entry
+    }
+
+    /// Whether the entry is known to have a field with the given name, either by its type
+    /// or by the fields read from it so far
+    fn has_field(
+        &self,
+        idx: TypeCheckEntryIdx,
+        name: &str,
+        visited: &mut HashSet<TypeCheckEntryIdx>,
+    ) -> bool {
+        if !visited.insert(idx) {
+            return false;
+        }
+        let entry = &self.entries[idx];
+        entry
+            .same_of
+            .iter()
+            .any(|same_of| self.has_field(*same_of, name, visited))
+            || entry.constraints.iter().any(|cons| match cons {
+                Constraint::Property(prop_name, _) => prop_name == name,
+                Constraint::Is(ty) => self.is_record_with_field(ty, name),
+                Constraint::TypeOf(target) => self.has_field(*target, name, visited),
+                _ => false,
+            })
+    }
+
+    /// Type of the field with the given name, if the entry is known to be a record with
+    /// that field
+    fn field_type(
+        &self,
+        idx: TypeCheckEntryIdx,
+        name: &str,
+        visited: &mut HashSet<TypeCheckEntryIdx>,
+    ) -> Option<b::Type> {
+        if !visited.insert(idx) {
+            return None;
+        }
+        let entry = &self.entries[idx];
+        entry
+            .same_of
+            .iter()
+            .find_map(|same_of| self.field_type(*same_of, name, visited))
+            .or_else(|| {
+                entry.constraints.iter().find_map(|cons| match cons {
+                    Constraint::Is(b::Type {
+                        body: b::TypeBody::TypeRef(mod_idx, ty_idx),
+                        ..
+                    }) => match &self.ctx.lock_modules()[*mod_idx].typedefs[*ty_idx].body
+                    {
+                        b::TypeDefBody::Record(rec) => {
+                            rec.fields.get(name).map(|field| field.ty.clone())
+                        }
+                        b::TypeDefBody::Variant(_) => None,
+                    },
+                    Constraint::TypeOf(target) => self.field_type(*target, name, visited),
+                    _ => None,
+                })
+            })
+    }
+
+    fn is_record_with_field(&self, ty: &b::Type, name: &str) -> bool {
+        let b::TypeBody::TypeRef(mod_idx, ty_idx) = &ty.body else {
+            return false;
+        };
+        match &self.ctx.lock_modules()[*mod_idx].typedefs[*ty_idx].body {
+            b::TypeDefBody::Record(rec) => rec.fields.contains(name),
+            b::TypeDefBody::Variant(_) => false,
+        }
+    }
+
+    fn add_entry(&mut self, loc: b::Loc) -> TypeCheckEntryIdx {
+        self.entries
+            .push(TypeCheckEntry::new(b::Type::unknown(None), loc));
+        self.entries.len() - 1
+    }
+
+    fn add_entry_from_type(&mut self, ty: b::Type, loc: b::Loc) -> TypeCheckEntryIdx {
+        let mut entry = TypeCheckEntry::new(ty.clone(), loc);
+
+        if let b::TypeBody::Inferred(b::InferredType { properties }) = ty.body {
+            for (prop_name, prop_ty) in properties {
+                let prop_idx = self.add_entry_from_type(prop_ty, loc);
+                entry
+                    .constraints
+                    .push(Constraint::Property(prop_name, prop_idx));
+            }
+        } else {
+            entry.constraints.push(Constraint::Is(ty.clone()));
+        }
+
+        self.entries.push(entry);
+        self.entries.len() - 1
+    }
+
+    fn add_constraint(&mut self, idx: TypeCheckEntryIdx, constraint: Constraint) {
+        let entry = &mut self.entries[idx];
+
+        if entry.same_of.len() > 0 {
+            for idx in entry.same_of.clone() {
+                self.add_constraint(idx, constraint.clone());
+            }
+            return;
+        }
+
+        // Arrays, options, variants and functions have a single type for each of their
+        // values, so values constrained separately, like the items of an array used
+        // before it was defined, should be the same
+        if let Constraint::Variant(mod_idx, ty_idx, args) = &constraint {
+            let existing = entry.constraints.iter().find_map(|cons| match cons {
+                Constraint::Variant(existing_mod_idx, existing_ty_idx, existing)
+                    if existing_mod_idx == mod_idx && existing_ty_idx == ty_idx =>
+                {
+                    Some(existing.clone())
+                }
+                _ => None,
+            });
+            if let Some(existing) = existing {
+                for (existing_arg, arg) in izip!(existing, args) {
+                    self.merge_entries(&[existing_arg, *arg]);
+                }
+                return;
+            }
+        }
+        if let Constraint::Func(params, ret) = &constraint {
+            let existing = entry.constraints.iter().find_map(|cons| match cons {
+                Constraint::Func(existing_params, existing_ret)
+                    if existing_params.len() == params.len() =>
+                {
+                    Some((existing_params.clone(), *existing_ret))
+                }
+                _ => None,
+            });
+            if let Some((existing_params, existing_ret)) = existing {
+                for (existing_param, param) in izip!(existing_params, params) {
+                    self.merge_entries(&[existing_param, *param]);
+                }
+                self.merge_entries(&[existing_ret, *ret]);
+                return;
+            }
+        }
+        if let Constraint::Array(item) | Constraint::Option(item) = constraint {
+            let existing =
+                entry
+                    .constraints
+                    .iter()
+                    .find_map(|cons| match (cons, &constraint) {
+                        (Constraint::Array(existing), Constraint::Array(_))
+                        | (Constraint::Option(existing), Constraint::Option(_)) => {
+                            Some(*existing)
+                        }
+                        _ => None,
+                    });
+            if let Some(existing) = existing {
+                self.merge_entries(&[existing, item]);
+                return;
+            }
+        }
+
+        entry.constraints.push(constraint);
+    }
+
+    fn merge_entries(&mut self, entries: &[TypeCheckEntryIdx]) -> TypeCheckEntryIdx {
+        let mut visited = HashSet::new();
+
+        let head = entries[0];
+        visited.insert(head);
+
+        for idx in &entries[1..] {
+            // If the head is already the same of the entry, they were merged before and
+            // linking them again would create a cycle
+            if visited.contains(idx) || self.is_same_of(head, *idx) {
+                continue;
+            }
+
+            self.entries[*idx].same_of.insert(head);
+            let constraints = mem::replace(&mut self.entries[*idx].constraints, vec![]);
+
+            for constraint in constraints {
+                self.add_constraint(head, constraint);
+            }
+
+            visited.insert(*idx);
+        }
+
+        head
+    }
+
+    fn is_same_of(&self, idx: TypeCheckEntryIdx, target: TypeCheckEntryIdx) -> bool {
+        idx == target
+            || self.entries[idx]
+                .same_of
+                .iter()
+                .any(|same_of| self.is_same_of(*same_of, target))
+    }
+
+    fn validate(&mut self) {
+        let mut visited = HashSet::new();
+        for entry in 0..self.entries.len() {
+            self.validate_entry(entry, &mut visited);
+        }
+    }
+
+    fn validate_entry(
+        &mut self,
+        idx: TypeCheckEntryIdx,
+        visited: &mut HashSet<TypeCheckEntryIdx>,
+    ) -> bool {
+        if visited.contains(&idx) {
             return true;
         }
         visited.insert(idx);
@@ -536,45 +1202,78 @@ impl<'a> TypeChecker<'a> {
         }
 
         for cons in self.entries[idx].constraints.clone() {
-            let dep = match cons {
+            let deps = match cons {
                 Constraint::TypeOf(target)
                 | Constraint::Array(target)
                 | Constraint::Property(_, target)
-                | Constraint::Ptr(target) => target,
-                Constraint::Is(_) => continue,
+                | Constraint::Ptr(target)
+                | Constraint::Option(target) => vec![target],
+                Constraint::Variant(_, _, args) => args,
+                Constraint::Func(params, ret) => {
+                    params.into_iter().chain([ret]).collect()
+                }
+                Constraint::Is(_) | Constraint::Expected(_) => continue,
             };
-            success = self.validate_entry(dep, visited) && success;
+            for dep in deps {
+                success = self.validate_entry(dep, visited) && success;
+            }
         }
 
         let mut merge_with = self.entries[idx]
             .constraints
             .iter()
-            .map(|cons| match cons {
-                Constraint::Is(ty) => ty.clone(),
+            .filter_map(|cons| match cons {
+                Constraint::Is(ty) => Some(ty.clone()),
                 Constraint::TypeOf(target) => {
                     let ty = self.entries[*target].ty.clone();
-                    ty
+                    Some(ty)
                 }
                 Constraint::Array(target) => {
                     let ty = self.entries[*target].ty.clone();
-                    b::Type::new(
+                    Some(b::Type::new(
                         b::TypeBody::Array(b::ArrayType::new(ty.into(), None)),
                         None,
-                    )
+                    ))
                 }
                 Constraint::Property(key, target) => {
                     let ty = self.entries[*target].ty.clone();
-                    b::Type::new(
+                    Some(b::Type::new(
                         b::TypeBody::Inferred(b::InferredType {
                             properties: SortedMap::from([(key.clone(), ty)]),
                         }),
                         None,
-                    )
+                    ))
                 }
                 Constraint::Ptr(target) => {
                     let ty = self.entries[*target].ty.clone();
-                    b::Type::new(b::TypeBody::Ptr(ty.into()), None)
+                    Some(b::Type::new(b::TypeBody::Ptr(ty.into()), None))
+                }
+                Constraint::Option(target) => {
+                    let ty = self.entries[*target].ty.clone();
+                    Some(b::Type::new(b::TypeBody::Option(ty.into()), None))
+                }
+                Constraint::Variant(mod_idx, ty_idx, args) => {
+                    let args_tys = args
+                        .iter()
+                        .map(|arg| self.entries[*arg].ty.clone())
+                        .collect();
+                    Some(b::Type::new(
+                        b::TypeBody::Variant(*mod_idx, *ty_idx, args_tys),
+                        None,
+                    ))
                 }
+                Constraint::Func(params, ret) => {
+                    let params_tys = params
+                        .iter()
+                        .map(|param| self.entries[*param].ty.clone())
+                        .collect();
+                    let ret_ty = self.entries[*ret].ty.clone();
+                    Some(b::Type::new(
+                        b::TypeBody::Func(b::FuncType::new(params_tys, ret_ty).into()),
+                        None,
+                    ))
+                }
+                Constraint::Expected(_) => None,
             })
             .collect_vec();
         merge_with.sort_by(|a, b| match (&a.body, &b.body) {
@@ -605,13 +1304,25 @@ impl<'a> TypeChecker<'a> {
             }
         }
 
-        if matches!(
-            &self.entries[idx].ty.body,
-            b::TypeBody::AnyNumber
-                | b::TypeBody::AnySignedNumber
-                | b::TypeBody::AnyFloat
-                | b::TypeBody::Inferred(_)
-        ) {
+        if success && !self.is_final(idx) {
+            let expected =
+                self.entries[idx]
+                    .constraints
+                    .iter()
+                    .filter_map(|cons| match cons {
+                        Constraint::Expected(ty) => Some(ty.clone()),
+                        _ => None,
+                    });
+            for ty in expected.collect_vec() {
+                // Mismatches are reported by the value that expects the type
+                let entry_ty = &self.entries[idx].ty;
+                if let Some(res) = entry_ty.intersection(&ty, &self.ctx.lock_modules()) {
+                    self.entries[idx].ty = res;
+                }
+            }
+        }
+
+        if !self.is_final(idx) {
             self.ctx.push_error(errors::Error::new(
                 errors::ErrorDetail::TypeNotFinal,
                 self.entries[idx].loc,
@@ -622,6 +1333,199 @@ impl<'a> TypeChecker<'a> {
         success
     }
 
+    fn is_final(&self, idx: TypeCheckEntryIdx) -> bool {
+        !matches!(
+            &self.entries[idx].ty.body,
+            b::TypeBody::AnyNumber
+                | b::TypeBody::AnySignedNumber
+                | b::TypeBody::AnyFloat
+                | b::TypeBody::Inferred(_)
+        )
+    }
+
+    /// Types are mostly inferred from the values, so values that have no type by
+    /// themselves, like empty arrays, can take the type expected by the record or array
+    /// that contains them
+    fn add_expected_types(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            let mut merged_into = vec![vec![]; self.entries.len()];
+            for (idx, entry) in enumerate(&self.entries) {
+                for same_of in &entry.same_of {
+                    merged_into[*same_of].push(idx);
+                }
+            }
+
+            for idx in 0..self.entries.len() {
+                let expected =
+                    self.expected_types(idx, &merged_into, &mut HashSet::new());
+                for cons in self.entries[idx].constraints.clone() {
+                    for ty in &expected {
+                        let targets = match (&cons, &ty.body) {
+                            (
+                                Constraint::Property(key, target),
+                                b::TypeBody::TypeRef(mod_idx, ty_idx),
+                            ) => {
+                                let modules = self.ctx.lock_modules();
+                                let b::TypeDefBody::Record(rec) =
+                                    &modules[*mod_idx].typedefs[*ty_idx].body
+                                else {
+                                    continue;
+                                };
+                                let Some(field) = rec.fields.get(key) else {
+                                    continue;
+                                };
+                                vec![(*target, field.ty.clone())]
+                            }
+                            (Constraint::Array(target), b::TypeBody::Array(array_ty)) => {
+                                vec![(*target, array_ty.item.as_ref().clone())]
+                            }
+                            (
+                                Constraint::Option(target),
+                                b::TypeBody::Option(item_ty),
+                            ) => vec![(*target, item_ty.as_ref().clone())],
+                            (
+                                Constraint::Variant(mod_idx, ty_idx, args),
+                                b::TypeBody::Variant(ty_mod_idx, ty_ty_idx, args_tys),
+                            ) if mod_idx == ty_mod_idx && ty_idx == ty_ty_idx => {
+                                izip!(args.clone(), args_tys.clone()).collect()
+                            }
+                            (
+                                Constraint::Func(params, ret),
+                                b::TypeBody::Func(func_ty),
+                            ) if params.len() == func_ty.params.len() => {
+                                izip!(params.clone(), func_ty.params.clone())
+                                    .chain([(*ret, func_ty.ret.clone())])
+                                    .collect()
+                            }
+                            _ => continue,
+                        };
+                        for (target, target_ty) in targets {
+                            if !target_ty.is_unknown() {
+                                changed = self.add_expected(target, target_ty) || changed;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn expected_types(
+        &self,
+        idx: TypeCheckEntryIdx,
+        merged_into: &[Vec<TypeCheckEntryIdx>],
+        visited: &mut HashSet<TypeCheckEntryIdx>,
+    ) -> Vec<b::Type> {
+        if !visited.insert(idx) {
+            return vec![];
+        }
+
+        let mut tys = vec![];
+        for cons in &self.entries[idx].constraints {
+            match cons {
+                Constraint::Is(ty) | Constraint::Expected(ty)
+                    if matches!(
+                        ty.body,
+                        b::TypeBody::TypeRef(..)
+                            | b::TypeBody::Array(_)
+                            | b::TypeBody::Option(_)
+                            | b::TypeBody::Variant(..)
+                            | b::TypeBody::Func(_)
+                    ) =>
+                {
+                    tys.push(ty.clone())
+                }
+                Constraint::TypeOf(target) => {
+                    tys.extend(self.expected_types(*target, merged_into, visited))
+                }
+                _ => {}
+            }
+        }
+        for same_of in &self.entries[idx].same_of {
+            tys.extend(self.expected_types(*same_of, merged_into, visited));
+        }
+
+        // When entries are merged, their constraints are moved to only one of them, like
+        // the return type of a function, which is moved to the first value returned. The
+        // other values are expected to have the same type as well
+        for parent in &merged_into[idx] {
+            tys.extend(self.expected_types(*parent, merged_into, visited));
+            for same_of in &self.entries[*parent].same_of {
+                tys.extend(self.expected_types(*same_of, merged_into, visited));
+            }
+        }
+
+        tys
+    }
+
+    fn add_expected(&mut self, idx: TypeCheckEntryIdx, ty: b::Type) -> bool {
+        let entry = &self.entries[idx];
+
+        if !entry.same_of.is_empty() {
+            let mut changed = false;
+            for idx in entry.same_of.clone() {
+                changed = self.add_expected(idx, ty.clone()) || changed;
+            }
+            return changed;
+        }
+
+        let cons = Constraint::Expected(ty);
+        if entry.constraints.contains(&cons) {
+            return false;
+        }
+        self.entries[idx].constraints.push(cons);
+        true
+    }
+
+    /// Reports the values marked as unused that are not unit. There's no unit type yet,
+    /// so `bool` takes its place, as it's the result of functions called only for their
+    /// effects, like `print`
+    fn report_unused_values(
+        &self,
+        body: &[b::Instr],
+        instrs_entries: &[Option<TypeCheckEntryIdx>],
+    ) {
+        for (instr, entry) in izip!(body, instrs_entries) {
+            let b::InstrBody::Unused = &instr.body else {
+                continue;
+            };
+            match &self.entries[entry.unwrap()].ty.body {
+                b::TypeBody::Bool => {}
+                // Type could not be resolved, which is already reported by `validate`
+                b::TypeBody::Inferred(_) => {}
+                _ => {
+                    self.ctx.push_warning(errors::Warning::new(
+                        errors::WarningDetail::DiscardedValue,
+                        instr.loc,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// The entry point should be either `bool`, which always exits successfully, or
+    /// `i32`, whose value is used as the exit status of the program
+    fn validate_entry_point(&self, idx: TypeCheckEntryIdx, loc: b::Loc) {
+        let ty = &self.entries[idx].ty;
+        match &ty.body {
+            b::TypeBody::Bool | b::TypeBody::I32 => {}
+            // Type could not be resolved, which is already reported by `validate`
+            b::TypeBody::AnyNumber
+            | b::TypeBody::AnySignedNumber
+            | b::TypeBody::AnyFloat
+            | b::TypeBody::Inferred(_) => {}
+            _ => {
+                self.ctx.push_error(errors::Error::new(
+                    errors::InvalidEntryPointType::new(ty.clone()).into(),
+                    loc,
+                ));
+            }
+        }
+    }
+
     fn property(
         &mut self,
         idx: TypeCheckEntryIdx,
@@ -680,6 +1584,280 @@ impl<'a> TypeChecker<'a> {
         self.add_constraint(idx, Constraint::Array(res));
         res
     }
+
+    fn ptr_item(&mut self, idx: TypeCheckEntryIdx, loc: b::Loc) -> TypeCheckEntryIdx {
+        let entry = &self.entries[idx];
+
+        let mut known_item_ty = None;
+        for item in &entry.constraints {
+            match item {
+                Constraint::Ptr(item_idx) => return *item_idx,
+                Constraint::Is(b::Type {
+                    body: b::TypeBody::Ptr(item_ty),
+                    ..
+                }) if !item_ty.is_unknown() => known_item_ty = Some(*item_ty.clone()),
+                _ => {}
+            }
+        }
+
+        let res = match entry.same_of.len() {
+            0 => match known_item_ty {
+                Some(ty) => self.add_entry_from_type(ty, loc),
+                None => self.add_entry(loc),
+            },
+            1 => self.ptr_item(*entry.same_of.iter().next().unwrap(), loc),
+            _ => {
+                let res = self.add_entry(loc);
+                for i in self.entries[idx].same_of.clone() {
+                    let prop = self.ptr_item(i, loc);
+                    self.entries[res].same_of.insert(prop);
+                }
+                res
+            }
+        };
+
+        self.add_constraint(idx, Constraint::Ptr(res));
+        res
+    }
+
+    fn option_item(&mut self, idx: TypeCheckEntryIdx, loc: b::Loc) -> TypeCheckEntryIdx {
+        let entry = &self.entries[idx];
+
+        let mut known_item_ty = None;
+        let mut type_of = None;
+        for item in &entry.constraints {
+            match item {
+                Constraint::Option(item_idx) => return *item_idx,
+                Constraint::Is(b::Type {
+                    body: b::TypeBody::Option(item_ty),
+                    ..
+                }) if !item_ty.is_unknown() => known_item_ty = Some(*item_ty.clone()),
+                Constraint::TypeOf(target) => type_of = Some(*target),
+                _ => {}
+            }
+        }
+
+        let res = match entry.same_of.len() {
+            0 => match (known_item_ty, type_of) {
+                (Some(ty), _) => self.add_entry_from_type(ty, loc),
+                // The item only takes the type of the target's item, so it can't change
+                // the type of the target, like the return type of a function
+                (None, Some(target)) => {
+                    let target_item = self.option_item(target, loc);
+                    let res = self.add_entry(loc);
+                    self.add_constraint(res, Constraint::TypeOf(target_item));
+                    res
+                }
+                (None, None) => self.add_entry(loc),
+            },
+            1 => self.option_item(*entry.same_of.iter().next().unwrap(), loc),
+            _ => {
+                let res = self.add_entry(loc);
+                for i in self.entries[idx].same_of.clone() {
+                    let prop = self.option_item(i, loc);
+                    self.entries[res].same_of.insert(prop);
+                }
+                res
+            }
+        };
+
+        self.add_constraint(idx, Constraint::Option(res));
+        res
+    }
+
+    /// Entries of the types given to the parameters of a variant type
+    fn variant_args(
+        &mut self,
+        idx: TypeCheckEntryIdx,
+        ty: &b::Type,
+        loc: b::Loc,
+    ) -> Vec<TypeCheckEntryIdx> {
+        let b::TypeBody::Variant(mod_idx, ty_idx, params) = &ty.body else {
+            panic!("type should be a variant type");
+        };
+        let entry = &self.entries[idx];
+
+        let mut known_args_tys = None;
+        let mut type_of = None;
+        for item in &entry.constraints {
+            match item {
+                Constraint::Variant(item_mod_idx, item_ty_idx, args)
+                    if item_mod_idx == mod_idx && item_ty_idx == ty_idx =>
+                {
+                    return args.clone()
+                }
+                Constraint::Is(b::Type {
+                    body: b::TypeBody::Variant(item_mod_idx, item_ty_idx, args_tys),
+                    ..
+                }) if item_mod_idx == mod_idx
+                    && item_ty_idx == ty_idx
+                    && args_tys.iter().all(|ty| !ty.is_unknown()) =>
+                {
+                    known_args_tys = Some(args_tys.clone())
+                }
+                Constraint::TypeOf(target) => type_of = Some(*target),
+                _ => {}
+            }
+        }
+
+        let res = match entry.same_of.len() {
+            0 => match (known_args_tys, type_of) {
+                (Some(args_tys), _) => args_tys
+                    .into_iter()
+                    .map(|ty| self.add_entry_from_type(ty, loc))
+                    .collect(),
+                // The values only take the types of the target's values, so they can't
+                // change the type of the target, like the return type of a function
+                (None, Some(target)) => self
+                    .variant_args(target, ty, loc)
+                    .into_iter()
+                    .map(|target_arg| {
+                        let arg = self.add_entry(loc);
+                        self.add_constraint(arg, Constraint::TypeOf(target_arg));
+                        arg
+                    })
+                    .collect(),
+                (None, None) => params.iter().map(|_| self.add_entry(loc)).collect(),
+            },
+            1 => self.variant_args(*entry.same_of.iter().next().unwrap(), ty, loc),
+            _ => {
+                let res = params.iter().map(|_| self.add_entry(loc)).collect_vec();
+                for i in self.entries[idx].same_of.clone() {
+                    let args = self.variant_args(i, ty, loc);
+                    for (res_arg, arg) in izip!(&res, args) {
+                        self.entries[*res_arg].same_of.insert(arg);
+                    }
+                }
+                res
+            }
+        };
+
+        self.add_constraint(idx, Constraint::Variant(*mod_idx, *ty_idx, res.clone()));
+        res
+    }
+
+    /// Entries of the types of the parameters and of the result of a function value. When
+    /// the entry is not known to be a function yet, it's expected to be one with `len`
+    /// parameters
+    fn func_parts(
+        &mut self,
+        idx: TypeCheckEntryIdx,
+        len: usize,
+        loc: b::Loc,
+    ) -> (Vec<TypeCheckEntryIdx>, TypeCheckEntryIdx) {
+        let entry = &self.entries[idx];
+
+        let mut known_func_ty = None;
+        let mut type_of = None;
+        for item in &entry.constraints {
+            match item {
+                Constraint::Func(params, ret) => return (params.clone(), *ret),
+                Constraint::Is(b::Type {
+                    body: b::TypeBody::Func(func_ty),
+                    ..
+                }) => known_func_ty = Some(func_ty.clone()),
+                Constraint::TypeOf(target) => type_of = Some(*target),
+                _ => {}
+            }
+        }
+
+        let (params, ret) = match entry.same_of.len() {
+            0 => match (known_func_ty, type_of) {
+                (Some(func_ty), _) => (
+                    func_ty
+                        .params
+                        .into_iter()
+                        .map(|ty| self.add_entry_from_type(ty, loc))
+                        .collect(),
+                    self.add_entry_from_type(func_ty.ret, loc),
+                ),
+                // The parts only take the types of the target's parts, so they can't
+                // change the type of the target, like the return type of a function
+                (None, Some(target)) => {
+                    let (target_params, target_ret) = self.func_parts(target, len, loc);
+                    let mut type_of_target = |target: TypeCheckEntryIdx| {
+                        let entry = self.add_entry(loc);
+                        self.add_constraint(entry, Constraint::TypeOf(target));
+                        entry
+                    };
+                    (
+                        target_params.into_iter().map(&mut type_of_target).collect(),
+                        type_of_target(target_ret),
+                    )
+                }
+                (None, None) => {
+                    let params = (0..len).map(|_| self.add_entry(loc)).collect();
+                    (params, self.add_entry(loc))
+                }
+            },
+            1 => return self.func_parts(*entry.same_of.iter().next().unwrap(), len, loc),
+            _ => {
+                let params = (0..len).map(|_| self.add_entry(loc)).collect_vec();
+                let ret = self.add_entry(loc);
+                for i in self.entries[idx].same_of.clone() {
+                    let (same_params, same_ret) = self.func_parts(i, len, loc);
+                    // Functions with other arities are reported when the types of the
+                    // entries are merged
+                    if same_params.len() != len {
+                        continue;
+                    }
+                    for (param, same_param) in izip!(&params, same_params) {
+                        self.entries[*param].same_of.insert(same_param);
+                    }
+                    self.entries[ret].same_of.insert(same_ret);
+                }
+                (params, ret)
+            }
+        };
+
+        self.add_constraint(idx, Constraint::Func(params.clone(), ret));
+        (params, ret)
+    }
+
+    fn case_type(&self, mod_idx: usize, ty_idx: usize, case: usize) -> b::Type {
+        let modules = self.ctx.lock_modules();
+        let b::TypeDefBody::Variant(v) = &modules[mod_idx].typedefs[ty_idx].body else {
+            panic!("type should be a variant type");
+        };
+        v.cases[case].ty.clone()
+    }
+
+    /// Adds the entry of the value held by a case of a variant type, whose parameters
+    /// have the types of `args`
+    fn add_case_entry(
+        &mut self,
+        ty: b::Type,
+        args: &[TypeCheckEntryIdx],
+        loc: b::Loc,
+    ) -> TypeCheckEntryIdx {
+        if !ty.has_params() {
+            return self.add_entry_from_type(ty, loc);
+        }
+
+        let constraint = match ty.body {
+            b::TypeBody::Param(idx) => return args[idx],
+            b::TypeBody::Array(array_ty) => {
+                Constraint::Array(self.add_case_entry(*array_ty.item, args, loc))
+            }
+            b::TypeBody::Ptr(item_ty) => {
+                Constraint::Ptr(self.add_case_entry(*item_ty, args, loc))
+            }
+            b::TypeBody::Option(item_ty) => {
+                Constraint::Option(self.add_case_entry(*item_ty, args, loc))
+            }
+            b::TypeBody::Variant(mod_idx, ty_idx, args_tys) => {
+                let variant_args = args_tys
+                    .into_iter()
+                    .map(|ty| self.add_case_entry(ty, args, loc))
+                    .collect();
+                Constraint::Variant(mod_idx, ty_idx, variant_args)
+            }
+            _ => unreachable!("type without parameters"),
+        };
+        let entry = self.add_entry(loc);
+        self.add_constraint(entry, constraint);
+        entry
+    }
 }
 
 #[derive(Debug, Clone, new)]
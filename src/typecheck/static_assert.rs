@@ -0,0 +1,70 @@
+use crate::{bytecode as b, context, errors};
+
+/// Checks the conditions of every `@static_assert` of the module, which are then
+/// replaced by their result. Conditions are evaluated like constant globals, so they
+/// can't depend on anything only known at runtime. Nothing is checked if the module
+/// has errors, since its types may not be known
+pub fn check_static_asserts(ctx: &context::BuildContext, mod_idx: usize) {
+    if !ctx.errors.lock().unwrap().is_empty() {
+        return;
+    }
+
+    let mut checked = vec![];
+    {
+        let modules = ctx.lock_modules();
+        let module = &modules[mod_idx];
+        let bodies = module
+            .globals
+            .iter()
+            .map(|global| &global.body)
+            .chain(module.funcs.iter().map(|func| &func.body));
+
+        for (body_idx, body) in bodies.enumerate() {
+            for (i, instr) in body.iter().enumerate() {
+                let b::InstrBody::StaticAssert(message, cond_len) = &instr.body else {
+                    continue;
+                };
+
+                let cond = &body[i - cond_len..i];
+                let value = b::ConstEvaluator::new(&modules, ctx.ptr_bytes())
+                    .eval_instrs(cond)
+                    .map(|value| value.body);
+                let detail = match value {
+                    Some(b::ConstValueBody::Bool(true)) => None,
+                    Some(b::ConstValueBody::Bool(false)) => {
+                        Some(errors::StaticAssertFailed::new(message.clone()).into())
+                    }
+                    _ => Some(errors::ErrorDetail::StaticAssertNotConstant),
+                };
+                if let Some(detail) = detail {
+                    ctx.push_error(errors::Error::new(detail, instr.loc));
+                }
+                checked.push((body_idx, i, *cond_len));
+            }
+        }
+    }
+
+    // The condition is never evaluated at runtime, so its instructions are replaced by
+    // the result, starting from the end so the positions of the others stay the same.
+    // Assertions nested in the condition of another are removed along with it
+    let module = &mut ctx.lock_modules_mut()[mod_idx];
+    let globals_len = module.globals.len();
+    let mut removed: Option<(usize, usize)> = None;
+    for (body_idx, i, cond_len) in checked.into_iter().rev() {
+        if removed.is_some_and(|(idx, start)| idx == body_idx && i >= start) {
+            continue;
+        }
+        removed = Some((body_idx, i - cond_len));
+
+        let body = if body_idx < globals_len {
+            &mut module.globals[body_idx].body
+        } else {
+            &mut module.funcs[body_idx - globals_len].body
+        };
+        let loc = body[i].loc;
+        body.splice(
+            i - cond_len..=i,
+            [b::Instr::new(b::InstrBody::CreateBool(true), loc)],
+        );
+    }
+}
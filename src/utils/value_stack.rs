@@ -83,6 +83,20 @@ impl<V, S> ValueStack<V, S> {
         self.scopes.iter_mut().rev().find(|scope| scope.is_loop)
     }
 
+    pub fn scopes(&self) -> &[Scope<S>] {
+        &self.scopes
+    }
+
+    /// All scopes from the innermost loop scope up to the current one
+    pub fn loop_scopes(&self) -> &[Scope<S>] {
+        let start = self
+            .scopes
+            .iter()
+            .rposition(|scope| scope.is_loop)
+            .unwrap_or(self.scopes.len());
+        &self.scopes[start..]
+    }
+
     pub fn create_scope(&mut self, payload: S) -> &mut Scope<S> {
         self.scopes.push(Scope::new(payload, self.stack.len()));
         self.scopes.last_mut().unwrap()
@@ -2,10 +2,14 @@
 
 let iota = 0
 const PREC = {
+    THEN_BRANCH: -2,
+    EXPR_STMT: -1,
     IF: iota++,
     BLOCK: iota++,
     BLOCK_BODY: iota++,
+    PIPE: iota++,
     LOGICAL: iota++,
+    COALESCE: iota++,
     SUM: iota++,
     MUL: iota++,
     POW: iota++,
@@ -19,9 +23,10 @@ const PREC = {
 module.exports = grammar({
     name: "nasin",
     word: ($) => $._ident,
-    extras: ($) => [$._whitespace],
+    extras: ($) => [$._whitespace, $._comment],
     rules: {
-        root: ($) => seq(optional($._newline), sep($._newline, $._module_stmt)),
+        root: ($) =>
+            seq(optional($._decl_newline), sep($._decl_newline, $._module_stmt)),
 
         _module_stmt: ($) => choice($.func_decl, $.global_decl, $.type_decl),
 
@@ -36,7 +41,7 @@ module.exports = grammar({
                         field("ret_type", $._type_expr),
                     ),
                 ),
-                optional($._func_directives),
+                optional($._directives),
                 optional(
                     seq(
                         token_with_nl("="),
@@ -52,12 +57,13 @@ module.exports = grammar({
                 sep(or_nl(",", $._newline), field("params", $.func_param)),
                 ")",
             ),
-        _func_directives: ($) => repeat1(field("directives", $.directive)),
+        _directives: ($) => repeat1(field("directives", $.directive)),
 
         func_param: ($) =>
             seq(
                 field("pat", $._pat),
                 optional(seq(":", optional($._newline), field("type", $._type_expr))),
+                optional(seq("=", optional($._newline), field("default", $._expr))),
             ),
 
         global_decl: ($) =>
@@ -70,6 +76,7 @@ module.exports = grammar({
                         field("type", $._type_expr),
                     ),
                 ),
+                optional($._directives),
                 token_with_nl("="),
                 optional($._newline),
                 field("value", $._expr),
@@ -92,6 +99,24 @@ module.exports = grammar({
                 field("value", $._expr),
             ),
 
+        let_func_stmt: ($) =>
+            seq(
+                "let",
+                optional($._newline),
+                field("name", $.ident),
+                $._func_params,
+                optional(
+                    seq(
+                        token_with_nl(":"),
+                        optional($._newline),
+                        field("ret_type", $._type_expr),
+                    ),
+                ),
+                token_with_nl("="),
+                optional($._newline),
+                field("return", $._expr),
+            ),
+
         directive: ($) =>
             seq(token_with_nl("@"), field("name", $.ident), optional($._directive_args)),
         _directive_args: ($) =>
@@ -119,18 +144,25 @@ module.exports = grammar({
                 $.number,
                 $.string_lit,
                 $.array_lit,
+                $.directive_pair,
             ),
+        directive_pair: ($) =>
+            seq(field("name", $.ident), "=", field("value", $._directive_arg)),
 
-        _expr: ($) =>
+        _expr: ($) => choice($._inline_expr, $.block),
+        // Expressions that don't start with a statement
+        _inline_expr: ($) =>
             choice(
                 prec(
                     PREC.ATOM,
-                    seq("(", optional($._newline), $._expr, optional($._newline), ")"),
+                    seq("(", optional($._newline), $._expr, token_with_nl(")")),
                 ),
                 $.true,
                 $.false,
                 $.ident,
                 $.get_prop,
+                $.slice,
+                $.try,
                 $.number,
                 $.string_lit,
                 $.array_lit,
@@ -138,7 +170,7 @@ module.exports = grammar({
                 $.macro,
                 $.record_lit,
                 $.bin_op,
-                $.block,
+                $.pipe,
                 $.if,
             ),
 
@@ -150,6 +182,7 @@ module.exports = grammar({
                 bin_op(PREC.LOGICAL, seq($.lt, optional($._newline)), $._expr),
                 bin_op(PREC.LOGICAL, seq($.gt_eq, optional($._newline)), $._expr),
                 bin_op(PREC.LOGICAL, seq($.lt_eq, optional($._newline)), $._expr),
+                bin_op(PREC.COALESCE, seq($.double_question, optional($._newline)), $._expr),
                 bin_op(PREC.SUM, seq($.plus, optional($._newline)), $._expr),
                 bin_op(PREC.SUM, seq($.minus, optional($._newline)), $._expr),
                 bin_op(PREC.MUL, seq($.star, optional($._newline)), $._expr),
@@ -158,17 +191,42 @@ module.exports = grammar({
                 bin_op(PREC.POW, seq($.double_star, optional($._newline)), $._expr),
             ),
 
+        pipe: ($) =>
+            prec.left(
+                PREC.PIPE,
+                seq(
+                    field("value", $._expr),
+                    token_with_nl("|>"),
+                    optional($._newline),
+                    field("func", $._expr),
+                ),
+            ),
+
         call: ($) => prec.left(PREC.CALL, seq(field("callee", $._expr), $._call_args)),
         _call_args: ($) =>
             seq(
                 "(",
                 optional($._newline),
-                sep(or_nl(",", $._newline), field("args", $._expr)),
+                sep(or_nl(",", $._newline), field("args", choice($._expr, $.named_arg))),
                 ")",
             ),
+        named_arg: ($) =>
+            seq(
+                field("name", $.ident),
+                "=",
+                optional($._newline),
+                field("value", $._expr),
+            ),
 
         macro: ($) =>
-            prec.left(PREC.CALL, seq("@", field("name", $.ident), $._call_args)),
+            prec.left(PREC.CALL, seq("@", field("name", $.ident), $._macro_args)),
+        _macro_args: ($) =>
+            seq(
+                "(",
+                optional($._newline),
+                sep(or_nl(",", $._newline), field("args", $._expr)),
+                ")",
+            ),
 
         get_prop: ($) =>
             prec.left(
@@ -181,6 +239,22 @@ module.exports = grammar({
                 ),
             ),
 
+        slice: ($) =>
+            prec.left(
+                PREC.GET_PROP,
+                seq(
+                    field("parent", $._expr),
+                    token.immediate("["),
+                    optional(field("start", $._expr)),
+                    "..",
+                    optional(field("end", $._expr)),
+                    "]",
+                ),
+            ),
+
+        try: ($) =>
+            prec.left(PREC.GET_PROP, seq(field("value", $._expr), token.immediate("?"))),
+
         string_lit: ($) =>
             prec(
                 PREC.ATOM,
@@ -229,7 +303,10 @@ module.exports = grammar({
                 ),
             ),
 
-        _block_stmt: ($) => choice($.let_stmt),
+        _block_stmt: ($) => choice($.let_stmt, $.let_func_stmt, $.expr_stmt),
+        // Lowest precedence, so a newline after an expression only ends a statement
+        // when it can't continue the enclosing expression
+        expr_stmt: ($) => prec(PREC.EXPR_STMT, field("value", $._inline_expr)),
 
         if: ($) =>
             prec.right(
@@ -241,19 +318,21 @@ module.exports = grammar({
                     optional($._newline),
                     "then",
                     optional($._newline),
-                    field("then", $._expr),
+                    field("then", $._then_branch),
                     optional(
                         seq(
-                            optional($._newline),
-                            "else",
+                            token_with_nl("else"),
                             optional($._newline),
                             field("else", $._expr),
                         ),
                     ),
                 ),
             ),
+        // Lower than `expr_stmt`, so a new line after an expression continues the `then`
+        // branch as a block, since it can only be ended by `else`
+        _then_branch: ($) => prec(PREC.THEN_BRANCH, choice($._inline_expr, $.block)),
 
-        _type_expr: ($) => choice($.ident, $.array_type, $.generic_type),
+        _type_expr: ($) => choice($.ident, $.array_type, $.generic_type, $.func_type),
 
         array_type: ($) =>
             prec(
@@ -284,11 +363,35 @@ module.exports = grammar({
                 ")",
             ),
 
+        func_type: ($) =>
+            seq(
+                "(",
+                optional($._newline),
+                sep(or_nl(",", $._newline), field("params", $._type_expr)),
+                ")",
+                ":",
+                optional($._newline),
+                field("ret_type", $._type_expr),
+            ),
+
         _pat: ($) => choice($.ident),
 
         type_decl: ($) =>
-            seq("type", field("name", $.ident), field("body", $._type_decl_body)),
-        _type_decl_body: ($) => choice($.record_type),
+            seq(
+                "type",
+                field("name", $.ident),
+                optional($._type_params),
+                field("body", $._type_decl_body),
+                optional($._directives),
+            ),
+        _type_params: ($) =>
+            seq(
+                "(",
+                optional($._newline),
+                sep(or_nl(",", $._newline), field("params", $.ident)),
+                ")",
+            ),
+        _type_decl_body: ($) => choice($.record_type, $.variant_type),
 
         record_type: ($) =>
             seq(
@@ -304,6 +407,28 @@ module.exports = grammar({
                 optional($._newline),
                 field("type", $._type_expr),
             ),
+        variant_type: ($) =>
+            seq(
+                token_with_nl("="),
+                optional($._newline),
+                field("variants", $.type_variant),
+                repeat(
+                    seq(
+                        token_with_nl("|"),
+                        optional($._newline),
+                        field("variants", $.type_variant),
+                    ),
+                ),
+            ),
+        type_variant: ($) =>
+            seq(
+                field("name", $.ident),
+                "(",
+                optional($._newline),
+                field("type", $._type_expr),
+                optional($._newline),
+                ")",
+            ),
 
         plus: () => token_with_nl("+"),
         minus: () => token_with_nl("-"),
@@ -317,6 +442,7 @@ module.exports = grammar({
         lt: () => token_with_nl("<"),
         gt_eq: () => token_with_nl(">="),
         lt_eq: () => token_with_nl("<="),
+        double_question: () => token_with_nl("??"),
         dot: () => token_with_nl("."),
 
         ident: ($) => prec(PREC.ATOM, $._ident),
@@ -328,7 +454,15 @@ module.exports = grammar({
         number: () => prec(PREC.ATOM, /(\d(_?\d)*)?\.?\d(_?\d)*/),
 
         _whitespace: () => /[ \t]+/,
-        _newline: () => /(\r?\n)+/,
+        // Comments go until the end of the line, so they are part of the new line. The
+        // last line of a file may have no new line, so a comment can also end a new line
+        // or be on its own
+        _comment: () => /[ \t]*\/\/[^\r\n]*/,
+        _newline: () => /(([ \t]*\/\/[^\r\n]*)?\r?\n[ \t]*)+(\/\/[^\r\n]*)?/,
+        // A new line that goes back to the first column, so the next declaration can't be
+        // confused with a statement of the previous one
+        _decl_newline: () =>
+            token(prec(1, /(([ \t]*\/\/[^\r\n]*)?\r?\n[ \t]*)*([ \t]*\/\/[^\r\n]*)?\r?\n([ \t]*\/\/[^\r\n]*)?/)),
     },
 })
 